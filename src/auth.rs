@@ -0,0 +1,79 @@
+use actix_web::HttpRequest;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::HashMap;
+
+/// Static API keys, each bound to one `user_id`. Read from a config file as a
+/// `key = user_id` table; serialized only as a count so keys never reach logs.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct ApiKeys {
+    keys: HashMap<String, u32>,
+}
+
+impl Serialize for ApiKeys {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("<{} keys redacted>", self.keys.len()))
+    }
+}
+
+/// Result of checking a request's credentials.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Auth {
+    Anonymous,
+    User(u32),
+    Invalid,
+}
+
+impl ApiKeys {
+    /// Parses `key:user_id` pairs separated by commas, e.g. `k1:1,k2:2`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut keys = HashMap::new();
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (key, user_id) = entry
+                .rsplit_once(':')
+                .ok_or_else(|| format!("API key entry '{}' is not key:user_id", entry))?;
+            let user_id = user_id
+                .parse()
+                .map_err(|_| format!("API key entry '{}' has an invalid user_id", entry))?;
+            keys.insert(key.to_string(), user_id);
+        }
+        Ok(Self { keys })
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Looks up the key from `Authorization: Bearer <key>`, `X-API-Key: <key>` or the
+    /// `api_key` query parameter (browsers cannot set headers on a WebSocket upgrade).
+    pub fn authenticate(&self, req: &HttpRequest) -> Auth {
+        let Some(key) = request_key(req) else {
+            return Auth::Anonymous;
+        };
+        match self.keys.get(&key) {
+            Some(&user_id) => Auth::User(user_id),
+            None => Auth::Invalid,
+        }
+    }
+}
+
+fn request_key(req: &HttpRequest) -> Option<String> {
+    let headers = req.headers();
+    let bearer = headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let header = headers.get("x-api-key").and_then(|v| v.to_str().ok());
+
+    if let Some(key) = bearer.or(header) {
+        return Some(key.trim().to_string());
+    }
+
+    actix_web::web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.get("api_key").cloned())
+}
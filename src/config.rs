@@ -0,0 +1,550 @@
+use crate::auth::ApiKeys;
+use crate::persist::{DEFAULT_DEAD_LETTER_PATH, RetryPolicy};
+use rdkafka::config::ClientConfig;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::net::ToSocketAddrs;
+use std::time::Duration;
+
+/// HTTP listener settings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: String,
+    /// actix worker threads.
+    pub workers: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: "127.0.0.1:8080".to_string(),
+            workers: 16,
+        }
+    }
+}
+
+/// Where the engine sends `PersistEvent`s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PersistBackend {
+    /// In-process channel straight into the Scylla persistence worker.
+    #[default]
+    Direct,
+    /// Engine → Kafka → Scylla, with the consumer optionally running in this process.
+    Kafka,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersistenceConfig {
+    pub backend: PersistBackend,
+    /// Scylla node used by the persistence worker, the Kafka consumer and `redrive-dlq`.
+    pub scylla_uri: String,
+    /// File the persistence worker appends events to once retries are exhausted, and that
+    /// `redrive-dlq` reads.
+    pub dead_letter_path: String,
+    /// Attempts at a Scylla write, the first included, before it is dead-lettered.
+    pub retry_max_attempts: u32,
+    /// Backoff before the first retry; it doubles with every attempt.
+    #[serde(rename = "retry_initial_backoff_ms", with = "millis")]
+    pub retry_initial_backoff: Duration,
+    #[serde(rename = "retry_max_backoff_ms", with = "millis")]
+    pub retry_max_backoff: Duration,
+}
+
+impl Default for PersistenceConfig {
+    fn default() -> Self {
+        let retry = RetryPolicy::default();
+        Self {
+            backend: PersistBackend::Direct,
+            scylla_uri: "127.0.0.1:9042".to_string(),
+            dead_letter_path: DEFAULT_DEAD_LETTER_PATH.to_string(),
+            retry_max_attempts: retry.max_attempts,
+            retry_initial_backoff: retry.initial_backoff,
+            retry_max_backoff: retry.max_backoff,
+        }
+    }
+}
+
+impl PersistenceConfig {
+    /// How Scylla writes are retried, by the persistence worker, the Kafka consumer and
+    /// `redrive-dlq` alike.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.retry_max_attempts,
+            initial_backoff: self.retry_initial_backoff,
+            max_backoff: self.retry_max_backoff,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KafkaConfig {
+    pub brokers: String,
+    pub orders_topic: String,
+    pub trades_topic: String,
+    pub consumer_group: String,
+    /// Records the consumer could not apply are forwarded here instead of being skipped.
+    pub dead_letter_topic: String,
+    /// Run the Kafka → Scylla consumer inside the server process.
+    pub run_consumer: bool,
+    /// Accept orders from `order_input_topic` in addition to HTTP.
+    pub run_order_ingest: bool,
+    pub order_input_topic: String,
+    pub order_response_topic: String,
+    pub order_ingest_group: String,
+    pub acks: String,
+    pub linger_ms: u32,
+    pub compression: String,
+    pub message_timeout_ms: u32,
+    pub enable_idempotence: bool,
+}
+
+impl Default for KafkaConfig {
+    fn default() -> Self {
+        Self {
+            brokers: "localhost:9092".to_string(),
+            orders_topic: "orders".to_string(),
+            trades_topic: "trades".to_string(),
+            consumer_group: "clob-consumer".to_string(),
+            dead_letter_topic: "clob-dead-letter".to_string(),
+            run_consumer: true,
+            run_order_ingest: false,
+            order_input_topic: "order-requests".to_string(),
+            order_response_topic: "order-responses".to_string(),
+            order_ingest_group: "clob-order-ingest".to_string(),
+            acks: "all".to_string(),
+            linger_ms: 5,
+            compression: "lz4".to_string(),
+            message_timeout_ms: 30_000,
+            enable_idempotence: true,
+        }
+    }
+}
+
+impl KafkaConfig {
+    pub fn producer_config(&self) -> ClientConfig {
+        let mut config = ClientConfig::new();
+        config
+            .set("bootstrap.servers", &self.brokers)
+            .set("acks", &self.acks)
+            .set("linger.ms", self.linger_ms.to_string())
+            .set("compression.type", &self.compression)
+            .set("message.timeout.ms", self.message_timeout_ms.to_string())
+            .set("enable.idempotence", self.enable_idempotence.to_string());
+        config
+    }
+
+    pub fn consumer_config(&self) -> ClientConfig {
+        self.consumer_config_for(&self.consumer_group)
+    }
+
+    pub fn consumer_config_for(&self, group_id: &str) -> ClientConfig {
+        let mut config = ClientConfig::new();
+        config
+            .set("bootstrap.servers", &self.brokers)
+            .set("group.id", group_id)
+            .set("auto.offset.reset", "earliest")
+            .set("enable.auto.commit", "false");
+        config
+    }
+}
+
+/// Matching loop tuning.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EngineConfig {
+    /// Capacity of the ring buffer between order entry and the matching loop.
+    pub ring_capacity: usize,
+    /// Empty polls the matching loop spins through before yielding to the runtime.
+    pub idle_spins: u32,
+    /// Under load, pending `GET /l3` snapshot requests are answered every this many events.
+    pub l3_snapshot_interval: u64,
+    /// `GET /l3` gets the previous snapshot again until it is this old, even if the book
+    /// changed since, so requests cannot keep the matching loop copying the book.
+    #[serde(rename = "l3_min_snapshot_interval_ms", with = "millis")]
+    pub l3_min_snapshot_interval: Duration,
+    /// How long `/depth`, `/trades/recent`, `/ticker` and `/klines` may lag the matching
+    /// loop while a reader holds up its next publish.
+    #[serde(rename = "depth_max_staleness_ms", with = "millis")]
+    pub depth_max_staleness: Duration,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            ring_capacity: 65536,
+            idle_spins: 1000,
+            l3_snapshot_interval: 100,
+            l3_min_snapshot_interval: Duration::from_millis(100),
+            depth_max_staleness: Duration::from_millis(50),
+        }
+    }
+}
+
+/// Limits applied to each WebSocket session by the market-data fan-out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WsConfig {
+    /// Messages a session may have queued but not yet written before it counts as lagging.
+    pub max_pending: usize,
+    /// How long a session may stay lagging before it is disconnected.
+    #[serde(rename = "max_lag_ms", with = "millis")]
+    pub max_lag: Duration,
+    /// How often the server pings each session.
+    #[serde(rename = "heartbeat_ms", with = "millis")]
+    pub heartbeat_interval: Duration,
+    /// A session that sends nothing (not even a pong) for this long is closed.
+    #[serde(rename = "client_timeout_ms", with = "millis")]
+    pub client_timeout: Duration,
+    /// Sessions are closed after this long regardless of activity; `None` for no limit.
+    #[serde(rename = "max_connection_age_secs", with = "secs_or_zero")]
+    pub max_connection_age: Option<Duration>,
+    /// Messages kept per public topic for subscribers resuming after a known `seq`.
+    pub replay_capacity: usize,
+}
+
+impl Default for WsConfig {
+    fn default() -> Self {
+        Self {
+            max_pending: 1024,
+            max_lag: Duration::from_secs(10),
+            heartbeat_interval: Duration::from_secs(5),
+            client_timeout: Duration::from_secs(30),
+            max_connection_age: Some(Duration::from_secs(24 * 60 * 60)),
+            replay_capacity: 1000,
+        }
+    }
+}
+
+/// What the process was started to do, from its first argument.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// No argument: run the exchange.
+    Serve,
+    /// `redrive-dlq`: re-applies dead-lettered persistence events and exits.
+    RedriveDeadLetters,
+    /// `persist-consumer`: runs only the Kafka → Scylla half of the pipeline, for a
+    /// separately deployed consumer.
+    PersistConsumer,
+}
+
+impl Command {
+    pub fn parse(arg: Option<&str>) -> Result<Self, String> {
+        match arg {
+            None => Ok(Command::Serve),
+            Some("redrive-dlq") => Ok(Command::RedriveDeadLetters),
+            Some("persist-consumer") => Ok(Command::PersistConsumer),
+            Some(other) => Err(format!("unknown command '{}'", other)),
+        }
+    }
+}
+
+/// Effective configuration: defaults, then the TOML file named by `CLOB_CONFIG`, then
+/// `CLOB_*` environment variables.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The market this process serves; one engine runs one market.
+    pub market: String,
+    pub server: ServerConfig,
+    pub persistence: PersistenceConfig,
+    pub kafka: KafkaConfig,
+    pub engine: EngineConfig,
+    /// Keys accepted on private WebSocket streams. Printed only as a count, so a printed
+    /// configuration has to be given its keys again before it can be loaded.
+    pub api_keys: ApiKeys,
+    pub ws: WsConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            market: "BTC-USDT".to_string(),
+            server: ServerConfig::default(),
+            persistence: PersistenceConfig::default(),
+            kafka: KafkaConfig::default(),
+            engine: EngineConfig::default(),
+            api_keys: ApiKeys::default(),
+            ws: WsConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Loads and validates the configuration the server runs with.
+    pub fn load() -> Result<Self, String> {
+        let mut config = match env::var("CLOB_CONFIG") {
+            Ok(path) => Self::from_file(&path)?,
+            Err(_) => Self::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parses a TOML config file. Sections and keys it leaves out keep their defaults.
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Self::from_toml(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, String> {
+        toml::from_str(text).map_err(|e| e.to_string())
+    }
+
+    /// The configuration as TOML, with API keys redacted. Loading it back fails on the
+    /// redacted `api_keys` unless that line is removed or replaced with the real keys.
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).unwrap_or_else(|e| format!("<unprintable: {}>", e))
+    }
+
+    /// Overrides settings from any `CLOB_*` environment variables that are set.
+    pub fn apply_env(&mut self) -> Result<(), String> {
+        self.apply_overrides(&|key| env::var(key).ok())
+    }
+
+    /// Overrides settings from the `CLOB_*` variables `var` returns a value for.
+    pub fn apply_overrides(&mut self, var: Var) -> Result<(), String> {
+        override_string(var, "CLOB_BIND_ADDRESS", &mut self.server.bind_address);
+        override_parsed(var, "CLOB_WORKERS", &mut self.server.workers)?;
+
+        match var("CLOB_PERSIST_BACKEND").as_deref() {
+            None => {}
+            Some("direct") => self.persistence.backend = PersistBackend::Direct,
+            Some("kafka") => self.persistence.backend = PersistBackend::Kafka,
+            Some(other) => {
+                return Err(format!(
+                    "CLOB_PERSIST_BACKEND must be 'direct' or 'kafka', got '{}'",
+                    other
+                ));
+            }
+        }
+        let persistence = &mut self.persistence;
+        override_string(var, "CLOB_SCYLLA_URI", &mut persistence.scylla_uri);
+        override_string(
+            var,
+            "CLOB_DEAD_LETTER_PATH",
+            &mut persistence.dead_letter_path,
+        );
+        override_parsed(
+            var,
+            "CLOB_PERSIST_RETRY_MAX_ATTEMPTS",
+            &mut persistence.retry_max_attempts,
+        )?;
+        override_millis(
+            var,
+            "CLOB_PERSIST_RETRY_INITIAL_BACKOFF_MS",
+            &mut persistence.retry_initial_backoff,
+        )?;
+        override_millis(
+            var,
+            "CLOB_PERSIST_RETRY_MAX_BACKOFF_MS",
+            &mut persistence.retry_max_backoff,
+        )?;
+
+        let kafka = &mut self.kafka;
+        override_string(var, "CLOB_KAFKA_BROKERS", &mut kafka.brokers);
+        override_string(var, "CLOB_KAFKA_ORDERS_TOPIC", &mut kafka.orders_topic);
+        override_string(var, "CLOB_KAFKA_TRADES_TOPIC", &mut kafka.trades_topic);
+        override_string(var, "CLOB_KAFKA_CONSUMER_GROUP", &mut kafka.consumer_group);
+        override_string(
+            var,
+            "CLOB_KAFKA_DEAD_LETTER_TOPIC",
+            &mut kafka.dead_letter_topic,
+        );
+        override_parsed(var, "CLOB_KAFKA_RUN_CONSUMER", &mut kafka.run_consumer)?;
+        override_parsed(
+            var,
+            "CLOB_KAFKA_RUN_ORDER_INGEST",
+            &mut kafka.run_order_ingest,
+        )?;
+        override_string(
+            var,
+            "CLOB_KAFKA_ORDER_INPUT_TOPIC",
+            &mut kafka.order_input_topic,
+        );
+        override_string(
+            var,
+            "CLOB_KAFKA_ORDER_RESPONSE_TOPIC",
+            &mut kafka.order_response_topic,
+        );
+        override_string(
+            var,
+            "CLOB_KAFKA_ORDER_INGEST_GROUP",
+            &mut kafka.order_ingest_group,
+        );
+        override_string(var, "CLOB_KAFKA_ACKS", &mut kafka.acks);
+        override_parsed(var, "CLOB_KAFKA_LINGER_MS", &mut kafka.linger_ms)?;
+        override_string(var, "CLOB_KAFKA_COMPRESSION", &mut kafka.compression);
+        override_parsed(
+            var,
+            "CLOB_KAFKA_MESSAGE_TIMEOUT_MS",
+            &mut kafka.message_timeout_ms,
+        )?;
+        override_parsed(
+            var,
+            "CLOB_KAFKA_ENABLE_IDEMPOTENCE",
+            &mut kafka.enable_idempotence,
+        )?;
+
+        override_string(var, "CLOB_MARKET", &mut self.market);
+
+        let engine = &mut self.engine;
+        override_parsed(var, "CLOB_RING_CAPACITY", &mut engine.ring_capacity)?;
+        override_parsed(var, "CLOB_IDLE_SPINS", &mut engine.idle_spins)?;
+        override_parsed(
+            var,
+            "CLOB_L3_SNAPSHOT_INTERVAL",
+            &mut engine.l3_snapshot_interval,
+        )?;
+        override_millis(
+            var,
+            "CLOB_L3_MIN_SNAPSHOT_INTERVAL_MS",
+            &mut engine.l3_min_snapshot_interval,
+        )?;
+        override_millis(
+            var,
+            "CLOB_DEPTH_MAX_STALENESS_MS",
+            &mut engine.depth_max_staleness,
+        )?;
+
+        if let Some(spec) = var("CLOB_API_KEYS") {
+            self.api_keys = ApiKeys::parse(&spec).map_err(|e| format!("CLOB_API_KEYS: {}", e))?;
+        }
+
+        let ws = &mut self.ws;
+        override_parsed(var, "CLOB_WS_MAX_PENDING", &mut ws.max_pending)?;
+        override_millis(var, "CLOB_WS_MAX_LAG_MS", &mut ws.max_lag)?;
+        override_millis(var, "CLOB_WS_HEARTBEAT_MS", &mut ws.heartbeat_interval)?;
+        override_millis(var, "CLOB_WS_CLIENT_TIMEOUT_MS", &mut ws.client_timeout)?;
+        let mut max_age_secs = ws.max_connection_age.map_or(0, |age| age.as_secs());
+        override_parsed(var, "CLOB_WS_MAX_CONNECTION_AGE_SECS", &mut max_age_secs)?;
+        ws.max_connection_age = (max_age_secs > 0).then(|| Duration::from_secs(max_age_secs));
+        override_parsed(var, "CLOB_WS_REPLAY_CAPACITY", &mut ws.replay_capacity)?;
+        Ok(())
+    }
+
+    /// Rejects settings the server cannot start with.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.server.workers == 0 {
+            return Err("server.workers must be at least 1".to_string());
+        }
+        if self
+            .server
+            .bind_address
+            .to_socket_addrs()
+            .map_or(true, |mut addrs| addrs.next().is_none())
+        {
+            return Err(format!(
+                "server.bind_address '{}' is not a host:port address",
+                self.server.bind_address
+            ));
+        }
+        if self.persistence.scylla_uri.is_empty() {
+            return Err("persistence.scylla_uri must not be empty".to_string());
+        }
+        if self.persistence.dead_letter_path.is_empty() {
+            return Err("persistence.dead_letter_path must not be empty".to_string());
+        }
+        if self.persistence.retry_max_attempts == 0 {
+            return Err("persistence.retry_max_attempts must be at least 1".to_string());
+        }
+        if self.persistence.retry_initial_backoff.is_zero()
+            || self.persistence.retry_max_backoff < self.persistence.retry_initial_backoff
+        {
+            return Err(
+                "persistence.retry_max_backoff_ms must be at least a non-zero persistence.retry_initial_backoff_ms"
+                    .to_string(),
+            );
+        }
+        if self.market.is_empty() {
+            return Err("market must not be empty".to_string());
+        }
+        if self.engine.ring_capacity == 0
+            || self.engine.idle_spins == 0
+            || self.engine.l3_snapshot_interval == 0
+        {
+            return Err(
+                "engine.ring_capacity, engine.idle_spins and engine.l3_snapshot_interval must be non-zero"
+                    .to_string(),
+            );
+        }
+        if self.ws.max_pending == 0 {
+            return Err("ws.max_pending must be at least 1".to_string());
+        }
+        if self.ws.heartbeat_interval.is_zero()
+            || self.ws.client_timeout <= self.ws.heartbeat_interval
+        {
+            return Err(
+                "ws.client_timeout_ms must be greater than a non-zero ws.heartbeat_ms".to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Looks up an environment variable; `env::var` in production.
+pub type Var<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+fn override_string(var: Var, key: &str, target: &mut String) {
+    if let Some(value) = var(key) {
+        *target = value;
+    }
+}
+
+fn override_parsed<T: std::str::FromStr>(
+    var: Var,
+    key: &str,
+    target: &mut T,
+) -> Result<(), String> {
+    if let Some(value) = var(key) {
+        *target = value
+            .parse()
+            .map_err(|_| format!("{} has an invalid value '{}'", key, value))?;
+    }
+    Ok(())
+}
+
+fn override_millis(var: Var, key: &str, target: &mut Duration) -> Result<(), String> {
+    let mut millis = target.as_millis() as u64;
+    override_parsed(var, key, &mut millis)?;
+    *target = Duration::from_millis(millis);
+    Ok(())
+}
+
+/// A `Duration` stored as whole milliseconds.
+mod millis {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(value: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(value.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_millis)
+    }
+}
+
+/// An optional `Duration` stored as whole seconds, `0` meaning `None`.
+mod secs_or_zero {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(
+        value: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(value.map_or(0, |d| d.as_secs()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        let secs = u64::deserialize(deserializer)?;
+        Ok((secs > 0).then(|| Duration::from_secs(secs)))
+    }
+}
@@ -0,0 +1,214 @@
+//! Versioned framing for every wincode message that leaves the process (Kafka, WebSocket).
+//!
+//! Wire layout of an envelope, all integers little-endian:
+//!
+//! | Field       | Encoding                      |
+//! | ----------- | ----------------------------- |
+//! | magic       | 2 bytes, `0xC1 0x0B`          |
+//! | version     | u16                           |
+//! | msg_type    | u8, see [`MessageType`]       |
+//! | market      | u64 length + UTF-8 bytes      |
+//! | seq         | u64                           |
+//! | timestamp   | i64, ms since the Unix epoch  |
+//! | payload     | u64 length + wincode payload  |
+//!
+//! Schema versions:
+//!
+//! 1. The original unframed format: the bare wincode payload. No version 1 payload starts
+//!    with `0xC1`, so decoders tell it from an envelope by the magic bytes.
+//! 2. The envelope above.
+//! 3. `PersistEvent::OrderFilled` carries `remaining_qty`.
+//!
+//! Any change to a payload's layout bumps [`SCHEMA_VERSION`]. Decoders keep reading the
+//! previous versions through [`Payload::decode_older`], so records already on Kafka or in
+//! a dead-letter file are still applied after an upgrade. `tests/wire_format.rs` pins
+//! the bytes of every `PersistEvent` and `OrderEvent` variant and of `OrderAck`, and keeps
+//! fixed byte strings of older versions that must still decode.
+
+use std::fmt;
+use wincode::{SchemaRead, SchemaWrite};
+use wincode_derive::{SchemaRead, SchemaWrite};
+
+pub const MAGIC: [u8; 2] = [0xC1, 0x0B];
+pub const SCHEMA_VERSION: u16 = 3;
+/// Unframed payloads written before envelopes existed.
+pub const LEGACY_VERSION: u16 = 1;
+/// First version written in an envelope.
+pub const FIRST_FRAMED_VERSION: u16 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageType {
+    PersistEvent = 1,
+    Trade = 2,
+    OrderEvent = 3,
+    OrderAck = 4,
+    DepthUpdate = 5,
+    OrderUpdate = 6,
+    Bbo = 7,
+    Ticker = 8,
+    Candle = 9,
+    L3Update = 10,
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = EnvelopeError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(MessageType::PersistEvent),
+            2 => Ok(MessageType::Trade),
+            3 => Ok(MessageType::OrderEvent),
+            4 => Ok(MessageType::OrderAck),
+            5 => Ok(MessageType::DepthUpdate),
+            6 => Ok(MessageType::OrderUpdate),
+            7 => Ok(MessageType::Bbo),
+            8 => Ok(MessageType::Ticker),
+            9 => Ok(MessageType::Candle),
+            10 => Ok(MessageType::L3Update),
+            other => Err(EnvelopeError::UnknownType(other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, SchemaWrite, SchemaRead)]
+pub struct Envelope {
+    pub magic: [u8; 2],
+    pub version: u16,
+    pub msg_type: u8,
+    pub market: String,
+    pub seq: u64,
+    pub timestamp: i64,
+    pub payload: Vec<u8>,
+}
+
+/// Envelope fields, without the payload. For a legacy message only `version` and
+/// `msg_type` are meaningful; the rest is zeroed and has to come from elsewhere
+/// (e.g. Kafka headers).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvelopeHeader {
+    pub version: u16,
+    pub msg_type: MessageType,
+    pub market: String,
+    pub seq: u64,
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnvelopeError {
+    UnsupportedVersion(u16),
+    UnknownType(u8),
+    UnexpectedType {
+        expected: MessageType,
+        got: MessageType,
+    },
+    Encode(String),
+    Decode(String),
+}
+
+impl fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvelopeError::UnsupportedVersion(v) => write!(f, "unsupported schema version {}", v),
+            EnvelopeError::UnknownType(t) => write!(f, "unknown message type {}", t),
+            EnvelopeError::UnexpectedType { expected, got } => {
+                write!(f, "expected {:?} message, got {:?}", expected, got)
+            }
+            EnvelopeError::Encode(e) => write!(f, "encode error: {}", e),
+            EnvelopeError::Decode(e) => write!(f, "decode error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for EnvelopeError {}
+
+/// A wincode payload carried in an envelope.
+pub trait Payload: Sized + for<'de> SchemaRead<'de, Dst = Self> {
+    /// Decodes a payload written under `version`, older than [`SCHEMA_VERSION`]. Types whose
+    /// layout changed since override it to read the old layout.
+    fn decode_older(version: u16, bytes: &[u8]) -> Result<Self, EnvelopeError> {
+        let _ = version;
+        decode_payload(bytes)
+    }
+}
+
+pub fn encode<T>(
+    msg_type: MessageType,
+    market: &str,
+    seq: u64,
+    timestamp: i64,
+    payload: &T,
+) -> Result<Vec<u8>, EnvelopeError>
+where
+    T: SchemaWrite<Src = T>,
+{
+    let payload =
+        wincode::serialize(payload).map_err(|e| EnvelopeError::Encode(format!("{:?}", e)))?;
+
+    let envelope = Envelope {
+        magic: MAGIC,
+        version: SCHEMA_VERSION,
+        msg_type: msg_type as u8,
+        market: market.to_string(),
+        seq,
+        timestamp,
+        payload,
+    };
+
+    wincode::serialize(&envelope).map_err(|e| EnvelopeError::Encode(format!("{:?}", e)))
+}
+
+/// Decodes a message of the `expected` type, framed or legacy (version 1).
+pub fn decode<T: Payload>(
+    bytes: &[u8],
+    expected: MessageType,
+) -> Result<(EnvelopeHeader, T), EnvelopeError> {
+    if !bytes.starts_with(&MAGIC) {
+        let payload = T::decode_older(LEGACY_VERSION, bytes)?;
+        let header = EnvelopeHeader {
+            version: LEGACY_VERSION,
+            msg_type: expected,
+            market: String::new(),
+            seq: 0,
+            timestamp: 0,
+        };
+        return Ok((header, payload));
+    }
+
+    let envelope: Envelope =
+        wincode::deserialize(bytes).map_err(|e| EnvelopeError::Decode(format!("{:?}", e)))?;
+
+    if !(FIRST_FRAMED_VERSION..=SCHEMA_VERSION).contains(&envelope.version) {
+        return Err(EnvelopeError::UnsupportedVersion(envelope.version));
+    }
+
+    let msg_type = MessageType::try_from(envelope.msg_type)?;
+    if msg_type != expected {
+        return Err(EnvelopeError::UnexpectedType {
+            expected,
+            got: msg_type,
+        });
+    }
+
+    let payload = if envelope.version == SCHEMA_VERSION {
+        decode_payload(&envelope.payload)?
+    } else {
+        T::decode_older(envelope.version, &envelope.payload)?
+    };
+    let header = EnvelopeHeader {
+        version: envelope.version,
+        msg_type,
+        market: envelope.market,
+        seq: envelope.seq,
+        timestamp: envelope.timestamp,
+    };
+    Ok((header, payload))
+}
+
+/// Decodes a bare wincode payload.
+pub fn decode_payload<T>(bytes: &[u8]) -> Result<T, EnvelopeError>
+where
+    T: for<'de> SchemaRead<'de, Dst = T>,
+{
+    wincode::deserialize(bytes).map_err(|e| EnvelopeError::Decode(format!("{:?}", e)))
+}
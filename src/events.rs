@@ -1,4 +1,6 @@
+use crate::envelope::Payload;
 use crate::inputs::Side;
+use tokio::sync::oneshot;
 use wincode_derive::{SchemaRead, SchemaWrite};
 
 #[derive(Debug, Clone, Copy, SchemaWrite, SchemaRead)]
@@ -13,6 +15,49 @@ pub enum OrderEvent {
     DeleteOrder {
         order_id: u32,
     },
+    /// Like `DeleteOrder`, but only if `user_id` owns the order.
+    CancelOrder {
+        order_id: u32,
+        user_id: u32,
+    },
+    AmendOrder {
+        order_id: u32,
+        user_id: u32,
+        price: u32,
+        quantity: u32,
+    },
+}
+
+impl Payload for OrderEvent {}
+
+/// The engine's verdict on an order event: why it was refused, or `None` if it was taken.
+pub type Verdict = Option<&'static str>;
+
+/// An `OrderEvent` queued for the matching loop, with whoever waits for its verdict.
+#[derive(Debug)]
+pub struct OrderRequest {
+    pub event: OrderEvent,
+    /// Told the verdict once the engine has processed the event. `None` when nobody
+    /// waits, as for `POST /order`.
+    pub reply: Option<oneshot::Sender<Verdict>>,
+}
+
+impl OrderRequest {
+    /// A request whose verdict arrives on the returned receiver.
+    pub fn with_reply(event: OrderEvent) -> (Self, oneshot::Receiver<Verdict>) {
+        let (tx, rx) = oneshot::channel();
+        let request = Self {
+            event,
+            reply: Some(tx),
+        };
+        (request, rx)
+    }
+}
+
+impl From<OrderEvent> for OrderRequest {
+    fn from(event: OrderEvent) -> Self {
+        Self { event, reply: None }
+    }
 }
 
 #[derive(Debug, Clone, SchemaWrite, SchemaRead)]
@@ -1,34 +1,518 @@
-use crate::persist::{client::ScyllaClient, event::PersistEvent};
-use rdkafka::Message;
-use rdkafka::consumer::{Consumer, StreamConsumer};
-
-pub async fn start_kafka_consumer_worker(scylla: ScyllaClient) {
-    let consumer: StreamConsumer = rdkafka::config::ClientConfig::new()
-        .set("group.id", "clob-consumer")
-        .set("bootstrap.servers", "localhost:9092")
-        .set("enable.auto.commit", "true")
-        .create()
+use crate::config::KafkaConfig;
+use crate::envelope::{self, MessageType};
+use crate::kafka_worker::producer::{
+    HEADER_EPOCH, HEADER_MARKET, HEADER_SEQ, create_kafka_producer,
+};
+use crate::kafka_worker::sequence::{SeqCheck, SequenceTracker, StreamKey};
+use crate::metrics::{
+    KAFKA_DEAD_LETTERED_TOTAL, KAFKA_DUPLICATES_TOTAL, KAFKA_SEQUENCE_GAPS_TOTAL,
+};
+use crate::persist::client::ScyllaClient;
+use crate::persist::event::{PersistEvent, write_timestamp};
+use crate::persist::retry::RetryPolicy;
+use crate::persist::worker::apply_with_retry;
+use parking_lot::Mutex;
+use rdkafka::client::ClientContext;
+use rdkafka::consumer::{CommitMode, Consumer, ConsumerContext, Rebalance, StreamConsumer};
+use rdkafka::message::{Header, Headers, OwnedHeaders};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::util::Timeout;
+use rdkafka::{Message, Offset};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const HEADER_ERROR: &str = "error";
+pub const HEADER_SOURCE_TOPIC: &str = "source_topic";
+pub const HEADER_SOURCE_PARTITION: &str = "source_partition";
+pub const HEADER_SOURCE_OFFSET: &str = "source_offset";
+
+/// Consumes persistence records and applies each one to Scylla.
+///
+/// Offsets are committed only after a record has been applied (or forwarded to the
+/// dead-letter topic) and its sequence number stored, so a crash redelivers rather than
+/// drops. Redeliveries are then recognised by the per-partition applied sequence, which
+/// is stored in Scylla next to the data, and skipped. A record that slips through anyway
+/// (a crash between the apply and storing its sequence) is harmless: writes are
+/// idempotent.
+pub async fn start_kafka_consumer_worker(
+    scylla: ScyllaClient,
+    config: KafkaConfig,
+    retry: RetryPolicy,
+) {
+    let sequences = Arc::new(Mutex::new(SequenceTracker::new()));
+    let consumer: StreamConsumer<RebalanceContext> = config
+        .consumer_config()
+        .create_with_context(RebalanceContext {
+            sequences: sequences.clone(),
+        })
         .expect("Failed to create Kafka consumer");
 
     consumer
-        .subscribe(&["orders", "trades"])
+        .subscribe(&[&config.orders_topic, &config.trades_topic])
         .expect("Failed to subscribe to topics");
 
-    println!("[KAFKA CONSUMER] Subscribed to 'orders' and 'trades'");
+    println!(
+        "[KAFKA CONSUMER] Subscribed to '{}' and '{}' on {}",
+        config.orders_topic, config.trades_topic, config.brokers
+    );
+
+    let mut worker = ConsumerWorker {
+        target: ScyllaTarget {
+            dead_letter_producer: create_kafka_producer(&config),
+            scylla,
+            config,
+            retry: retry.clone(),
+        },
+        sequences,
+    };
+    let mut failures = 0;
+
+    loop {
+        let msg = match consumer.recv().await {
+            Ok(msg) => msg,
+            Err(e) => {
+                eprintln!("[KAFKA CONSUMER] Receive error: {:?}", e);
+                continue;
+            }
+        };
+
+        if let Err(e) = worker.process(&msg).await {
+            // Not committed: rewind so the record is delivered again.
+            failures += 1;
+            let delay = retry.backoff(failures);
+            eprintln!(
+                "[KAFKA CONSUMER] {}/{}@{} not completed, retrying in {:?}: {}",
+                msg.topic(),
+                msg.partition(),
+                msg.offset(),
+                delay,
+                e
+            );
+            if let Err(e) = consumer.seek(
+                msg.topic(),
+                msg.partition(),
+                Offset::Offset(msg.offset()),
+                Duration::from_secs(10),
+            ) {
+                eprintln!("[KAFKA CONSUMER] Failed to rewind: {:?}", e);
+            }
+            tokio::time::sleep(delay).await;
+            continue;
+        }
+        failures = 0;
+
+        if let Err(e) = consumer.commit_message(&msg, CommitMode::Async) {
+            eprintln!(
+                "[KAFKA CONSUMER] Failed to commit {}/{}@{}: {:?}",
+                msg.topic(),
+                msg.partition(),
+                msg.offset(),
+                e
+            );
+        }
+    }
+}
+
+/// Drops the tracked sequences of revoked partitions. Whoever gets a partition next
+/// (this consumer again, after a later rebalance) reloads its position from Scylla.
+struct RebalanceContext {
+    sequences: Arc<Mutex<SequenceTracker>>,
+}
+
+impl ClientContext for RebalanceContext {}
+
+impl ConsumerContext for RebalanceContext {
+    fn post_rebalance(&self, rebalance: &Rebalance<'_>) {
+        if let Rebalance::Revoke(partitions) = rebalance {
+            let mut sequences = self.sequences.lock();
+            for partition in partitions.elements() {
+                sequences.forget_partition(partition.topic(), partition.partition());
+            }
+        }
+    }
+}
+
+/// Where the consumer's records end up: Scylla and the dead-letter topic in production.
+pub(crate) trait ConsumerTarget {
+    async fn apply(&self, event: &PersistEvent, write_ts: i64) -> Result<(), String>;
+
+    /// Returns once the record is safely on the dead-letter topic.
+    async fn dead_letter<M: Message>(&self, msg: &M, reason: &str);
+
+    async fn load_applied_seq(&self, key: &StreamKey) -> Result<Option<(i64, u64)>, String>;
+
+    async fn store_applied_seq(&self, key: &StreamKey, epoch: i64, seq: u64) -> Result<(), String>;
+}
+
+pub(crate) struct ConsumerWorker<T> {
+    target: T,
+    sequences: Arc<Mutex<SequenceTracker>>,
+}
+
+impl<T: ConsumerTarget> ConsumerWorker<T> {
+    /// Applies, skips as a duplicate or dead-letters the record. `Ok` means its offset
+    /// may be committed. On `Err` its sequence was not recorded and it must be
+    /// delivered again.
+    async fn process<M: Message>(&mut self, msg: &M) -> Result<(), String> {
+        let position = record_position(msg);
 
-    while let Ok(msg) = consumer.recv().await {
-        if let Some(payload_bytes) = msg.payload() {
-            match wincode::deserialize::<PersistEvent>(payload_bytes) {
-                Ok(event) => {
-                    println!("[KAFKA CONSUMER] Processing event: {:?}", event);
-                    scylla.handle_event(event).await;
+        if let Some((key, epoch, seq)) = &position {
+            self.load_applied(key).await?;
+
+            let check = self.sequences.lock().check(key, *epoch, *seq);
+            match check {
+                SeqCheck::First | SeqCheck::InOrder => {}
+                SeqCheck::Gap { expected, got } => {
+                    KAFKA_SEQUENCE_GAPS_TOTAL.inc();
+                    eprintln!(
+                        "[KAFKA CONSUMER] Sequence gap on {}/{}/{}: expected {}, got {}",
+                        key.topic, key.partition, key.market, expected, got
+                    );
                 }
-                Err(e) => {
-                    eprintln!("[KAFKA CONSUMER] Deserialization error: {:?}", e);
+                SeqCheck::Duplicate => {
+                    KAFKA_DUPLICATES_TOTAL.inc();
+                    return Ok(());
                 }
             }
-        } else {
-            eprintln!("[KAFKA CONSUMER] Empty Kafka payload");
         }
+
+        let write_ts = match &position {
+            Some((_, epoch, seq)) => write_timestamp(*epoch, *seq),
+            None => write_timestamp(chrono::Utc::now().timestamp_millis(), 0),
+        };
+        if let Err(reason) = self.apply(msg, write_ts).await {
+            KAFKA_DEAD_LETTERED_TOTAL.inc();
+            eprintln!(
+                "[KAFKA CONSUMER] Dead-lettering {}/{}@{}: {}",
+                msg.topic(),
+                msg.partition(),
+                msg.offset(),
+                reason
+            );
+            self.target.dead_letter(msg, &reason).await;
+        }
+
+        if let Some((key, epoch, seq)) = position {
+            self.target
+                .store_applied_seq(&key, epoch, seq)
+                .await
+                .map_err(|e| format!("failed to record applied seq {}: {}", seq, e))?;
+            self.sequences.lock().advance(key, epoch, seq);
+        }
+        Ok(())
+    }
+
+    async fn apply<M: Message>(&self, msg: &M, write_ts: i64) -> Result<(), String> {
+        let payload = msg.payload().ok_or("empty payload")?;
+        let (_, event) = envelope::decode::<PersistEvent>(payload, MessageType::PersistEvent)
+            .map_err(|e| e.to_string())?;
+
+        self.target.apply(&event, write_ts).await
+    }
+
+    /// Seeds the tracker from Scylla the first time a stream is seen after startup or
+    /// after its partition was revoked, so records applied before are recognised.
+    async fn load_applied(&mut self, key: &StreamKey) -> Result<(), String> {
+        if self.sequences.lock().contains(key) {
+            return Ok(());
+        }
+
+        let applied = self
+            .target
+            .load_applied_seq(key)
+            .await
+            .map_err(|e| format!("failed to load applied seq: {}", e))?;
+        if let Some((epoch, seq)) = applied {
+            self.sequences.lock().advance(key.clone(), epoch, seq);
+        }
+        Ok(())
+    }
+}
+
+struct ScyllaTarget {
+    scylla: ScyllaClient,
+    dead_letter_producer: FutureProducer,
+    config: KafkaConfig,
+    retry: RetryPolicy,
+}
+
+impl ConsumerTarget for ScyllaTarget {
+    async fn apply(&self, event: &PersistEvent, write_ts: i64) -> Result<(), String> {
+        apply_with_retry(&self.scylla, event, write_ts, &self.retry)
+            .await
+            .map_err(|(e, attempts)| format!("failed after {} attempt(s): {}", attempts, e))
+    }
+
+    /// Forwards the original record, with its headers and the failure reason, to the
+    /// dead-letter topic. Retries until the broker accepts it: the offset must not be
+    /// committed before the record is safely somewhere.
+    async fn dead_letter<M: Message>(&self, msg: &M, reason: &str) {
+        let partition = msg.partition().to_string();
+        let offset = msg.offset().to_string();
+        let mut attempt = 1;
+
+        loop {
+            let mut headers = OwnedHeaders::new();
+            for header in msg.headers().into_iter().flat_map(|h| h.iter()) {
+                headers = headers.insert(header);
+            }
+            let headers = headers
+                .insert(Header {
+                    key: HEADER_ERROR,
+                    value: Some(reason),
+                })
+                .insert(Header {
+                    key: HEADER_SOURCE_TOPIC,
+                    value: Some(msg.topic()),
+                })
+                .insert(Header {
+                    key: HEADER_SOURCE_PARTITION,
+                    value: Some(&partition),
+                })
+                .insert(Header {
+                    key: HEADER_SOURCE_OFFSET,
+                    value: Some(&offset),
+                });
+
+            let mut record = FutureRecord::to(&self.config.dead_letter_topic).headers(headers);
+            if let Some(key) = msg.key() {
+                record = record.key(key);
+            }
+            if let Some(payload) = msg.payload() {
+                record = record.payload(payload);
+            }
+
+            match self.dead_letter_producer.send(record, Timeout::Never).await {
+                Ok(_) => return,
+                Err((e, _)) => {
+                    let delay = self.retry.backoff(attempt);
+                    eprintln!(
+                        "[KAFKA CONSUMER] Failed to dead-letter to {} (attempt {}), retrying in {:?}: {:?}",
+                        self.config.dead_letter_topic, attempt, delay, e
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    async fn load_applied_seq(&self, key: &StreamKey) -> Result<Option<(i64, u64)>, String> {
+        self.scylla
+            .load_applied_seq(&key.topic, key.partition, &key.market)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn store_applied_seq(&self, key: &StreamKey, epoch: i64, seq: u64) -> Result<(), String> {
+        self.scylla
+            .store_applied_seq(&key.topic, key.partition, &key.market, epoch, seq)
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Reads the (stream, epoch, seq) position stamped by the producer, if present.
+fn record_position<M: Message>(msg: &M) -> Option<(StreamKey, i64, u64)> {
+    let headers = msg.headers()?;
+    let mut market = None;
+    let mut epoch = None;
+    let mut seq = None;
+
+    for header in headers.iter() {
+        let Some(value) = header.value.and_then(|v| std::str::from_utf8(v).ok()) else {
+            continue;
+        };
+        match header.key {
+            HEADER_MARKET => market = Some(value),
+            HEADER_EPOCH => epoch = value.parse().ok(),
+            HEADER_SEQ => seq = value.parse().ok(),
+            _ => {}
+        }
+    }
+
+    let key = StreamKey {
+        topic: msg.topic().to_string(),
+        partition: msg.partition(),
+        market: market?.to_string(),
+    };
+    Some((key, epoch?, seq?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdkafka::Timestamp;
+    use rdkafka::message::OwnedMessage;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    const EPOCH: i64 = 1_000;
+
+    #[derive(Default)]
+    struct FakeTarget {
+        /// (order_id, write_ts) of every apply.
+        applied: Mutex<Vec<(u32, i64)>>,
+        dead_letters: Mutex<Vec<String>>,
+        stored: Mutex<HashMap<StreamKey, (i64, u64)>>,
+        fail_store: AtomicBool,
+    }
+
+    impl ConsumerTarget for Arc<FakeTarget> {
+        async fn apply(&self, event: &PersistEvent, write_ts: i64) -> Result<(), String> {
+            self.applied
+                .lock()
+                .push((event.order_id().unwrap(), write_ts));
+            Ok(())
+        }
+
+        async fn dead_letter<M: Message>(&self, _msg: &M, reason: &str) {
+            self.dead_letters.lock().push(reason.to_string());
+        }
+
+        async fn load_applied_seq(&self, key: &StreamKey) -> Result<Option<(i64, u64)>, String> {
+            Ok(self.stored.lock().get(key).copied())
+        }
+
+        async fn store_applied_seq(
+            &self,
+            key: &StreamKey,
+            epoch: i64,
+            seq: u64,
+        ) -> Result<(), String> {
+            if self.fail_store.load(Ordering::Relaxed) {
+                return Err("unavailable".to_string());
+            }
+            self.stored.lock().insert(key.clone(), (epoch, seq));
+            Ok(())
+        }
+    }
+
+    fn worker() -> (ConsumerWorker<Arc<FakeTarget>>, Arc<FakeTarget>) {
+        let target = Arc::new(FakeTarget::default());
+        let worker = ConsumerWorker {
+            target: target.clone(),
+            sequences: Arc::new(Mutex::new(SequenceTracker::new())),
+        };
+        (worker, target)
+    }
+
+    fn key() -> StreamKey {
+        StreamKey {
+            topic: "orders".to_string(),
+            partition: 0,
+            market: "BTC-USDT".to_string(),
+        }
+    }
+
+    fn record(seq: u64, payload: Vec<u8>) -> OwnedMessage {
+        let headers = OwnedHeaders::new()
+            .insert(Header {
+                key: HEADER_MARKET,
+                value: Some("BTC-USDT"),
+            })
+            .insert(Header {
+                key: HEADER_EPOCH,
+                value: Some(&EPOCH.to_string()),
+            })
+            .insert(Header {
+                key: HEADER_SEQ,
+                value: Some(&seq.to_string()),
+            });
+        OwnedMessage::new(
+            Some(payload),
+            Some(b"BTC-USDT".to_vec()),
+            "orders".to_string(),
+            Timestamp::NotAvailable,
+            0,
+            seq as i64,
+            Some(headers),
+        )
+    }
+
+    fn deleted(seq: u64, order_id: u32) -> OwnedMessage {
+        let event = PersistEvent::OrderDeleted { order_id };
+        let payload =
+            envelope::encode(MessageType::PersistEvent, "BTC-USDT", seq, 0, &event).unwrap();
+        record(seq, payload)
+    }
+
+    #[tokio::test]
+    async fn applies_each_record_once_and_skips_redeliveries() {
+        let (mut worker, target) = worker();
+
+        worker.process(&deleted(1, 10)).await.unwrap();
+        worker.process(&deleted(2, 20)).await.unwrap();
+        worker.process(&deleted(1, 10)).await.unwrap();
+
+        assert_eq!(
+            *target.applied.lock(),
+            vec![
+                (10, write_timestamp(EPOCH, 1)),
+                (20, write_timestamp(EPOCH, 2))
+            ]
+        );
+        assert_eq!(target.stored.lock()[&key()], (EPOCH, 2));
+    }
+
+    #[tokio::test]
+    async fn a_record_whose_seq_was_not_stored_is_not_completed() {
+        let (mut worker, target) = worker();
+        target.fail_store.store(true, Ordering::Relaxed);
+
+        assert!(worker.process(&deleted(1, 10)).await.is_err());
+        assert!(!worker.sequences.lock().contains(&key()));
+
+        // The redelivery is applied again (harmless, writes are idempotent) and completes.
+        target.fail_store.store(false, Ordering::Relaxed);
+        worker.process(&deleted(1, 10)).await.unwrap();
+        assert_eq!(target.applied.lock().len(), 2);
+        assert_eq!(target.stored.lock()[&key()], (EPOCH, 1));
+    }
+
+    #[tokio::test]
+    async fn resumes_from_the_stored_position() {
+        let (mut worker, target) = worker();
+        target.stored.lock().insert(key(), (EPOCH, 5));
+
+        worker.process(&deleted(5, 50)).await.unwrap();
+        worker.process(&deleted(6, 60)).await.unwrap();
+
+        assert_eq!(
+            *target.applied.lock(),
+            vec![(60, write_timestamp(EPOCH, 6))]
+        );
+    }
+
+    #[tokio::test]
+    async fn undecodable_records_are_dead_lettered_and_completed() {
+        let (mut worker, target) = worker();
+
+        worker
+            .process(&record(1, b"garbage".to_vec()))
+            .await
+            .unwrap();
+
+        assert!(target.applied.lock().is_empty());
+        assert_eq!(target.dead_letters.lock().len(), 1);
+        assert_eq!(target.stored.lock()[&key()], (EPOCH, 1));
+    }
+
+    #[tokio::test]
+    async fn a_revoked_partition_is_reloaded_from_the_store() {
+        let (mut worker, target) = worker();
+        worker.process(&deleted(1, 10)).await.unwrap();
+
+        // Another consumer owned the partition meanwhile and applied up to seq 3.
+        worker.sequences.lock().forget_partition("orders", 0);
+        target.stored.lock().insert(key(), (EPOCH, 3));
+
+        worker.process(&deleted(3, 30)).await.unwrap();
+        worker.process(&deleted(4, 40)).await.unwrap();
+        assert_eq!(
+            target.applied.lock().last(),
+            Some(&(40, write_timestamp(EPOCH, 4)))
+        );
+        assert_eq!(target.applied.lock().len(), 2);
     }
 }
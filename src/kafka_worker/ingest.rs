@@ -0,0 +1,582 @@
+use crate::ORDER_ID_COUNTER;
+use crate::config::KafkaConfig;
+use crate::envelope::{self, MessageType};
+use crate::events::{OrderEvent, OrderRequest, Verdict};
+use crate::kafka_worker::producer::{create_kafka_producer, enqueue};
+use crate::metrics::{KAFKA_ORDERS_INGESTED_TOTAL, KAFKA_ORDERS_REJECTED_TOTAL};
+use crate::outputs::{AckStatus, OrderAck};
+use crate::routes::OrderSender;
+use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
+use rdkafka::error::KafkaError;
+use rdkafka::message::{Header, Headers, OwnedHeaders};
+use rdkafka::producer::{DeliveryFuture, FutureProducer, FutureRecord};
+use rdkafka::{Message, Offset, TopicPartitionList};
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+
+pub const HEADER_CLIENT_ORDER_ID: &str = "client_order_id";
+
+/// How many recent client order ids are remembered to reject redelivered requests.
+///
+/// Deduplication is best-effort: the ids live only in memory. A request redelivered after
+/// a restart or a rebalance (its offset not yet committed when the previous owner
+/// stopped) is accepted again. The window is small, since offsets are committed as soon
+/// as the ack is delivered, but clients that cannot tolerate a duplicate must reconcile
+/// through the acks or their `orders` stream.
+const RECENT_CLIENT_IDS: usize = 100_000;
+
+/// How long to wait before sending an ack again after its delivery failed.
+const ACK_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Consumes `OrderEvent`s from `order_input_topic` and queues them for matching exactly like
+/// `POST /order` / `DELETE /order` do. Every request gets an `OrderAck` on
+/// `order_response_topic`, keyed by the client order id taken from the `client_order_id`
+/// header (or the record key). `order_id` on a `NewOrder` is ignored; the engine assigns it.
+///
+/// A queued request is acked with the engine's verdict, so a cancel of someone else's
+/// order is `Rejected`. Acks are sent without waiting for the broker between requests,
+/// and a request's offset is committed only once its ack has been delivered; a failed ack
+/// is sent again until it is, holding back the commits behind it.
+pub async fn start_kafka_order_ingest_worker(
+    config: KafkaConfig,
+    market: String,
+    sender: OrderSender,
+) {
+    let consumer: Arc<StreamConsumer> = Arc::new(
+        config
+            .consumer_config_for(&config.order_ingest_group)
+            .create()
+            .expect("Failed to create Kafka order ingest consumer"),
+    );
+
+    consumer
+        .subscribe(&[&config.order_input_topic])
+        .expect("Failed to subscribe to order input topic");
+
+    println!(
+        "[KAFKA INGEST] Accepting orders from '{}', replying on '{}'",
+        config.order_input_topic, config.order_response_topic
+    );
+
+    let producer = create_kafka_producer(&config);
+    let (ack_tx, ack_rx) = mpsc::unbounded_channel();
+    let (delivery_tx, delivery_rx) = mpsc::unbounded_channel();
+    tokio::spawn(send_acks(
+        ack_rx,
+        delivery_tx,
+        producer.clone(),
+        config.clone(),
+        market.clone(),
+    ));
+    tokio::spawn(commit_delivered(
+        delivery_rx,
+        consumer.clone(),
+        producer,
+        config,
+    ));
+
+    let mut ingest = OrderIngest {
+        market,
+        sender,
+        recent: RecentIds::new(RECENT_CLIENT_IDS),
+    };
+
+    loop {
+        let msg = match consumer.recv().await {
+            Ok(msg) => msg,
+            Err(e) => {
+                eprintln!("[KAFKA INGEST] Receive error: {:?}", e);
+                continue;
+            }
+        };
+
+        let mut commit = TopicPartitionList::new();
+        if let Err(e) = commit.add_partition_offset(
+            msg.topic(),
+            msg.partition(),
+            Offset::Offset(msg.offset() + 1),
+        ) {
+            eprintln!("[KAFKA INGEST] Invalid offset to commit: {:?}", e);
+        }
+        let source = format!("{}/{}@{}", msg.topic(), msg.partition(), msg.offset());
+
+        let _ = ack_tx.send(IngestedRequest {
+            ack: ingest.accept(&msg),
+            source,
+            commit,
+        });
+    }
+}
+
+/// A consumed request, in consumption order, waiting for its ack to be sent.
+struct IngestedRequest {
+    ack: PendingAck,
+    /// `topic/partition@offset`, for logs.
+    source: String,
+    commit: TopicPartitionList,
+}
+
+/// An ack handed to the producer, and the offset to commit once it is delivered.
+struct AckDelivery {
+    client_order_id: String,
+    /// `None` if the ack could not be encoded; the offset is then committed without one.
+    payload: Option<Vec<u8>>,
+    delivery: Result<DeliveryFuture, KafkaError>,
+    commit: TopicPartitionList,
+}
+
+/// Settles each ack in order and queues it on the producer.
+async fn send_acks(
+    mut rx: UnboundedReceiver<IngestedRequest>,
+    deliveries: UnboundedSender<AckDelivery>,
+    producer: FutureProducer,
+    config: KafkaConfig,
+    market: String,
+) {
+    let queue_timeout = Duration::from_millis(config.message_timeout_ms.into());
+
+    while let Some(request) = rx.recv().await {
+        let ack = request.ack.settle().await;
+        match ack.status {
+            AckStatus::Accepted => KAFKA_ORDERS_INGESTED_TOTAL.inc(),
+            AckStatus::Rejected => {
+                KAFKA_ORDERS_REJECTED_TOTAL.inc();
+                eprintln!(
+                    "[KAFKA INGEST] Rejected '{}' from {}: {}",
+                    ack.client_order_id, request.source, ack.reason
+                );
+            }
+        }
+
+        let payload = match envelope::encode(
+            MessageType::OrderAck,
+            &market,
+            0,
+            chrono::Utc::now().timestamp_millis(),
+            &ack,
+        ) {
+            Ok(payload) => Some(payload),
+            Err(e) => {
+                eprintln!("[KAFKA INGEST] Failed to encode ack: {}", e);
+                None
+            }
+        };
+        let delivery = match &payload {
+            Some(payload) => {
+                let record = ack_record(&config, &ack.client_order_id, payload);
+                enqueue(&producer, record, queue_timeout).await
+            }
+            None => Err(KafkaError::Canceled),
+        };
+
+        let _ = deliveries.send(AckDelivery {
+            client_order_id: ack.client_order_id,
+            payload,
+            delivery,
+            commit: request.commit,
+        });
+    }
+}
+
+/// Awaits ack deliveries in order, sending failed ones again, and commits each request's
+/// offset once its ack is out.
+async fn commit_delivered(
+    mut rx: UnboundedReceiver<AckDelivery>,
+    consumer: Arc<StreamConsumer>,
+    producer: FutureProducer,
+    config: KafkaConfig,
+) {
+    let queue_timeout = Duration::from_millis(config.message_timeout_ms.into());
+
+    while let Some(mut ack) = rx.recv().await {
+        if let Some(payload) = &ack.payload {
+            loop {
+                let error = match ack.delivery {
+                    Ok(delivery) => match delivery.await {
+                        Ok(Ok(_)) => break,
+                        Ok(Err((e, _))) => e.to_string(),
+                        Err(_) => "producer dropped".to_string(),
+                    },
+                    Err(e) => e.to_string(),
+                };
+                eprintln!(
+                    "[KAFKA INGEST] Failed to send ack for '{}', retrying: {}",
+                    ack.client_order_id, error
+                );
+                tokio::time::sleep(ACK_RETRY_BACKOFF).await;
+                let record = ack_record(&config, &ack.client_order_id, payload);
+                ack.delivery = enqueue(&producer, record, queue_timeout).await;
+            }
+        }
+
+        if let Err(e) = consumer.commit(&ack.commit, CommitMode::Async) {
+            eprintln!("[KAFKA INGEST] Failed to commit offset: {:?}", e);
+        }
+    }
+}
+
+fn ack_record<'a>(
+    config: &'a KafkaConfig,
+    client_order_id: &'a str,
+    payload: &'a Vec<u8>,
+) -> FutureRecord<'a, str, Vec<u8>> {
+    let headers = OwnedHeaders::new().insert(Header {
+        key: HEADER_CLIENT_ORDER_ID,
+        value: Some(client_order_id),
+    });
+    FutureRecord::to(&config.order_response_topic)
+        .key(client_order_id)
+        .payload(payload)
+        .headers(headers)
+}
+
+/// An `OrderAck` that, for a request queued for the engine, still waits on its verdict.
+struct PendingAck {
+    ack: OrderAck,
+    verdict: Option<oneshot::Receiver<Verdict>>,
+}
+
+impl PendingAck {
+    async fn settle(mut self) -> OrderAck {
+        if let Some(verdict) = self.verdict {
+            let refused = match verdict.await {
+                Ok(verdict) => verdict,
+                Err(_) => Some("order processing unavailable"),
+            };
+            if let Some(reason) = refused {
+                self.ack.status = AckStatus::Rejected;
+                self.ack.reason = reason.to_string();
+            }
+        }
+        self.ack
+    }
+}
+
+struct OrderIngest {
+    market: String,
+    sender: OrderSender,
+    recent: RecentIds,
+}
+
+impl OrderIngest {
+    /// Checks a request and queues it for the engine. The client order id of a queued
+    /// request is remembered even if the engine then refuses it, so it cannot be reused.
+    fn accept<M: Message>(&mut self, msg: &M) -> PendingAck {
+        let client_order_id = client_order_id(msg).unwrap_or_default();
+        let reject = |reason: &str| PendingAck {
+            ack: OrderAck {
+                client_order_id: client_order_id.clone(),
+                order_id: 0,
+                status: AckStatus::Rejected,
+                reason: reason.to_string(),
+            },
+            verdict: None,
+        };
+
+        if client_order_id.is_empty() {
+            return reject("missing client_order_id");
+        }
+
+        let Some(payload) = msg.payload() else {
+            return reject("empty payload");
+        };
+
+        let (header, event) = match envelope::decode::<OrderEvent>(payload, MessageType::OrderEvent)
+        {
+            Ok(decoded) => decoded,
+            Err(e) => return reject(&e.to_string()),
+        };
+
+        if !header.market.is_empty() && header.market != self.market {
+            return reject(&format!("unknown market '{}'", header.market));
+        }
+
+        if self.recent.contains(&client_order_id) {
+            return reject("duplicate client_order_id");
+        }
+
+        let (event, order_id) = match event {
+            OrderEvent::NewOrder {
+                user_id,
+                price,
+                quantity,
+                side,
+                ..
+            } => {
+                if price == 0 || quantity == 0 {
+                    return reject("price and quantity must be positive");
+                }
+                let order_id = ORDER_ID_COUNTER.fetch_add(1, Ordering::SeqCst);
+                let event = OrderEvent::NewOrder {
+                    order_id,
+                    user_id,
+                    price,
+                    quantity,
+                    side,
+                };
+                (event, order_id)
+            }
+            OrderEvent::AmendOrder {
+                order_id,
+                price,
+                quantity,
+                ..
+            } => {
+                if price == 0 || quantity == 0 {
+                    return reject("price and quantity must be positive");
+                }
+                (event, order_id)
+            }
+            OrderEvent::DeleteOrder { order_id } | OrderEvent::CancelOrder { order_id, .. } => {
+                (event, order_id)
+            }
+        };
+
+        let (request, verdict) = OrderRequest::with_reply(event);
+        if self.sender.send(request).is_err() {
+            return reject("order processing unavailable");
+        }
+
+        self.recent.insert(client_order_id.clone());
+
+        PendingAck {
+            ack: OrderAck {
+                client_order_id,
+                order_id,
+                status: AckStatus::Accepted,
+                reason: String::new(),
+            },
+            verdict: Some(verdict),
+        }
+    }
+}
+
+fn client_order_id<M: Message>(msg: &M) -> Option<String> {
+    let from_header = msg.headers().and_then(|headers| {
+        headers
+            .iter()
+            .find(|h| h.key == HEADER_CLIENT_ORDER_ID)
+            .and_then(|h| h.value)
+            .and_then(|v| std::str::from_utf8(v).ok())
+            .map(str::to_owned)
+    });
+
+    from_header.or_else(|| {
+        msg.key()
+            .and_then(|k| std::str::from_utf8(k).ok())
+            .map(str::to_owned)
+    })
+}
+
+/// Bounded set of the most recently seen ids, oldest evicted first.
+struct RecentIds {
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+    capacity: usize,
+}
+
+impl RecentIds {
+    fn new(capacity: usize) -> Self {
+        Self {
+            ids: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    fn contains(&self, id: &str) -> bool {
+        self.ids.contains(id)
+    }
+
+    fn insert(&mut self, id: String) {
+        if self.order.len() == self.capacity
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.ids.remove(&oldest);
+        }
+        self.ids.insert(id.clone());
+        self.order.push_back(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inputs::Side;
+    use rdkafka::Timestamp;
+    use rdkafka::message::OwnedMessage;
+
+    fn ingest(capacity: usize) -> (OrderIngest, mpsc::UnboundedReceiver<OrderRequest>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let ingest = OrderIngest {
+            market: "BTC-USDT".to_string(),
+            sender: Arc::new(tx),
+            recent: RecentIds::new(capacity),
+        };
+        (ingest, rx)
+    }
+
+    fn request(
+        header_id: Option<&str>,
+        key: Option<&str>,
+        market: &str,
+        event: &OrderEvent,
+    ) -> OwnedMessage {
+        let payload = envelope::encode(MessageType::OrderEvent, market, 0, 0, event).unwrap();
+        let headers = header_id.map(|id| {
+            OwnedHeaders::new().insert(Header {
+                key: HEADER_CLIENT_ORDER_ID,
+                value: Some(id),
+            })
+        });
+        OwnedMessage::new(
+            Some(payload),
+            key.map(|k| k.as_bytes().to_vec()),
+            "orders.in".to_string(),
+            Timestamp::NotAvailable,
+            0,
+            0,
+            headers,
+        )
+    }
+
+    fn new_order(price: u32, quantity: u32) -> OrderEvent {
+        OrderEvent::NewOrder {
+            order_id: 0,
+            user_id: 7,
+            price,
+            quantity,
+            side: Side::Buy,
+        }
+    }
+
+    /// Answers every queued request with `verdict`, as the matching loop would, and returns
+    /// their events.
+    fn engine(rx: &mut mpsc::UnboundedReceiver<OrderRequest>, verdict: Verdict) -> Vec<OrderEvent> {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|request| {
+                if let Some(reply) = request.reply {
+                    let _ = reply.send(verdict);
+                }
+                request.event
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn accepted_order_gets_an_engine_assigned_id() {
+        let (mut ingest, mut rx) = ingest(4);
+        let pending = ingest.accept(&request(Some("c1"), None, "BTC-USDT", &new_order(100, 5)));
+        let events = engine(&mut rx, None);
+        let ack = pending.settle().await;
+
+        assert!(matches!(ack.status, AckStatus::Accepted));
+        assert_eq!(ack.client_order_id, "c1");
+        match events[..] {
+            [OrderEvent::NewOrder { order_id, .. }] => assert_eq!(order_id, ack.order_id),
+            ref other => panic!("unexpected events {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_refused_by_the_engine_are_acked_rejected() {
+        let (mut ingest, mut rx) = ingest(4);
+        let cancel = OrderEvent::CancelOrder {
+            order_id: 3,
+            user_id: 7,
+        };
+        let pending = ingest.accept(&request(Some("c1"), None, "BTC-USDT", &cancel));
+        engine(&mut rx, Some("order belongs to another user"));
+        let ack = pending.settle().await;
+
+        assert!(matches!(ack.status, AckStatus::Rejected));
+        assert_eq!(ack.order_id, 3);
+        assert_eq!(ack.reason, "order belongs to another user");
+
+        let pending = ingest.accept(&request(Some("c2"), None, "BTC-USDT", &cancel));
+        drop(rx);
+        assert_eq!(
+            pending.settle().await.reason,
+            "order processing unavailable"
+        );
+    }
+
+    #[tokio::test]
+    async fn duplicate_client_order_id_is_rejected() {
+        let (mut ingest, mut rx) = ingest(4);
+        let msg = request(Some("c1"), None, "BTC-USDT", &new_order(100, 5));
+        ingest.accept(&msg);
+        let ack = ingest.accept(&msg).settle().await;
+
+        assert!(matches!(ack.status, AckStatus::Rejected));
+        assert_eq!(ack.reason, "duplicate client_order_id");
+        assert_eq!(
+            engine(&mut rx, None).len(),
+            1,
+            "duplicate must not reach the engine"
+        );
+    }
+
+    #[tokio::test]
+    async fn oldest_client_order_id_is_forgotten_past_capacity() {
+        let (mut ingest, mut rx) = ingest(2);
+        for id in ["c1", "c2", "c3"] {
+            ingest.accept(&request(Some(id), None, "BTC-USDT", &new_order(100, 5)));
+        }
+
+        let mut again = |id| {
+            let pending = ingest.accept(&request(Some(id), None, "BTC-USDT", &new_order(100, 5)));
+            engine(&mut rx, None);
+            pending.settle()
+        };
+        assert!(matches!(again("c3").await.status, AckStatus::Rejected));
+        assert!(matches!(again("c1").await.status, AckStatus::Accepted));
+    }
+
+    #[tokio::test]
+    async fn client_order_id_falls_back_to_the_key() {
+        let (mut ingest, mut rx) = ingest(4);
+        let pending = ingest.accept(&request(None, Some("k1"), "BTC-USDT", &new_order(100, 5)));
+        engine(&mut rx, None);
+        let ack = pending.settle().await;
+        assert!(matches!(ack.status, AckStatus::Accepted));
+        assert_eq!(ack.client_order_id, "k1");
+
+        let pending = ingest.accept(&request(None, None, "BTC-USDT", &new_order(100, 5)));
+        assert_eq!(pending.settle().await.reason, "missing client_order_id");
+    }
+
+    #[tokio::test]
+    async fn invalid_requests_are_rejected_without_reaching_the_engine() {
+        let (mut ingest, mut rx) = ingest(4);
+        let reason = |ingest: &mut OrderIngest, id, market, event: &OrderEvent| {
+            ingest
+                .accept(&request(Some(id), None, market, event))
+                .settle()
+        };
+        let ack = reason(&mut ingest, "c1", "ETH-USDT", &new_order(100, 5)).await;
+        assert_eq!(ack.reason, "unknown market 'ETH-USDT'");
+
+        let ack = reason(&mut ingest, "c2", "BTC-USDT", &new_order(0, 5)).await;
+        assert_eq!(ack.reason, "price and quantity must be positive");
+
+        let amend = OrderEvent::AmendOrder {
+            order_id: 3,
+            user_id: 7,
+            price: 100,
+            quantity: 0,
+        };
+        let ack = reason(&mut ingest, "c3", "BTC-USDT", &amend).await;
+        assert_eq!(ack.reason, "price and quantity must be positive");
+
+        assert!(rx.try_recv().is_err());
+        // A rejected id is not remembered, so a corrected retry goes through.
+        let pending = ingest.accept(&request(Some("c2"), None, "BTC-USDT", &new_order(100, 5)));
+        engine(&mut rx, None);
+        assert!(matches!(pending.settle().await.status, AckStatus::Accepted));
+    }
+}
@@ -3,3 +3,9 @@ pub use producer::*;
 
 pub mod consumer;
 pub use consumer::*;
+
+pub mod sequence;
+pub use sequence::*;
+
+pub mod ingest;
+pub use ingest::*;
@@ -1,34 +1,165 @@
-use crate::persist::event::PersistEvent;
-use rdkafka::producer::{FutureProducer, FutureRecord};
-use rdkafka::util::Timeout;
-use tokio::sync::mpsc::UnboundedReceiver;
+use crate::config::KafkaConfig;
+use crate::envelope::{self, MessageType};
+use crate::metrics::KAFKA_PRODUCE_FAILURES_TOTAL;
+use crate::persist::event::{PersistRecord, PersistStream};
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::message::{Header, OwnedHeaders};
+use rdkafka::producer::{DeliveryFuture, FutureProducer, FutureRecord};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
 
+pub const HEADER_MARKET: &str = "market";
+pub const HEADER_EPOCH: &str = "epoch";
+pub const HEADER_SEQ: &str = "seq";
+pub const HEADER_ORDER_ID: &str = "order_id";
+
+pub fn create_kafka_producer(config: &KafkaConfig) -> FutureProducer {
+    config
+        .producer_config()
+        .create()
+        .expect("Failed to create Kafka producer")
+}
+
+/// How often a full local queue is re-checked while enqueuing.
+const QUEUE_FULL_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Sends every record to Kafka without waiting for the broker between records.
+///
+/// Records are handed to librdkafka's local queue in order and their deliveries are
+/// awaited on a separate task. Idempotence keeps the broker-side order. A full queue
+/// is waited on for at most `message_timeout_ms`, and a delivery that does not succeed
+/// within `message_timeout_ms` fails. Either way the record is counted in
+/// `kafka_produce_failures_total` and logged, so a stuck broker shows up as errors
+/// rather than as a stalled pipeline.
 pub async fn start_kafka_producer_worker(
-    mut rx: UnboundedReceiver<PersistEvent>,
+    mut rx: UnboundedReceiver<PersistRecord>,
     producer: FutureProducer,
+    config: KafkaConfig,
 ) {
+    let (delivery_tx, delivery_rx) = mpsc::unbounded_channel();
+    tokio::spawn(report_deliveries(delivery_rx));
+
     tokio::spawn(async move {
-        while let Some(event) = rx.recv().await {
-            match wincode::serialize(&event) {
-                Ok(payload) => {
-                    let topic = match event {
-                        PersistEvent::TradeExecuted { .. } => "trades",
-                        _ => "orders",
-                    };
-                    if let Err((e, _)) = producer
-                        .send(
-                            FutureRecord::to(topic).payload(&payload).key("clob-event"),
-                            Timeout::Never,
-                        )
-                        .await
-                    {
-                        eprintln!("[KAFKA PRODUCER] Error sending to {}: {:?}", topic, e);
-                    }
-                }
+        let queue_timeout = Duration::from_millis(config.message_timeout_ms.into());
+
+        while let Some(record) = rx.recv().await {
+            let payload = envelope::encode(
+                MessageType::PersistEvent,
+                &record.market,
+                record.seq,
+                chrono::Utc::now().timestamp_millis(),
+                &record.event,
+            );
+
+            let payload = match payload {
+                Ok(payload) => payload,
                 Err(e) => {
                     eprintln!("[KAFKA PRODUCER] Serialization error: {:?}", e);
+                    continue;
+                }
+            };
+
+            let topic = match record.event.stream() {
+                PersistStream::Trades => &config.trades_topic,
+                PersistStream::Orders => &config.orders_topic,
+            };
+
+            // Headers repeat the envelope's market and seq (plus the engine epoch) so the
+            // consumer and tooling can route and dedupe without decoding the payload.
+            //
+            // Keyed by market: every event of a market lands on the same partition of
+            // its topic, so per-market order survives any partition count.
+            let kafka_record = FutureRecord::to(topic)
+                .payload(&payload)
+                .key(&*record.market)
+                .headers(record_headers(&record));
+
+            match enqueue(&producer, kafka_record, queue_timeout).await {
+                Ok(delivery) => {
+                    let _ = delivery_tx.send(PendingDelivery {
+                        topic: topic.clone(),
+                        seq: record.seq,
+                        delivery,
+                    });
+                }
+                Err(e) => {
+                    KAFKA_PRODUCE_FAILURES_TOTAL.inc();
+                    eprintln!(
+                        "[KAFKA PRODUCER] Could not queue seq {} for {}: {:?}",
+                        record.seq, topic, e
+                    );
                 }
             }
         }
     });
 }
+
+/// Hands a record to the producer's local queue, waiting up to `timeout` while it is full.
+pub(crate) async fn enqueue(
+    producer: &FutureProducer,
+    mut record: FutureRecord<'_, str, Vec<u8>>,
+    timeout: Duration,
+) -> Result<DeliveryFuture, KafkaError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match producer.send_result(record) {
+            Ok(delivery) => return Ok(delivery),
+            Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), returned))
+                if Instant::now() < deadline =>
+            {
+                record = returned;
+                tokio::time::sleep(QUEUE_FULL_BACKOFF).await;
+            }
+            Err((e, _)) => return Err(e),
+        }
+    }
+}
+
+struct PendingDelivery {
+    topic: String,
+    seq: u64,
+    delivery: DeliveryFuture,
+}
+
+/// Awaits deliveries in the order the records were queued and reports failures.
+async fn report_deliveries(mut rx: UnboundedReceiver<PendingDelivery>) {
+    while let Some(pending) = rx.recv().await {
+        let error = match pending.delivery.await {
+            Ok(Ok(_)) => continue,
+            Ok(Err((e, _))) => e.to_string(),
+            Err(_) => "producer dropped".to_string(),
+        };
+        KAFKA_PRODUCE_FAILURES_TOTAL.inc();
+        eprintln!(
+            "[KAFKA PRODUCER] Error sending seq {} to {}: {}",
+            pending.seq, pending.topic, error
+        );
+    }
+}
+
+fn record_headers(record: &PersistRecord) -> OwnedHeaders {
+    let epoch = record.epoch.to_string();
+    let seq = record.seq.to_string();
+
+    let headers = OwnedHeaders::new_with_capacity(4)
+        .insert(Header {
+            key: HEADER_MARKET,
+            value: Some(&*record.market),
+        })
+        .insert(Header {
+            key: HEADER_EPOCH,
+            value: Some(&epoch),
+        })
+        .insert(Header {
+            key: HEADER_SEQ,
+            value: Some(&seq),
+        });
+
+    match record.event.order_id() {
+        Some(order_id) => headers.insert(Header {
+            key: HEADER_ORDER_ID,
+            value: Some(&order_id.to_string()),
+        }),
+        None => headers,
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StreamKey {
+    pub topic: String,
+    pub partition: i32,
+    pub market: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeqCheck {
+    /// First record seen for this stream, or the first of a newer engine epoch.
+    First,
+    InOrder,
+    /// Records between `expected` and the received one never arrived.
+    Gap {
+        expected: u64,
+        got: u64,
+    },
+    /// Already applied (or from an older epoch); safe to skip.
+    Duplicate,
+}
+
+/// Tracks the last applied sequence number per partition and market to flag gaps and
+/// duplicates. Only `advance` moves the position, so a record that failed to apply is
+/// checked again on redelivery.
+#[derive(Debug, Default)]
+pub struct SequenceTracker {
+    applied: HashMap<StreamKey, (i64, u64)>,
+}
+
+impl SequenceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(&self, key: &StreamKey) -> bool {
+        self.applied.contains_key(key)
+    }
+
+    pub fn check(&self, key: &StreamKey, epoch: i64, seq: u64) -> SeqCheck {
+        let Some(&(last_epoch, last_seq)) = self.applied.get(key) else {
+            return SeqCheck::First;
+        };
+
+        if epoch < last_epoch || (epoch == last_epoch && seq <= last_seq) {
+            SeqCheck::Duplicate
+        } else if epoch > last_epoch {
+            SeqCheck::First
+        } else if seq == last_seq + 1 {
+            SeqCheck::InOrder
+        } else {
+            SeqCheck::Gap {
+                expected: last_seq + 1,
+                got: seq,
+            }
+        }
+    }
+
+    pub fn advance(&mut self, key: StreamKey, epoch: i64, seq: u64) {
+        self.applied.insert(key, (epoch, seq));
+    }
+
+    /// Forgets every market's position on a partition, e.g. once it is revoked.
+    pub fn forget_partition(&mut self, topic: &str, partition: i32) {
+        self.applied
+            .retain(|key, _| key.topic != topic || key.partition != partition);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(partition: i32) -> StreamKey {
+        StreamKey {
+            topic: "orders".to_string(),
+            partition,
+            market: "BTC-USDT".to_string(),
+        }
+    }
+
+    #[test]
+    fn first_record_then_in_order() {
+        let mut tracker = SequenceTracker::new();
+        assert!(!tracker.contains(&key(0)));
+        assert_eq!(tracker.check(&key(0), 10, 1), SeqCheck::First);
+
+        tracker.advance(key(0), 10, 1);
+        assert!(tracker.contains(&key(0)));
+        assert_eq!(tracker.check(&key(0), 10, 2), SeqCheck::InOrder);
+        // Streams are tracked independently.
+        assert_eq!(tracker.check(&key(1), 10, 7), SeqCheck::First);
+    }
+
+    #[test]
+    fn gaps_and_duplicates() {
+        let mut tracker = SequenceTracker::new();
+        tracker.advance(key(0), 10, 5);
+
+        assert_eq!(
+            tracker.check(&key(0), 10, 9),
+            SeqCheck::Gap {
+                expected: 6,
+                got: 9
+            }
+        );
+        assert_eq!(tracker.check(&key(0), 10, 5), SeqCheck::Duplicate);
+        assert_eq!(tracker.check(&key(0), 10, 1), SeqCheck::Duplicate);
+    }
+
+    #[test]
+    fn a_newer_epoch_restarts_the_sequence_and_an_older_one_is_stale() {
+        let mut tracker = SequenceTracker::new();
+        tracker.advance(key(0), 10, 500);
+
+        assert_eq!(tracker.check(&key(0), 11, 1), SeqCheck::First);
+        assert_eq!(tracker.check(&key(0), 9, 501), SeqCheck::Duplicate);
+
+        tracker.advance(key(0), 11, 1);
+        assert_eq!(tracker.check(&key(0), 11, 2), SeqCheck::InOrder);
+        assert_eq!(tracker.check(&key(0), 10, 501), SeqCheck::Duplicate);
+    }
+
+    #[test]
+    fn checking_does_not_advance() {
+        let mut tracker = SequenceTracker::new();
+        tracker.advance(key(0), 10, 1);
+        assert_eq!(tracker.check(&key(0), 10, 2), SeqCheck::InOrder);
+        // Not applied (e.g. the apply failed), so the redelivery is still in order.
+        assert_eq!(tracker.check(&key(0), 10, 2), SeqCheck::InOrder);
+    }
+}
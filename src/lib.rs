@@ -1,7 +1,11 @@
+pub mod auth;
+pub mod config;
+pub mod envelope;
 pub mod events;
 pub mod inputs;
 
 pub mod kafka_worker;
+pub mod market_data;
 pub mod matching_loop;
 pub mod metrics;
 pub mod msgpack;
@@ -1,5 +1,4 @@
 use actix_web::{App, HttpServer, web::Data};
-use parking_lot::RwLock;
 use ringbuf::HeapRb;
 use ringbuf::traits::Producer;
 use ringbuf::traits::Split;
@@ -7,17 +6,32 @@ use std::sync::Arc;
 use std::sync::atomic::AtomicU32;
 use tokio::sync::mpsc;
 
-use crate::events::OrderEvent;
+use crate::config::{Command, Config, PersistBackend};
+use crate::events::OrderRequest;
+use crate::kafka_worker::{
+    create_kafka_producer, start_kafka_consumer_worker, start_kafka_order_ingest_worker,
+    start_kafka_producer_worker,
+};
+use crate::market_data::MarketData;
 use crate::matching_loop::start_matching_loop;
 use crate::metrics::start_console_metrics_printer;
-use crate::outputs::Depth;
-use crate::persist::{client::ScyllaClient, event::PersistEvent, worker::start_persistence_worker};
-use crate::routes::{create_order, delete_order, get_depth, metrics_endpoint};
-use crate::worker::{Broadcaster, ws_index};
+use crate::persist::worker::redrive_dead_letters;
+use crate::persist::{
+    DeadLetterQueue, client::ScyllaClient, event::PersistRecord, worker::start_persistence_worker,
+};
+use crate::routes::{
+    create_order, delete_order, get_bbo, get_depth, get_klines, get_l3, get_recent_trades,
+    get_ticker, metrics_endpoint,
+};
+use crate::worker::{Broadcaster, OrderEntry, sse_index, ws_index};
 
+pub mod auth;
+pub mod config;
+pub mod envelope;
 pub mod events;
 pub mod inputs;
 pub mod kafka_worker;
+pub mod market_data;
 pub mod matching_loop;
 pub mod metrics;
 pub mod msgpack;
@@ -31,50 +45,111 @@ pub static ORDER_ID_COUNTER: AtomicU32 = AtomicU32::new(1);
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    let command =
+        Command::parse(std::env::args().nth(1).as_deref()).map_err(std::io::Error::other)?;
+    let config = Config::load().map_err(std::io::Error::other)?;
+    println!(
+        "[Config] Effective configuration (API keys redacted, so not loadable as is):\n{}",
+        config.to_toml()
+    );
+    let dead_letters = DeadLetterQueue::new(&config.persistence.dead_letter_path);
+    let retry = config.persistence.retry_policy();
+
+    match command {
+        Command::RedriveDeadLetters => {
+            let scylla = ScyllaClient::new(&config.persistence.scylla_uri).await;
+            let report = redrive_dead_letters(&scylla, &dead_letters, &retry).await?;
+            println!(
+                "[Persist] Re-drive complete: redriven={} failed={}",
+                report.redriven, report.failed
+            );
+            return Ok(());
+        }
+        Command::PersistConsumer => {
+            let scylla = ScyllaClient::new(&config.persistence.scylla_uri).await;
+            start_kafka_consumer_worker(scylla, config.kafka, retry).await;
+            return Ok(());
+        }
+        Command::Serve => {}
+    }
+
     start_console_metrics_printer();
 
-    let scylla = ScyllaClient::new("127.0.0.1:9042").await;
-    let (tx_persist, rx_persist) = mpsc::unbounded_channel::<PersistEvent>();
-    start_persistence_worker(rx_persist, scylla).await;
+    let market = config.market.clone();
+    let api_keys = config.api_keys.clone();
+    let ws_config = config.ws.clone();
 
-    let broadcaster = Broadcaster::new();
-    let broadcaster_arc = Arc::new(broadcaster.clone());
+    let (market_data, l3_requests) =
+        MarketData::shared(market.as_str(), config.engine.depth_max_staleness);
+
+    let (tx_persist, rx_persist) = mpsc::unbounded_channel::<PersistRecord>();
+    match config.persistence.backend {
+        PersistBackend::Direct => {
+            let scylla = ScyllaClient::new(&config.persistence.scylla_uri).await;
+            market_data.restore_candles(&scylla).await;
+            start_persistence_worker(rx_persist, scylla, retry, dead_letters).await;
+        }
+        PersistBackend::Kafka => {
+            let producer = create_kafka_producer(&config.kafka);
+            start_kafka_producer_worker(rx_persist, producer, config.kafka.clone()).await;
 
-    let depth_snapshot = Arc::new(RwLock::new(Depth {
-        bids: vec![],
-        asks: vec![],
-        last_update_id: "0".to_string(),
-    }));
+            if config.kafka.run_consumer {
+                let scylla = ScyllaClient::new(&config.persistence.scylla_uri).await;
+                market_data.restore_candles(&scylla).await;
+                tokio::spawn(start_kafka_consumer_worker(
+                    scylla,
+                    config.kafka.clone(),
+                    retry,
+                ));
+            }
+        }
+    }
+
+    let broadcaster = Broadcaster::new(&config.ws);
+    let broadcaster_arc = Arc::new(broadcaster.clone());
 
-    let (order_tx, mut order_rx) = mpsc::unbounded_channel::<OrderEvent>();
+    let (order_tx, mut order_rx) = mpsc::unbounded_channel::<OrderRequest>();
     let order_sender = Arc::new(order_tx);
 
-    let order_rb = HeapRb::<OrderEvent>::new(65536);
+    if config.kafka.run_order_ingest {
+        tokio::spawn(start_kafka_order_ingest_worker(
+            config.kafka.clone(),
+            market.clone(),
+            order_sender.clone(),
+        ));
+    }
+
+    let order_entry = OrderEntry {
+        sender: order_sender.clone(),
+        market: market.clone(),
+    };
+
+    let order_rb = HeapRb::<OrderRequest>::new(config.engine.ring_capacity);
     let (mut order_prod, order_cons) = order_rb.split();
 
     tokio::spawn(async move {
-        while let Some(event) = order_rx.recv().await {
-            loop {
-                if order_prod.try_push(event).is_ok() {
-                    break;
-                }
+        while let Some(mut request) = order_rx.recv().await {
+            while let Err(full) = order_prod.try_push(request) {
+                request = full;
                 tokio::task::yield_now().await;
             }
         }
     });
 
     {
-        let depth_snapshot = depth_snapshot.clone();
+        let market_data = market_data.clone();
         let broadcaster_arc = broadcaster_arc.clone();
         let tx_persist = tx_persist.clone();
+        let engine_config = config.engine.clone();
 
         tokio::spawn(async move {
             start_matching_loop(
                 order_cons,
                 tx_persist,
                 broadcaster_arc,
-                depth_snapshot,
-                "BTC-USDT".to_string(),
+                market_data,
+                l3_requests,
+                &engine_config,
             )
             .await;
         });
@@ -84,15 +159,24 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .app_data(Data::new(order_sender.clone()))
             .app_data(Data::new(broadcaster.clone()))
-            .app_data(Data::new(depth_snapshot.clone()))
+            .app_data(Data::new(market_data.clone()))
+            .app_data(Data::new(api_keys.clone()))
+            .app_data(Data::new(order_entry.clone()))
+            .app_data(Data::new(ws_config.clone()))
             .service(create_order)
             .service(delete_order)
             .service(get_depth)
+            .service(get_recent_trades)
+            .service(get_bbo)
+            .service(get_ticker)
+            .service(get_klines)
+            .service(get_l3)
             .service(metrics_endpoint)
             .route("/ws", actix_web::web::get().to(ws_index))
+            .route("/stream", actix_web::web::get().to(sse_index))
     })
-    .bind("127.0.0.1:8080")?
-    .workers(16)
+    .bind(config.server.bind_address.as_str())?
+    .workers(config.server.workers)
     .run()
     .await
 }
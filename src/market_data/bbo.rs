@@ -0,0 +1,12 @@
+use crate::outputs::BboMsg;
+use serde::Serialize;
+
+/// Latest top of book as served by `GET /bbo`: the last published [`BboMsg`] and the
+/// market-data `seq` it went out under. All zero until the first change.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BboSnapshot {
+    pub seq: u64,
+    pub timestamp: i64,
+    #[serde(flatten)]
+    pub bbo: BboMsg,
+}
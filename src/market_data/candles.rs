@@ -0,0 +1,141 @@
+use crate::outputs::{CandleInterval, CandleMsg, TradeMsg};
+use std::collections::VecDeque;
+
+/// Closed candles kept in memory per interval for `GET /klines`.
+pub const CANDLE_HISTORY: usize = 1000;
+
+#[derive(Clone, Default)]
+struct Series {
+    closed: VecDeque<CandleMsg>,
+    open: Option<CandleMsg>,
+}
+
+/// OHLCV candles of a market at every [`CandleInterval`], built from executed trades.
+///
+/// A candle is closed once its period is over, by [`Candles::close_until`] or by the first
+/// trade of a later period, whichever comes first. Periods without trades produce no
+/// candle.
+#[derive(Clone, Default)]
+pub struct Candles {
+    series: [Series; CandleInterval::ALL.len()],
+}
+
+impl Candles {
+    /// Folds `trade` into every interval. Candles it closes are appended to `closed`.
+    pub fn record(&mut self, trade: &TradeMsg, closed: &mut Vec<CandleMsg>) {
+        for interval in CandleInterval::ALL {
+            let series = &mut self.series[interval.index()];
+            let open_time = trade.timestamp - trade.timestamp.rem_euclid(interval.millis());
+
+            if let Some(candle) = &mut series.open
+                && candle.open_time >= open_time
+            {
+                candle.high = candle.high.max(trade.price);
+                candle.low = candle.low.min(trade.price);
+                candle.close = trade.price;
+                candle.volume += trade.quantity as u64;
+                candle.quote_volume += trade.price as u64 * trade.quantity as u64;
+                candle.trade_count += 1;
+                continue;
+            }
+
+            close_open(series, closed);
+            series.open = Some(CandleMsg {
+                interval,
+                open_time,
+                close_time: open_time + interval.millis() - 1,
+                open: trade.price,
+                high: trade.price,
+                low: trade.price,
+                close: trade.price,
+                volume: trade.quantity as u64,
+                quote_volume: trade.price as u64 * trade.quantity as u64,
+                trade_count: 1,
+                closed: false,
+            });
+        }
+    }
+
+    /// Closes the candles whose period ended before `now` and appends them to `closed`.
+    pub fn close_until(&mut self, now: i64, closed: &mut Vec<CandleMsg>) {
+        for series in &mut self.series {
+            if series.open.as_ref().is_some_and(|c| c.close_time < now) {
+                close_open(series, closed);
+            }
+        }
+    }
+
+    /// The candle of `interval` that is still being updated, if any trade happened yet.
+    pub fn current(&self, interval: CandleInterval) -> Option<&CandleMsg> {
+        self.series[interval.index()].open.as_ref()
+    }
+
+    /// Restores persisted candles, oldest first, ahead of anything recorded since. The
+    /// newest one becomes the open candle again if its period is not over (`closed` is
+    /// false) and nothing has been recorded yet.
+    pub fn restore(&mut self, interval: CandleInterval, candles: Vec<CandleMsg>) {
+        let series = &mut self.series[interval.index()];
+        let first = series
+            .closed
+            .front()
+            .or(series.open.as_ref())
+            .map_or(i64::MAX, |c| c.open_time);
+        for candle in candles.into_iter().rev() {
+            if candle.open_time >= first {
+                continue;
+            }
+            if !candle.closed && series.open.is_none() && series.closed.is_empty() {
+                series.open = Some(candle);
+            } else if series.closed.len() < CANDLE_HISTORY {
+                series.closed.push_front(candle);
+            }
+        }
+    }
+
+    /// Candles of `interval` opened within `[from, to]`, oldest first, including the one
+    /// still open. At most `limit` are returned: the earliest ones when `from` is given,
+    /// otherwise the latest.
+    pub fn range(
+        &self,
+        interval: CandleInterval,
+        from: Option<i64>,
+        to: Option<i64>,
+        limit: usize,
+    ) -> Vec<CandleMsg> {
+        let series = &self.series[interval.index()];
+        let from_time = from.unwrap_or(i64::MIN);
+        let to_time = to.unwrap_or(i64::MAX);
+        let matching: Vec<&CandleMsg> = series
+            .closed
+            .iter()
+            .chain(series.open.as_ref())
+            .filter(|c| c.open_time >= from_time && c.open_time <= to_time)
+            .collect();
+
+        let skip = match from {
+            Some(_) => 0,
+            None => matching.len().saturating_sub(limit),
+        };
+        matching
+            .into_iter()
+            .skip(skip)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+fn close_open(series: &mut Series, closed: &mut Vec<CandleMsg>) {
+    if let Some(mut candle) = series.open.take() {
+        candle.closed = true;
+        closed.push(candle.clone());
+        push_closed(&mut series.closed, candle);
+    }
+}
+
+fn push_closed(closed: &mut VecDeque<CandleMsg>, candle: CandleMsg) {
+    if closed.len() == CANDLE_HISTORY {
+        closed.pop_front();
+    }
+    closed.push_back(candle);
+}
@@ -0,0 +1,88 @@
+use crate::inputs::Side;
+use crate::market_data::double_buffer::{BufferWriter, DoubleBuffered, Replay};
+use crate::orderbook::aggregate_levels;
+use crate::outputs::Depth;
+
+/// Level changes of one book event, as published on the `depth` channel.
+#[derive(Debug, Clone)]
+pub struct DepthDiff {
+    pub update_id: u64,
+    pub timestamp: i64,
+    pub checksum: u32,
+    pub bids: Vec<[u32; 2]>,
+    pub asks: Vec<[u32; 2]>,
+}
+
+/// Every level of the book. Both sides are stored worst to best, so the changes near the
+/// top of the book, which are most of them, move little memory.
+#[derive(Default)]
+pub struct DepthBook {
+    bids: Vec<[u32; 2]>,
+    asks: Vec<[u32; 2]>,
+    update_id: u64,
+    timestamp: i64,
+    checksum: u32,
+}
+
+impl Replay for DepthBook {
+    type Update = DepthDiff;
+
+    fn apply(&mut self, diff: &DepthDiff) {
+        for &level in &diff.bids {
+            apply_level(&mut self.bids, level, |l, price| l.cmp(&price));
+        }
+        for &level in &diff.asks {
+            apply_level(&mut self.asks, level, |l, price| price.cmp(&l));
+        }
+        self.update_id = diff.update_id;
+        self.timestamp = diff.timestamp;
+        self.checksum = diff.checksum;
+    }
+}
+
+fn apply_level(
+    levels: &mut Vec<[u32; 2]>,
+    [price, quantity]: [u32; 2],
+    order: impl Fn(u32, u32) -> std::cmp::Ordering,
+) {
+    match (levels.binary_search_by(|l| order(l[0], price)), quantity) {
+        (Ok(i), 0) => {
+            levels.remove(i);
+        }
+        (Ok(i), quantity) => levels[i][1] = quantity,
+        (Err(_), 0) => {}
+        (Err(i), quantity) => levels.insert(i, [price, quantity]),
+    }
+}
+
+/// The `/depth` view of a market.
+pub type DepthSnapshots = DoubleBuffered<DepthBook>;
+
+/// Matching-loop side of [`DepthSnapshots`].
+pub type DepthPublisher = BufferWriter<DepthBook>;
+
+impl DepthSnapshots {
+    /// Top `limit` levels per side, best first.
+    pub fn depth(&self, limit: usize) -> Depth {
+        let book = self.read();
+        Depth {
+            bids: book.bids.iter().rev().take(limit).copied().collect(),
+            asks: book.asks.iter().rev().take(limit).copied().collect(),
+            last_update_id: book.update_id.to_string(),
+            checksum: book.checksum,
+            timestamp: book.timestamp,
+        }
+    }
+
+    /// Top `limit` price buckets of `step` per side, see [`aggregate_levels`].
+    pub fn aggregated(&self, step: u32, limit: usize) -> Depth {
+        let book = self.read();
+        Depth {
+            bids: aggregate_levels(book.bids.iter().rev(), step, Side::Buy, limit),
+            asks: aggregate_levels(book.asks.iter().rev(), step, Side::Sell, limit),
+            last_update_id: book.update_id.to_string(),
+            checksum: book.checksum,
+            timestamp: book.timestamp,
+        }
+    }
+}
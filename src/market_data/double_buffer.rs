@@ -0,0 +1,186 @@
+use parking_lot::{RwLock, RwLockReadGuard};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// A read model rebuilt by replaying the matching loop's updates.
+pub trait Replay: Default {
+    type Update;
+
+    fn apply(&mut self, update: &Self::Update);
+}
+
+/// A read model double-buffered between the matching loop and its readers: the matching
+/// loop replays updates into the buffer readers are not using and then flips `current`,
+/// so readers never hold it up.
+pub struct DoubleBuffered<T> {
+    buffers: [RwLock<T>; 2],
+    current: AtomicUsize,
+    /// How long the matching loop may skip publishing because a reader still holds the
+    /// back buffer before it waits for that reader.
+    max_staleness: Duration,
+}
+
+impl<T: Default> DoubleBuffered<T> {
+    pub fn new(max_staleness: Duration) -> Self {
+        Self {
+            buffers: Default::default(),
+            current: AtomicUsize::new(0),
+            max_staleness,
+        }
+    }
+
+    /// The latest published state. Hold the guard briefly: while a reader holds the
+    /// buffer being written next, publishing is deferred.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.buffers[self.current.load(Ordering::Acquire)].read()
+    }
+
+    /// Changes both buffers alike, e.g. to load persisted state before the matching loop
+    /// starts publishing.
+    pub fn update(&self, mut change: impl FnMut(&mut T)) {
+        for buffer in &self.buffers {
+            change(&mut buffer.write());
+        }
+    }
+}
+
+/// Matching-loop side of [`DoubleBuffered`]. Keeps the updates the back buffer has not seen
+/// yet, which is normally just the ones that went into the front buffer last.
+pub struct BufferWriter<T: Replay> {
+    log: VecDeque<(u64, T::Update)>,
+    version: u64,
+    applied: [u64; 2],
+    /// When publishing was first skipped because the back buffer was busy.
+    blocked_since: Option<Instant>,
+}
+
+impl<T: Replay> Default for BufferWriter<T> {
+    fn default() -> Self {
+        Self {
+            log: VecDeque::new(),
+            version: 0,
+            applied: [0; 2],
+            blocked_since: None,
+        }
+    }
+}
+
+impl<T: Replay> BufferWriter<T> {
+    /// Makes `update` visible to readers, unless a reader still holds the back buffer and
+    /// the published state is not yet `max_staleness` old; then it goes out with the next
+    /// one.
+    pub fn publish(&mut self, buffered: &DoubleBuffered<T>, update: T::Update) {
+        self.publish_all(buffered, std::iter::once(update));
+    }
+
+    /// Like [`BufferWriter::publish`], with a single flip for all of `updates`.
+    pub fn publish_all(
+        &mut self,
+        buffered: &DoubleBuffered<T>,
+        updates: impl IntoIterator<Item = T::Update>,
+    ) {
+        for update in updates {
+            self.version += 1;
+            self.log.push_back((self.version, update));
+        }
+        self.flush(buffered, false);
+    }
+
+    /// Publishes anything still pending, waiting for readers if it has to. Called when the
+    /// matching loop is idle.
+    pub fn flush_pending(&mut self, buffered: &DoubleBuffered<T>) {
+        self.flush(buffered, true);
+    }
+
+    fn flush(&mut self, buffered: &DoubleBuffered<T>, wait: bool) {
+        let front = buffered.current.load(Ordering::Relaxed);
+        let latest = self.version;
+        if self.applied[front] == latest {
+            return;
+        }
+
+        let back = 1 - front;
+        let buffer = &buffered.buffers[back];
+        let mut state = match buffer.try_write() {
+            Some(state) => state,
+            None => {
+                let since = *self.blocked_since.get_or_insert_with(Instant::now);
+                if !wait && since.elapsed() < buffered.max_staleness {
+                    return;
+                }
+                buffer.write()
+            }
+        };
+        for (_, update) in self.log.iter().filter(|(v, _)| *v > self.applied[back]) {
+            state.apply(update);
+        }
+        drop(state);
+
+        self.applied[back] = latest;
+        buffered.current.store(back, Ordering::Release);
+        self.blocked_since = None;
+
+        let oldest = self.applied[front].min(latest);
+        while self.log.front().is_some_and(|(v, _)| *v <= oldest) {
+            self.log.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Sum(u64);
+
+    impl Replay for Sum {
+        type Update = u64;
+
+        fn apply(&mut self, update: &u64) {
+            self.0 += update;
+        }
+    }
+
+    #[test]
+    fn busy_back_buffer_defers_publishing_until_flushed() {
+        let buffered = DoubleBuffered::<Sum>::new(Duration::from_secs(60));
+        let mut writer = BufferWriter::default();
+
+        writer.publish(&buffered, 1);
+        assert_eq!(buffered.read().0, 1);
+
+        // A reader that loaded the old index still holds what is now the back buffer.
+        let slow_reader = buffered.buffers[0].read();
+        writer.publish_all(&buffered, [2, 3]);
+        assert_eq!(buffered.read().0, 1);
+        drop(slow_reader);
+
+        writer.flush_pending(&buffered);
+        assert_eq!(buffered.read().0, 6);
+        writer.publish(&buffered, 4);
+        assert_eq!(buffered.read().0, 10);
+        // Both buffers have seen everything up to the one before last.
+        assert_eq!(writer.log.len(), 1);
+    }
+
+    #[test]
+    fn stale_buffer_waits_for_the_reader() {
+        let buffered = DoubleBuffered::<Sum>::new(Duration::ZERO);
+        let mut writer = BufferWriter::default();
+        writer.publish(&buffered, 1);
+
+        let (held_tx, held_rx) = std::sync::mpsc::channel();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let _slow_reader = buffered.buffers[0].read();
+                held_tx.send(()).unwrap();
+                std::thread::sleep(Duration::from_millis(20));
+            });
+            held_rx.recv().unwrap();
+            writer.publish(&buffered, 2);
+        });
+        assert_eq!(buffered.read().0, 3);
+    }
+}
@@ -0,0 +1,67 @@
+use crate::outputs::L3Level;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+
+/// Snapshots kept for paging; older ones answer `410 Gone`.
+const RETAINED_SNAPSHOTS: usize = 4;
+
+/// Every resting order of a market at one depth update id.
+#[derive(Debug, Serialize)]
+pub struct L3Book {
+    pub update_id: u64,
+    pub timestamp: i64,
+    pub bids: Vec<L3Level>,
+    pub asks: Vec<L3Level>,
+}
+
+/// Asks the matching loop for a snapshot of its book.
+pub type L3Request = oneshot::Sender<Arc<L3Book>>;
+pub type L3Requests = mpsc::UnboundedReceiver<L3Request>;
+
+/// HTTP side of L3 snapshots: requests new ones from the matching loop and keeps the last
+/// few so a client can page through one consistent book.
+pub struct L3Snapshots {
+    requests: mpsc::UnboundedSender<L3Request>,
+    retained: Mutex<VecDeque<Arc<L3Book>>>,
+}
+
+impl L3Snapshots {
+    pub fn new() -> (Self, L3Requests) {
+        let (requests, rx) = mpsc::unbounded_channel();
+        let snapshots = Self {
+            requests,
+            retained: Mutex::new(VecDeque::with_capacity(RETAINED_SNAPSHOTS)),
+        };
+        (snapshots, rx)
+    }
+
+    /// Gets a snapshot from the matching loop; `None` if it is gone.
+    pub async fn take(&self) -> Option<Arc<L3Book>> {
+        let (tx, rx) = oneshot::channel();
+        self.requests.send(tx).ok()?;
+        let book = rx.await.ok()?;
+
+        // The matching loop hands out its latest snapshot again while it is recent enough.
+        let mut retained = self.retained.lock();
+        if !retained.iter().any(|kept| Arc::ptr_eq(kept, &book)) {
+            if retained.len() == RETAINED_SNAPSHOTS {
+                retained.pop_front();
+            }
+            retained.push_back(book.clone());
+        }
+        Some(book)
+    }
+
+    /// A retained snapshot by its `update_id`.
+    pub fn get(&self, update_id: u64) -> Option<Arc<L3Book>> {
+        self.retained
+            .lock()
+            .iter()
+            .rev()
+            .find(|book| book.update_id == update_id)
+            .cloned()
+    }
+}
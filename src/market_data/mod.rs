@@ -0,0 +1,74 @@
+pub mod bbo;
+pub mod candles;
+pub mod depth;
+pub mod double_buffer;
+pub mod l3;
+pub mod recent_trades;
+pub mod ticker;
+pub mod trade_view;
+pub use bbo::*;
+pub use candles::*;
+pub use depth::*;
+pub use double_buffer::*;
+pub use l3::*;
+pub use recent_trades::*;
+pub use ticker::*;
+pub use trade_view::*;
+
+use crate::outputs::CandleInterval;
+use crate::persist::client::ScyllaClient;
+use parking_lot::RwLock;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Read models of one market, written by its matching loop and read by the HTTP and
+/// WebSocket handlers.
+pub struct MarketData {
+    pub market: Arc<str>,
+    pub depth: DepthSnapshots,
+    pub trades: TradeSnapshots,
+    pub bbo: RwLock<BboSnapshot>,
+    pub l3: L3Snapshots,
+}
+
+pub type SharedMarketData = Arc<MarketData>;
+
+impl MarketData {
+    /// The market's read models, and the L3 snapshot requests its matching loop serves.
+    /// `max_staleness` bounds how long the double-buffered views may lag the matching loop.
+    pub fn shared(
+        market: impl Into<Arc<str>>,
+        max_staleness: Duration,
+    ) -> (SharedMarketData, L3Requests) {
+        let (l3, l3_requests) = L3Snapshots::new();
+        let market_data = Arc::new(Self {
+            market: market.into(),
+            depth: DepthSnapshots::new(max_staleness),
+            trades: TradeSnapshots::new(max_staleness),
+            bbo: RwLock::new(BboSnapshot::default()),
+            l3,
+        });
+        (market_data, l3_requests)
+    }
+
+    /// Loads the closed candles persisted by earlier runs, so `GET /klines` survives a
+    /// restart. Must run before the matching loop starts.
+    pub async fn restore_candles(&self, scylla: &ScyllaClient) {
+        for interval in CandleInterval::ALL {
+            match scylla
+                .load_candles(&self.market, interval, CANDLE_HISTORY)
+                .await
+            {
+                Ok(candles) => self
+                    .trades
+                    .update(|view| view.candles.restore(interval, candles.clone())),
+                Err(e) => eprintln!(
+                    "[Candles] Failed to load {} candles of {}: {:?}",
+                    interval.name(),
+                    self.market,
+                    e
+                ),
+            }
+        }
+    }
+}
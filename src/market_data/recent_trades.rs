@@ -0,0 +1,48 @@
+use crate::outputs::TradeMsg;
+use serde::Serialize;
+use std::collections::VecDeque;
+
+/// Trades kept per market for `GET /trades/recent` and the subscribe burst.
+pub const RECENT_TRADES_CAPACITY: usize = 1000;
+
+/// A trade together with the market-data `seq` it was published under.
+#[derive(Debug, Clone, Serialize)]
+pub struct RecentTrade {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub trade: TradeMsg,
+}
+
+/// Bounded ring of a market's latest trades, written by the matching loop.
+pub struct RecentTrades {
+    trades: VecDeque<RecentTrade>,
+    capacity: usize,
+}
+
+impl Default for RecentTrades {
+    fn default() -> Self {
+        Self::new(RECENT_TRADES_CAPACITY)
+    }
+}
+
+impl RecentTrades {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            trades: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, trade: RecentTrade) {
+        if self.trades.len() == self.capacity {
+            self.trades.pop_front();
+        }
+        self.trades.push_back(trade);
+    }
+
+    /// Up to `limit` of the newest trades, oldest first.
+    pub fn latest(&self, limit: usize) -> Vec<RecentTrade> {
+        let skip = self.trades.len().saturating_sub(limit);
+        self.trades.iter().skip(skip).cloned().collect()
+    }
+}
@@ -0,0 +1,166 @@
+use crate::outputs::{TickerMsg, TradeMsg};
+use std::collections::VecDeque;
+
+/// Length of the rolling ticker window.
+pub const TICKER_WINDOW_MS: i64 = 24 * 60 * 60 * 1000;
+/// The window advances in steps of this size, so it covers between 24h and 24h + 1 minute.
+const BUCKET_MS: i64 = 60 * 1000;
+
+struct Bucket {
+    start: i64,
+    open: u32,
+    high: u32,
+    low: u32,
+    volume: u64,
+    quote_volume: u64,
+    trade_count: u64,
+}
+
+/// Rolling 24h statistics of a market, updated from executed trades.
+///
+/// Trades are folded into one-minute buckets. Volume, quote volume and trade count are kept
+/// as running sums; high and low are only rescanned when a bucket holding one of them
+/// leaves the window.
+#[derive(Default)]
+pub struct Ticker {
+    buckets: VecDeque<Bucket>,
+    last_price: u32,
+    last_timestamp: i64,
+    high: u32,
+    low: u32,
+    volume: u64,
+    quote_volume: u64,
+    trade_count: u64,
+}
+
+impl Ticker {
+    pub fn record(&mut self, trade: &TradeMsg) {
+        self.evict(trade.timestamp);
+
+        let start = trade.timestamp - trade.timestamp.rem_euclid(BUCKET_MS);
+        let quote_volume = trade.price as u64 * trade.quantity as u64;
+        match self.buckets.back_mut() {
+            Some(bucket) if bucket.start >= start => {
+                bucket.high = bucket.high.max(trade.price);
+                bucket.low = bucket.low.min(trade.price);
+                bucket.volume += trade.quantity as u64;
+                bucket.quote_volume += quote_volume;
+                bucket.trade_count += 1;
+            }
+            _ => self.buckets.push_back(Bucket {
+                start,
+                open: trade.price,
+                high: trade.price,
+                low: trade.price,
+                volume: trade.quantity as u64,
+                quote_volume,
+                trade_count: 1,
+            }),
+        }
+
+        if self.trade_count == 0 {
+            self.high = trade.price;
+            self.low = trade.price;
+        } else {
+            self.high = self.high.max(trade.price);
+            self.low = self.low.min(trade.price);
+        }
+        self.volume += trade.quantity as u64;
+        self.quote_volume += quote_volume;
+        self.trade_count += 1;
+        self.last_price = trade.price;
+        self.last_timestamp = trade.timestamp;
+    }
+
+    pub fn last_trade_time(&self) -> i64 {
+        self.last_timestamp
+    }
+
+    /// Statistics as of `now`. Buckets that have left the window since the last trade are
+    /// skipped without being evicted.
+    pub fn stats(&self, now: i64) -> TickerMsg {
+        let cutoff = now - TICKER_WINDOW_MS;
+        let stale = self
+            .buckets
+            .front()
+            .is_some_and(|b| b.start + BUCKET_MS <= cutoff);
+        if !stale {
+            return self.message(
+                self.buckets.front().map_or(0, |b| b.open),
+                self.high,
+                self.low,
+                self.volume,
+                self.quote_volume,
+                self.trade_count,
+            );
+        }
+
+        let mut live = self
+            .buckets
+            .iter()
+            .filter(|b| b.start + BUCKET_MS > cutoff)
+            .peekable();
+        let open = live.peek().map_or(0, |b| b.open);
+        let (mut high, mut low) = (0, u32::MAX);
+        let (mut volume, mut quote_volume, mut trade_count) = (0, 0, 0);
+        for bucket in live {
+            high = high.max(bucket.high);
+            low = low.min(bucket.low);
+            volume += bucket.volume;
+            quote_volume += bucket.quote_volume;
+            trade_count += bucket.trade_count;
+        }
+        if trade_count == 0 {
+            low = 0;
+        }
+        self.message(open, high, low, volume, quote_volume, trade_count)
+    }
+
+    fn message(
+        &self,
+        open: u32,
+        high: u32,
+        low: u32,
+        volume: u64,
+        quote_volume: u64,
+        trade_count: u64,
+    ) -> TickerMsg {
+        // With no trade in the window, the last price is carried over and the change is 0.
+        let open_price = if trade_count == 0 {
+            self.last_price
+        } else {
+            open
+        };
+        TickerMsg {
+            last_price: self.last_price,
+            open_price,
+            high_price: high,
+            low_price: low,
+            volume,
+            quote_volume,
+            price_change: self.last_price as i64 - open_price as i64,
+            trade_count,
+            last_trade_time: self.last_timestamp,
+        }
+    }
+
+    fn evict(&mut self, now: i64) {
+        let cutoff = now - TICKER_WINDOW_MS;
+        let mut rescan = false;
+        while let Some(bucket) = self.buckets.front() {
+            if bucket.start + BUCKET_MS > cutoff {
+                break;
+            }
+            rescan |= bucket.high >= self.high || bucket.low <= self.low;
+            self.volume -= bucket.volume;
+            self.quote_volume -= bucket.quote_volume;
+            self.trade_count -= bucket.trade_count;
+            self.buckets.pop_front();
+        }
+
+        if rescan {
+            self.high = self.buckets.iter().map(|b| b.high).max().unwrap_or(0);
+            self.low = self.buckets.iter().map(|b| b.low).min().unwrap_or(0);
+        }
+    }
+}
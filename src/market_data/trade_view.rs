@@ -0,0 +1,41 @@
+use crate::market_data::double_buffer::{BufferWriter, DoubleBuffered, Replay};
+use crate::market_data::{Candles, RecentTrade, RecentTrades, Ticker};
+
+/// What `GET /trades/recent`, `/ticker` and `/klines` and the subscribe burst read about a
+/// market's trades.
+#[derive(Default)]
+pub struct TradeView {
+    pub recent_trades: RecentTrades,
+    pub ticker: Ticker,
+    pub candles: Candles,
+}
+
+/// A change to [`TradeView`], as the matching loop made it to its own ticker and candles.
+#[derive(Debug, Clone)]
+pub enum TradeUpdate {
+    Trade(RecentTrade),
+    /// The candles whose period ended before this time (ms) were closed.
+    CloseCandles(i64),
+}
+
+impl Replay for TradeView {
+    type Update = TradeUpdate;
+
+    fn apply(&mut self, update: &TradeUpdate) {
+        // The matching loop publishes and persists closed candles from its own copy.
+        match update {
+            TradeUpdate::Trade(recent) => {
+                self.ticker.record(&recent.trade);
+                self.candles.record(&recent.trade, &mut Vec::new());
+                self.recent_trades.push(recent.clone());
+            }
+            TradeUpdate::CloseCandles(now) => self.candles.close_until(*now, &mut Vec::new()),
+        }
+    }
+}
+
+/// The trade read models of a market.
+pub type TradeSnapshots = DoubleBuffered<TradeView>;
+
+/// Matching-loop side of [`TradeSnapshots`].
+pub type TradePublisher = BufferWriter<TradeView>;
@@ -1,36 +1,44 @@
 use crate::{
-    events::OrderEvent,
+    config::EngineConfig,
+    events::{OrderEvent, OrderRequest},
+    market_data::{L3Book, L3Requests, SharedMarketData},
     metrics::{CHANNEL_BUFFER_SIZE, MATCHING_LATENCY_MS, ORDERS_MATCHED_TOTAL},
     orderbook::OrderBook,
-    outputs::Depth,
-    persist::event::PersistEvent,
+    persist::event::PersistRecord,
     worker::Broadcaster,
 };
-use parking_lot::RwLock;
+use chrono::Utc;
 use ringbuf::traits::Observer;
 use ringbuf::{HeapCons, traits::Consumer};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::UnboundedSender;
 
 pub async fn start_matching_loop(
-    mut order_rx: HeapCons<OrderEvent>,
-    tx_persist: UnboundedSender<PersistEvent>,
+    mut order_rx: HeapCons<OrderRequest>,
+    tx_persist: UnboundedSender<PersistRecord>,
     broadcaster: Arc<Broadcaster>,
-    depth_snapshot: Arc<RwLock<Depth>>,
-    _market: String,
+    market_data: SharedMarketData,
+    mut l3_requests: L3Requests,
+    config: &EngineConfig,
 ) {
-    let mut orderbook = OrderBook::new(tx_persist.clone(), broadcaster.clone());
+    let mut orderbook = OrderBook::new(
+        market_data.market.clone(),
+        tx_persist.clone(),
+        broadcaster.clone(),
+        market_data,
+    );
     let mut events_processed = 0u64;
+    let mut latest_l3 = None;
     let mut idle_iterations = 0u32;
 
     loop {
         match order_rx.try_pop() {
-            Some(event) => {
+            Some(request) => {
                 idle_iterations = 0;
                 let start = Instant::now();
 
-                match event {
+                let verdict = match request.event {
                     OrderEvent::NewOrder {
                         order_id,
                         user_id,
@@ -38,7 +46,7 @@ pub async fn start_matching_loop(
                         quantity,
                         side,
                     } => {
-                        orderbook.match_limit_order(crate::orderbook::Order {
+                        let verdict = orderbook.match_limit_order(crate::orderbook::Order {
                             order_id,
                             user_id,
                             price,
@@ -46,16 +54,36 @@ pub async fn start_matching_loop(
                             side,
                         });
                         ORDERS_MATCHED_TOTAL.inc();
+                        verdict
                     }
                     OrderEvent::DeleteOrder { order_id } => {
                         orderbook.delete_order(order_id);
+                        None
                     }
+                    OrderEvent::CancelOrder { order_id, user_id } => {
+                        orderbook.cancel_order(order_id, user_id)
+                    }
+                    OrderEvent::AmendOrder {
+                        order_id,
+                        user_id,
+                        price,
+                        quantity,
+                    } => orderbook.amend_order(order_id, user_id, price, quantity),
+                };
+                if let Some(reply) = request.reply {
+                    let _ = reply.send(verdict);
                 }
 
                 events_processed += 1;
+                orderbook.close_due_candles(Utc::now().timestamp_millis());
 
-                if events_processed % 100 == 0 {
-                    update_depth_snapshot(&mut orderbook, &depth_snapshot);
+                if events_processed.is_multiple_of(config.l3_snapshot_interval) {
+                    serve_l3_requests(
+                        &orderbook,
+                        &mut l3_requests,
+                        &mut latest_l3,
+                        config.l3_min_snapshot_interval,
+                    );
                 }
 
                 MATCHING_LATENCY_MS.observe(start.elapsed().as_secs_f64() * 1000.0);
@@ -65,10 +93,17 @@ pub async fn start_matching_loop(
                 idle_iterations += 1;
 
                 if idle_iterations == 1 {
-                    update_depth_snapshot(&mut orderbook, &depth_snapshot);
+                    orderbook.close_due_candles(Utc::now().timestamp_millis());
+                    orderbook.flush_market_data();
+                    serve_l3_requests(
+                        &orderbook,
+                        &mut l3_requests,
+                        &mut latest_l3,
+                        config.l3_min_snapshot_interval,
+                    );
                 }
 
-                if idle_iterations < 1000 {
+                if idle_iterations < config.idle_spins {
                     std::hint::spin_loop();
                 } else {
                     tokio::task::yield_now().await;
@@ -79,11 +114,84 @@ pub async fn start_matching_loop(
     }
 }
 
-#[inline]
-fn update_depth_snapshot(orderbook: &mut OrderBook, depth_snapshot: &Arc<RwLock<Depth>>) {
-    let depth = orderbook.get_depth(10);
-    let mut snapshot = depth_snapshot.write();
-    snapshot.bids = depth.bids;
-    snapshot.asks = depth.asks;
-    snapshot.last_update_id = depth.last_update_id;
+/// Answers pending `GET /l3` requests, all with the same snapshot. `latest` is handed out
+/// again while the book has not changed since or it is younger than `min_interval`, so
+/// requests cannot keep the loop copying the book.
+fn serve_l3_requests(
+    orderbook: &OrderBook,
+    l3_requests: &mut L3Requests,
+    latest: &mut Option<Arc<L3Book>>,
+    min_interval: Duration,
+) {
+    let Ok(first) = l3_requests.try_recv() else {
+        return;
+    };
+    let now = Utc::now().timestamp_millis();
+    let book = match latest {
+        Some(book)
+            if book.update_id == orderbook.update_id()
+                || now - book.timestamp < min_interval.as_millis() as i64 =>
+        {
+            book.clone()
+        }
+        _ => latest.insert(Arc::new(orderbook.l3_snapshot())).clone(),
+    };
+    let _ = first.send(book.clone());
+    while let Ok(request) = l3_requests.try_recv() {
+        let _ = request.send(book.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inputs::Side;
+    use crate::market_data::MarketData;
+    use crate::orderbook::Order;
+    use tokio::sync::{mpsc, oneshot};
+
+    fn snapshot(
+        orderbook: &OrderBook,
+        latest: &mut Option<Arc<L3Book>>,
+        min_interval: Duration,
+    ) -> Arc<L3Book> {
+        let (requests, mut l3_requests) = mpsc::unbounded_channel();
+        let (tx, mut rx) = oneshot::channel();
+        requests.send(tx).unwrap();
+        serve_l3_requests(orderbook, &mut l3_requests, latest, min_interval);
+        rx.try_recv().unwrap()
+    }
+
+    #[test]
+    fn l3_snapshot_is_reused_until_the_book_changes_and_it_is_old_enough() {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let (market_data, _l3_requests) = MarketData::shared("BTC-USDT", Duration::from_millis(50));
+        let mut orderbook = OrderBook::new(
+            market_data.market.clone(),
+            tx,
+            Arc::new(Broadcaster::default()),
+            market_data,
+        );
+        let order = |order_id| Order {
+            order_id,
+            user_id: 1,
+            price: 100,
+            quantity: 1,
+            side: Side::Buy,
+        };
+        let mut latest = None;
+
+        orderbook.match_limit_order(order(1));
+        let first = snapshot(&orderbook, &mut latest, Duration::ZERO);
+        let again = snapshot(&orderbook, &mut latest, Duration::ZERO);
+        assert!(Arc::ptr_eq(&first, &again));
+
+        orderbook.match_limit_order(order(2));
+        let throttled = snapshot(&orderbook, &mut latest, Duration::from_secs(60));
+        assert!(Arc::ptr_eq(&first, &throttled));
+
+        let fresh = snapshot(&orderbook, &mut latest, Duration::ZERO);
+        assert_eq!(fresh.update_id, orderbook.update_id());
+        assert_eq!(fresh.bids[0].orders.len(), 2);
+    }
 }
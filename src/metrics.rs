@@ -37,6 +37,81 @@ lazy_static! {
         "Current orders in channel buffer"
     )
     .expect("failed to register CHANNEL_BUFFER_SIZE");
+    pub static ref WS_CONNECTED_CLIENTS: IntGauge = register_int_gauge!(
+        "ws_connected_clients",
+        "WebSocket sessions currently connected"
+    )
+    .expect("failed to register WS_CONNECTED_CLIENTS");
+    pub static ref SSE_CONNECTED_CLIENTS: IntGauge = register_int_gauge!(
+        "sse_connected_clients",
+        "Server-Sent Events streams currently open"
+    )
+    .expect("failed to register SSE_CONNECTED_CLIENTS");
+    pub static ref MD_PUBLISH_DROPPED_TOTAL: IntCounter = register_int_counter!(
+        "md_publish_dropped_total",
+        "Market data messages dropped because the fan-out queue was full"
+    )
+    .expect("failed to register MD_PUBLISH_DROPPED_TOTAL");
+    pub static ref WS_MESSAGES_DROPPED_TOTAL: IntCounter = register_int_counter!(
+        "ws_messages_dropped_total",
+        "Public messages not sent to a lagging WebSocket session"
+    )
+    .expect("failed to register WS_MESSAGES_DROPPED_TOTAL");
+    pub static ref WS_DEPTH_CONFLATED_TOTAL: IntCounter = register_int_counter!(
+        "ws_depth_conflated_total",
+        "Depth updates merged into a pending update for a lagging WebSocket session"
+    )
+    .expect("failed to register WS_DEPTH_CONFLATED_TOTAL");
+    pub static ref WS_SLOW_DISCONNECTS_TOTAL: IntCounter = register_int_counter!(
+        "ws_slow_disconnects_total",
+        "WebSocket sessions disconnected for lagging too long"
+    )
+    .expect("failed to register WS_SLOW_DISCONNECTS_TOTAL");
+    pub static ref PERSIST_RETRIES_TOTAL: IntCounter = register_int_counter!(
+        "persist_retries_total",
+        "Persistence writes retried after a transient error"
+    )
+    .expect("failed to register PERSIST_RETRIES_TOTAL");
+    pub static ref PERSIST_FAILURES_TOTAL: IntCounter = register_int_counter!(
+        "persist_failures_total",
+        "Persistence events that exhausted retries and were dead-lettered"
+    )
+    .expect("failed to register PERSIST_FAILURES_TOTAL");
+    pub static ref PERSIST_REDRIVEN_TOTAL: IntCounter = register_int_counter!(
+        "persist_redriven_total",
+        "Dead-lettered persistence events successfully re-driven"
+    )
+    .expect("failed to register PERSIST_REDRIVEN_TOTAL");
+    pub static ref KAFKA_SEQUENCE_GAPS_TOTAL: IntCounter = register_int_counter!(
+        "kafka_sequence_gaps_total",
+        "Kafka records received after a gap in the engine sequence"
+    )
+    .expect("failed to register KAFKA_SEQUENCE_GAPS_TOTAL");
+    pub static ref KAFKA_DUPLICATES_TOTAL: IntCounter = register_int_counter!(
+        "kafka_duplicates_total",
+        "Kafka records skipped as already-applied duplicates"
+    )
+    .expect("failed to register KAFKA_DUPLICATES_TOTAL");
+    pub static ref KAFKA_DEAD_LETTERED_TOTAL: IntCounter = register_int_counter!(
+        "kafka_dead_lettered_total",
+        "Kafka records forwarded to the dead-letter topic"
+    )
+    .expect("failed to register KAFKA_DEAD_LETTERED_TOTAL");
+    pub static ref KAFKA_PRODUCE_FAILURES_TOTAL: IntCounter = register_int_counter!(
+        "kafka_produce_failures_total",
+        "Persistence records the producer could not queue or deliver"
+    )
+    .expect("failed to register KAFKA_PRODUCE_FAILURES_TOTAL");
+    pub static ref KAFKA_ORDERS_INGESTED_TOTAL: IntCounter = register_int_counter!(
+        "kafka_orders_ingested_total",
+        "Order requests from Kafka accepted into the engine queue"
+    )
+    .expect("failed to register KAFKA_ORDERS_INGESTED_TOTAL");
+    pub static ref KAFKA_ORDERS_REJECTED_TOTAL: IntCounter = register_int_counter!(
+        "kafka_orders_rejected_total",
+        "Order requests from Kafka rejected before reaching the engine"
+    )
+    .expect("failed to register KAFKA_ORDERS_REJECTED_TOTAL");
 }
 
 #[get("/metrics")]
@@ -1,18 +1,34 @@
 use chrono::Utc;
+use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap};
-use std::mem::MaybeUninit;
 use std::sync::Arc;
 use tokio::sync::mpsc::UnboundedSender;
 use uuid::Uuid;
 
 use crate::inputs::Side;
-use crate::outputs::Depth;
-use crate::persist::PersistEvent;
-use crate::worker::Broadcaster;
+use crate::market_data::{
+    BboSnapshot, Candles, DepthDiff, DepthPublisher, L3Book, RecentTrade, SharedMarketData, Ticker,
+    TradePublisher, TradeUpdate,
+};
+use crate::metrics::DEPTH_UPDATES;
+use crate::outputs::{
+    BboMsg, CandleInterval, CandleMsg, Depth, DepthUpdateMsg, L3Action, L3Event, L3Level, L3Order,
+    L3UpdateMsg, OrderStatus, OrderUpdateMsg, TradeMsg,
+};
+use crate::persist::{PersistEvent, PersistRecord, PersistStream};
+use crate::worker::{Broadcaster, Channel, FeedMessage, FeedPayload, Topic};
 
 use wincode_derive::{SchemaRead, SchemaWrite};
 
-#[derive(Debug, Clone, SchemaWrite, SchemaRead)]
+/// Levels per side covered by [`depth_checksum`].
+pub const CHECKSUM_LEVELS: usize = 10;
+/// Most levels per side [`OrderBook::get_depth`] and `GET /depth` return.
+pub const MAX_DEPTH_LEVELS: usize = 500;
+/// How often open candles that changed are persisted (ms). A restart loses at most the
+/// trades of this long from them.
+pub const OPEN_CANDLE_CHECKPOINT_MS: i64 = 1_000;
+
+#[derive(Debug, Clone, Serialize, Deserialize, SchemaWrite, SchemaRead)]
 pub struct Order {
     pub order_id: u32,
     pub user_id: u32,
@@ -79,21 +95,18 @@ impl PriceLevel {
     }
 }
 
-#[derive(SchemaWrite, SchemaRead)]
-struct TradeMsg {
-    msg_type: u8,
-    price: u32,
-    quantity: u32,
-    maker_order_id: u32,
-    taker_order_id: u32,
-    timestamp: i64,
+/// Price levels touched by the current event, reported as one depth update.
+#[derive(Default)]
+struct DepthChanges {
+    bids: Vec<u32>,
+    asks: Vec<u32>,
 }
 
+/// Top of each side, rebuilt lazily to as many levels as were last asked for.
 struct DepthCache {
-    bids: [[u32; 2]; 20],
-    asks: [[u32; 2]; 20],
-    bid_count: usize,
-    ask_count: usize,
+    bids: Vec<[u32; 2]>,
+    asks: Vec<[u32; 2]>,
+    levels: usize,
     dirty: bool,
 }
 
@@ -103,45 +116,141 @@ pub struct OrderBook {
 
     order_locations: HashMap<u32, OrderLocation>,
     depth_cache: DepthCache,
+    depth_changes: DepthChanges,
+    /// Incremented once per book-changing event; `/depth` reports it as `last_update_id`.
+    depth_update_id: u64,
+    depth_publisher: DepthPublisher,
+    /// Top of book as last published on the `bbo` channel.
+    bbo: BboMsg,
+    /// The matching loop's own trade aggregates, feeding the `ticker` and `candles`
+    /// channels; readers get a double-buffered copy through `trade_publisher`.
+    ticker: Ticker,
+    candles: Candles,
+    trade_publisher: TradePublisher,
+    /// Trades of the current event, as handed to `trade_publisher`.
+    recent_trades: Vec<RecentTrade>,
+
+    /// Trades of the current event, published after matching.
+    trades: Vec<TradeMsg>,
+    order_updates: Vec<OrderUpdateMsg>,
+    /// `OrderFilled` events of the makers hit by the current event, persisted after matching.
+    maker_fills: Vec<PersistEvent>,
+    closed_candles: Vec<CandleMsg>,
+    /// Whether a trade changed the open candles since they were last persisted, and when
+    /// that was.
+    open_candles_dirty: bool,
+    candles_checkpointed_at: i64,
+    /// Order-by-order changes of the current event, published with its depth update.
+    l3_events: Vec<L3Event>,
 
-    trade_buf: [MaybeUninit<TradeMsg>; 64],
-    trade_len: usize,
+    market: Arc<str>,
+    epoch: i64,
+    orders_seq: u64,
+    trades_seq: u64,
+    /// Sequence of public market-data messages for this market.
+    md_seq: u64,
+    /// Sequence of each user's private order updates in this market, so a gap in a
+    /// user's stream means an update was lost.
+    private_seqs: HashMap<u32, u64>,
+    trades_topic: Topic,
+    depth_topic: Topic,
+    bbo_topic: Topic,
+    ticker_topic: Topic,
+    candle_topics: [Topic; CandleInterval::ALL.len()],
+    l3_topic: Topic,
 
-    pub tx: UnboundedSender<PersistEvent>,
+    pub tx: UnboundedSender<PersistRecord>,
     pub broadcaster: Arc<Broadcaster>,
+    pub market_data: SharedMarketData,
 }
 
 impl OrderBook {
-    pub fn new(tx: UnboundedSender<PersistEvent>, broadcaster: Arc<Broadcaster>) -> Self {
+    pub fn new(
+        market: Arc<str>,
+        tx: UnboundedSender<PersistRecord>,
+        broadcaster: Arc<Broadcaster>,
+        market_data: SharedMarketData,
+    ) -> Self {
+        // Carries on with the candles restored from storage, if any.
+        let candles = market_data.trades.read().candles.clone();
         Self {
             bids: BTreeMap::new(),
             asks: BTreeMap::new(),
             order_locations: HashMap::with_capacity(10000),
 
             depth_cache: DepthCache {
-                bids: [[0; 2]; 20],
-                asks: [[0; 2]; 20],
-                bid_count: 0,
-                ask_count: 0,
+                bids: Vec::with_capacity(MAX_DEPTH_LEVELS),
+                asks: Vec::with_capacity(MAX_DEPTH_LEVELS),
+                levels: 0,
                 dirty: true,
             },
+            depth_changes: DepthChanges::default(),
+            depth_update_id: 0,
+            depth_publisher: DepthPublisher::default(),
+            bbo: BboMsg::default(),
+            ticker: Ticker::default(),
+            candles,
+            trade_publisher: TradePublisher::default(),
+            recent_trades: Vec::with_capacity(64),
 
-            trade_buf: unsafe { MaybeUninit::uninit().assume_init() },
-            trade_len: 0,
+            trades: Vec::with_capacity(64),
+            order_updates: Vec::with_capacity(64),
+            maker_fills: Vec::with_capacity(64),
+            closed_candles: Vec::with_capacity(CandleInterval::ALL.len()),
+            open_candles_dirty: false,
+            candles_checkpointed_at: 0,
+            l3_events: Vec::with_capacity(64),
+
+            trades_topic: Topic::new(Channel::Trades, market.clone()),
+            depth_topic: Topic::new(Channel::Depth, market.clone()),
+            bbo_topic: Topic::new(Channel::Bbo, market.clone()),
+            ticker_topic: Topic::new(Channel::Ticker, market.clone()),
+            l3_topic: Topic::new(Channel::L3, market.clone()),
+            candle_topics: CandleInterval::ALL
+                .map(|interval| Topic::new(Channel::candles(interval), market.clone())),
+            market,
+            epoch: Utc::now().timestamp_millis(),
+            orders_seq: 0,
+            trades_seq: 0,
+            md_seq: 0,
+            private_seqs: HashMap::new(),
 
             tx,
             broadcaster,
+            market_data,
         }
     }
 
-    pub fn match_limit_order(&mut self, mut taker: Order) {
+    /// Matches and rests `taker`; returns why it was refused, if it was.
+    pub fn match_limit_order(&mut self, taker: Order) -> Option<&'static str> {
         let timestamp = Utc::now().timestamp_millis();
 
+        if let Some(reason) = self.reject_reason(&taker) {
+            self.order_updates.push(OrderUpdateMsg {
+                reason: reason.to_string(),
+                ..order_update(&taker, OrderStatus::Rejected, timestamp)
+            });
+            self.flush_order_updates();
+            return Some(reason);
+        }
+        self.order_updates
+            .push(order_update(&taker, OrderStatus::Accepted, timestamp));
+        self.execute(taker, timestamp);
+        None
+    }
+
+    /// Matches `taker` against the opposite side and rests what is left.
+    fn execute(&mut self, mut taker: Order, timestamp: i64) {
         let book = match taker.side {
             Side::Buy => &mut self.asks,
             Side::Sell => &mut self.bids,
         };
 
+        let (changed, maker_side) = match taker.side {
+            Side::Buy => (&mut self.depth_changes.asks, Side::Sell),
+            Side::Sell => (&mut self.depth_changes.bids, Side::Buy),
+        };
+
         let mut prices_to_remove = Vec::with_capacity(8);
 
         let range: Box<dyn Iterator<Item = (&u32, &mut PriceLevel)>> = match taker.side {
@@ -155,6 +264,7 @@ impl OrderBook {
             }
 
             let mut idx = 0;
+            changed.push(price);
 
             while idx < level.prices.len() && taker.quantity > 0 {
                 if level.tombstone[idx] {
@@ -163,6 +273,7 @@ impl OrderBook {
                 }
 
                 let maker_id = level.prices[idx];
+                let maker_user = level.users[idx];
                 let maker_qty = level.quantities[idx];
                 let traded = taker.quantity.min(maker_qty);
 
@@ -175,22 +286,47 @@ impl OrderBook {
 
                 crate::metrics::TRADES_EXECUTED.inc();
 
-                if self.trade_len < 64 {
-                    self.trade_buf[self.trade_len].write(TradeMsg {
-                        msg_type: 1,
-                        price,
-                        quantity: traded,
-                        maker_order_id: maker_id,
-                        taker_order_id: taker.order_id,
-                        timestamp,
-                    });
-                    self.trade_len += 1;
-                }
+                self.trades.push(TradeMsg {
+                    msg_type: 1,
+                    price,
+                    quantity: traded,
+                    maker_order_id: maker_id,
+                    taker_order_id: taker.order_id,
+                    timestamp,
+                });
+
+                let maker = Order {
+                    order_id: maker_id,
+                    user_id: maker_user,
+                    price,
+                    quantity: new_maker_qty,
+                    side: maker_side,
+                };
+                self.l3_events.push(L3Event {
+                    fill_quantity: traded,
+                    ..l3_event(L3Action::Execute, &maker)
+                });
+                self.order_updates.push(OrderUpdateMsg {
+                    fill_price: price,
+                    fill_quantity: traded,
+                    ..order_update(&maker, fill_status(new_maker_qty), timestamp)
+                });
+                self.maker_fills.push(PersistEvent::OrderFilled {
+                    order_id: maker_id,
+                    traded_qty: traded,
+                    remaining_qty: new_maker_qty,
+                });
+                self.order_updates.push(OrderUpdateMsg {
+                    fill_price: price,
+                    fill_quantity: traded,
+                    ..order_update(&taker, fill_status(taker.quantity), timestamp)
+                });
 
                 if new_maker_qty == 0 {
                     unsafe {
                         level.remove_fast(idx);
                     }
+                    self.order_locations.remove(&maker_id);
                 } else {
                     idx += 1;
                 }
@@ -205,12 +341,30 @@ impl OrderBook {
             book.remove(&price);
         }
 
+        let mut maker_fills = std::mem::take(&mut self.maker_fills);
+        for fill in maker_fills.drain(..) {
+            self.persist(fill);
+        }
+        self.maker_fills = maker_fills;
+
         if taker.quantity > 0 {
             self.inserting_resting(taker);
         }
 
         self.flush_trades();
+        self.flush_order_updates();
         self.depth_cache.dirty = true;
+        self.publish_depth_update();
+    }
+
+    fn reject_reason(&self, order: &Order) -> Option<&'static str> {
+        if order.price == 0 || order.quantity == 0 {
+            Some("price and quantity must be positive")
+        } else if self.order_locations.contains_key(&order.order_id) {
+            Some("duplicate order_id")
+        } else {
+            None
+        }
     }
 
     #[inline]
@@ -222,8 +376,13 @@ impl OrderBook {
 
         let level = book.entry(order.price).or_insert_with(PriceLevel::new);
         let index = level.prices.len();
+        match order.side {
+            Side::Buy => self.depth_changes.bids.push(order.price),
+            Side::Sell => self.depth_changes.asks.push(order.price),
+        }
 
         level.push(&order);
+        self.l3_events.push(l3_event(L3Action::Add, &order));
 
         self.order_locations.insert(
             order.order_id,
@@ -234,42 +393,356 @@ impl OrderBook {
             },
         );
 
-        let _ = self.tx.send(PersistEvent::NewOrder(order));
+        self.persist(PersistEvent::NewOrder(order));
     }
 
     pub fn delete_order(&mut self, order_id: u32) {
-        if let Some(loc) = self.order_locations.remove(&order_id) {
-            let book = match loc.side {
-                Side::Buy => &mut self.bids,
-                Side::Sell => &mut self.asks,
-            };
+        if let Some(order) = self.remove_resting(order_id) {
+            self.order_updates.push(order_update(
+                &order,
+                OrderStatus::Cancelled,
+                Utc::now().timestamp_millis(),
+            ));
+        }
+        self.flush_order_updates();
+        self.depth_cache.dirty = true;
+        self.publish_depth_update();
+    }
 
-            if let Some(level) = book.get_mut(&loc.price) {
-                unsafe {
-                    level.remove_fast(loc.index);
-                }
+    /// Cancels an order on behalf of `user_id`, who must own it; returns why it was
+    /// refused, if it was.
+    pub fn cancel_order(&mut self, order_id: u32, user_id: u32) -> Option<&'static str> {
+        match self.resting(order_id) {
+            Some(order) if order.user_id == user_id => {
+                self.delete_order(order_id);
+                None
+            }
+            found => Some(self.reject_request(order_id, user_id, found)),
+        }
+    }
+
+    /// Changes the price and/or quantity of a resting order owned by `user_id`. Reducing the
+    /// quantity at the same price keeps the order's time priority; any other change moves
+    /// it to the back of the queue, and a new price may match immediately. An amend that
+    /// changes nothing is acknowledged to the owner but leaves the book and feeds untouched.
+    /// Returns why the amend was refused, if it was.
+    pub fn amend_order(
+        &mut self,
+        order_id: u32,
+        user_id: u32,
+        price: u32,
+        quantity: u32,
+    ) -> Option<&'static str> {
+        let order = match self.resting(order_id) {
+            Some(order) if order.user_id == user_id => order,
+            found => return Some(self.reject_request(order_id, user_id, found)),
+        };
 
-                if level.is_empty() {
-                    book.remove(&loc.price);
+        let timestamp = Utc::now().timestamp_millis();
+        let amended = Order {
+            price,
+            quantity,
+            ..order
+        };
+
+        if price == 0 || quantity == 0 {
+            let reason = "price and quantity must be positive";
+            self.order_updates.push(OrderUpdateMsg {
+                reason: reason.to_string(),
+                ..order_update(&order, OrderStatus::Rejected, timestamp)
+            });
+            self.flush_order_updates();
+            return Some(reason);
+        }
+
+        if price == order.price && quantity == order.quantity {
+            self.order_updates
+                .push(order_update(&amended, OrderStatus::Amended, timestamp));
+            self.flush_order_updates();
+            return None;
+        }
+
+        if price == order.price && quantity < order.quantity {
+            if let Some(loc) = self.order_locations.get(&order_id) {
+                let book = match loc.side {
+                    Side::Buy => &mut self.bids,
+                    Side::Sell => &mut self.asks,
+                };
+                if let Some(level) = book.get_mut(&loc.price) {
+                    unsafe {
+                        level.reduce_qty(loc.index, quantity);
+                    }
                 }
+                match loc.side {
+                    Side::Buy => self.depth_changes.bids.push(loc.price),
+                    Side::Sell => self.depth_changes.asks.push(loc.price),
+                }
+            }
+            self.l3_events.push(l3_event(L3Action::Modify, &amended));
+            self.persist(PersistEvent::OrderAmended {
+                order_id,
+                remaining_qty: quantity,
+            });
+            self.order_updates
+                .push(order_update(&amended, OrderStatus::Amended, timestamp));
+            self.flush_order_updates();
+            self.depth_cache.dirty = true;
+            self.publish_depth_update();
+            return None;
+        }
+
+        self.remove_resting(order_id);
+        self.order_updates
+            .push(order_update(&amended, OrderStatus::Amended, timestamp));
+        self.execute(amended, timestamp);
+        None
+    }
+
+    /// The live resting order with this id, with its remaining quantity.
+    fn resting(&self, order_id: u32) -> Option<Order> {
+        let loc = self.order_locations.get(&order_id)?;
+        let book = match loc.side {
+            Side::Buy => &self.bids,
+            Side::Sell => &self.asks,
+        };
+        let level = book.get(&loc.price)?;
+        if level.tombstone[loc.index] {
+            return None;
+        }
+        Some(Order {
+            order_id,
+            user_id: level.users[loc.index],
+            price: loc.price,
+            quantity: level.quantities[loc.index],
+            side: loc.side,
+        })
+    }
+
+    /// Takes an order off the book, returning it if it was still live.
+    fn remove_resting(&mut self, order_id: u32) -> Option<Order> {
+        let order = self.resting(order_id);
+        let loc = self.order_locations.remove(&order_id)?;
+        self.persist(PersistEvent::OrderDeleted { order_id });
+        if let Some(order) = &order {
+            self.l3_events.push(L3Event {
+                quantity: 0,
+                ..l3_event(L3Action::Delete, order)
+            });
+        }
+        let book = match loc.side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        };
+
+        if let Some(level) = book.get_mut(&loc.price) {
+            unsafe {
+                level.remove_fast(loc.index);
+            }
+
+            if level.is_empty() {
+                book.remove(&loc.price);
+            }
+
+            match loc.side {
+                Side::Buy => self.depth_changes.bids.push(loc.price),
+                Side::Sell => self.depth_changes.asks.push(loc.price),
             }
         }
-        self.depth_cache.dirty = true;
+        order
+    }
+
+    /// Tells `user_id` that a cancel or amend of `order_id` was refused, and why.
+    fn reject_request(
+        &mut self,
+        order_id: u32,
+        user_id: u32,
+        found: Option<Order>,
+    ) -> &'static str {
+        let (order, reason) = match found {
+            Some(order) => (order, "order belongs to another user"),
+            None => (
+                Order {
+                    order_id,
+                    user_id,
+                    price: 0,
+                    quantity: 0,
+                    side: Side::Buy,
+                },
+                "unknown order",
+            ),
+        };
+        // Never leak the other user's order details back to the requester.
+        let update = OrderUpdateMsg {
+            user_id,
+            price: 0,
+            remaining_quantity: 0,
+            reason: reason.to_string(),
+            ..order_update(&order, OrderStatus::Rejected, Utc::now().timestamp_millis())
+        };
+        self.order_updates.push(update);
+        self.flush_order_updates();
+        reason
+    }
+
+    /// Publishes the new quantity of every level touched by the last event (0 = level
+    /// gone) under the next update id. Events that changed nothing publish nothing.
+    fn publish_depth_update(&mut self) {
+        if self.depth_changes.bids.is_empty() && self.depth_changes.asks.is_empty() {
+            return;
+        }
+
+        self.depth_update_id += 1;
+        let bids = level_quantities(&mut self.depth_changes.bids, &self.bids);
+        let asks = level_quantities(&mut self.depth_changes.asks, &self.asks);
+
+        let timestamp = Utc::now().timestamp_millis();
+        let checksum = self.checksum();
+        self.depth_publisher.publish(
+            &self.market_data.depth,
+            DepthDiff {
+                update_id: self.depth_update_id,
+                timestamp,
+                checksum,
+                bids: bids.clone(),
+                asks: asks.clone(),
+            },
+        );
+
+        let update = DepthUpdateMsg {
+            first_update_id: self.depth_update_id,
+            final_update_id: self.depth_update_id,
+            bids,
+            asks,
+            checksum,
+        };
+
+        self.md_seq += 1;
+        let message = self.feed_message(self.md_seq, timestamp, FeedPayload::DepthUpdate(update));
+        self.broadcaster.publish(self.depth_topic.clone(), message);
+        DEPTH_UPDATES.inc();
+
+        if !self.l3_events.is_empty() {
+            let update = L3UpdateMsg {
+                update_id: self.depth_update_id,
+                events: std::mem::replace(&mut self.l3_events, Vec::with_capacity(64)),
+            };
+            self.md_seq += 1;
+            let message = self.feed_message(
+                self.md_seq,
+                Utc::now().timestamp_millis(),
+                FeedPayload::L3Update(update),
+            );
+            self.broadcaster.publish(self.l3_topic.clone(), message);
+        }
+
+        self.publish_bbo();
+    }
+
+    /// Publishes the top of book if the last depth update moved the best price or its
+    /// quantity on either side.
+    fn publish_bbo(&mut self) {
+        let (bid_price, bid_quantity) = self
+            .bids
+            .last_key_value()
+            .map_or((0, 0), |(&price, level)| (price, level.total_qty));
+        let (ask_price, ask_quantity) = self
+            .asks
+            .first_key_value()
+            .map_or((0, 0), |(&price, level)| (price, level.total_qty));
+
+        if (bid_price, bid_quantity, ask_price, ask_quantity)
+            == (
+                self.bbo.bid_price,
+                self.bbo.bid_quantity,
+                self.bbo.ask_price,
+                self.bbo.ask_quantity,
+            )
+        {
+            return;
+        }
+
+        self.bbo = BboMsg {
+            bid_price,
+            bid_quantity,
+            ask_price,
+            ask_quantity,
+            update_id: self.depth_update_id,
+        };
+
+        self.md_seq += 1;
+        let timestamp = Utc::now().timestamp_millis();
+        *self.market_data.bbo.write() = BboSnapshot {
+            seq: self.md_seq,
+            timestamp,
+            bbo: self.bbo.clone(),
+        };
+        let message = self.feed_message(self.md_seq, timestamp, FeedPayload::Bbo(self.bbo.clone()));
+        self.broadcaster.publish(self.bbo_topic.clone(), message);
+    }
+
+    fn feed_message(&self, seq: u64, timestamp: i64, payload: FeedPayload) -> FeedMessage {
+        FeedMessage {
+            market: self.market.clone(),
+            seq,
+            timestamp,
+            payload,
+        }
+    }
+
+    #[inline]
+    fn persist(&mut self, event: PersistEvent) {
+        let seq = match event.stream() {
+            PersistStream::Orders => &mut self.orders_seq,
+            PersistStream::Trades => &mut self.trades_seq,
+        };
+        *seq += 1;
+
+        let _ = self.tx.send(PersistRecord {
+            market: self.market.clone(),
+            epoch: self.epoch,
+            seq: *seq,
+            event,
+        });
+    }
+
+    /// Sends each buffered update to its owner's private `orders` stream.
+    fn flush_order_updates(&mut self) {
+        let mut updates = std::mem::take(&mut self.order_updates);
+        for update in updates.drain(..) {
+            let seq = self.private_seqs.entry(update.user_id).or_default();
+            *seq += 1;
+            let seq = *seq;
+            let topic = Topic::private(Channel::Orders, self.market.clone(), update.user_id);
+            let message =
+                self.feed_message(seq, update.timestamp, FeedPayload::OrderUpdate(update));
+            self.broadcaster.publish_private(topic, message);
+        }
+        self.order_updates = updates;
     }
 
     #[inline]
     fn flush_trades(&mut self) {
-        if self.trade_len == 0 {
+        if self.trades.is_empty() {
             return;
         }
 
-        for i in 0..self.trade_len {
-            let trade = unsafe { self.trade_buf[i].assume_init_read() };
-            if let Ok(encoded) = wincode::serialize(&trade) {
-                self.broadcaster.broadcast_bytes(&encoded);
-            }
+        let mut trades = std::mem::take(&mut self.trades);
+        for trade in trades.drain(..) {
+            self.md_seq += 1;
+            self.recent_trades.push(RecentTrade {
+                seq: self.md_seq,
+                trade: trade.clone(),
+            });
+            self.ticker.record(&trade);
+            self.candles.record(&trade, &mut self.closed_candles);
+            let message = self.feed_message(
+                self.md_seq,
+                trade.timestamp,
+                FeedPayload::Trade(trade.clone()),
+            );
+            self.broadcaster.publish(self.trades_topic.clone(), message);
 
-            let _ = self.tx.send(PersistEvent::TradeExecuted {
+            self.persist(PersistEvent::TradeExecuted {
                 trade_id: Uuid::new_v4().into_bytes(),
                 price: trade.price,
                 quantity: trade.quantity,
@@ -278,38 +751,284 @@ impl OrderBook {
                 timestamp: trade.timestamp,
             });
         }
-        self.trade_len = 0;
+        self.trades = trades;
+        self.open_candles_dirty = true;
+        self.trade_publisher.publish_all(
+            &self.market_data.trades,
+            self.recent_trades.drain(..).map(TradeUpdate::Trade),
+        );
+
+        let timestamp = self.ticker.last_trade_time();
+        let stats = self.ticker.stats(timestamp);
+        self.md_seq += 1;
+        let message = self.feed_message(self.md_seq, timestamp, FeedPayload::Ticker(stats));
+        self.broadcaster.publish(self.ticker_topic.clone(), message);
+
+        // Candles closed by these trades go out before the ones they opened.
+        self.flush_closed_candles(timestamp);
+        for interval in CandleInterval::ALL {
+            if let Some(candle) = self.candles.current(interval).cloned() {
+                self.publish_candle(candle, timestamp);
+            }
+        }
     }
 
-    pub fn get_depth(&mut self, limit: usize) -> Depth {
-        if self.depth_cache.dirty {
-            self.rebuild_depth_cache();
+    /// Closes the candles whose period ended before `now` (ms) and persists the open ones
+    /// every [`OPEN_CANDLE_CHECKPOINT_MS`] if they changed. The matching loop calls it
+    /// between events, so a candle closes on time even if no trade follows it.
+    pub fn close_due_candles(&mut self, now: i64) {
+        let closed_before = self.closed_candles.len();
+        self.candles.close_until(now, &mut self.closed_candles);
+        if self.closed_candles.len() > closed_before {
+            self.trade_publisher
+                .publish(&self.market_data.trades, TradeUpdate::CloseCandles(now));
+            self.flush_closed_candles(now);
         }
 
-        let bids = self.depth_cache.bids[..self.depth_cache.bid_count.min(limit)].to_vec();
-        let asks = self.depth_cache.asks[..self.depth_cache.ask_count.min(limit)].to_vec();
+        if self.open_candles_dirty
+            && now - self.candles_checkpointed_at >= OPEN_CANDLE_CHECKPOINT_MS
+        {
+            for interval in CandleInterval::ALL {
+                if let Some(candle) = self.candles.current(interval).cloned() {
+                    self.persist(PersistEvent::CandleUpdated {
+                        market: self.market.to_string(),
+                        candle,
+                    });
+                }
+            }
+            self.open_candles_dirty = false;
+            self.candles_checkpointed_at = now;
+        }
+    }
+
+    /// Persists and publishes the candles closed since the last call.
+    fn flush_closed_candles(&mut self, timestamp: i64) {
+        let mut closed = std::mem::take(&mut self.closed_candles);
+        for candle in closed.drain(..) {
+            self.persist(PersistEvent::CandleClosed {
+                market: self.market.to_string(),
+                candle: candle.clone(),
+            });
+            self.publish_candle(candle, timestamp);
+        }
+        self.closed_candles = closed;
+    }
+
+    fn publish_candle(&mut self, candle: CandleMsg, timestamp: i64) {
+        self.md_seq += 1;
+        let topic = self.candle_topics[candle.interval.index()].clone();
+        let message = self.feed_message(self.md_seq, timestamp, FeedPayload::Candle(candle));
+        self.broadcaster.publish(topic, message);
+    }
+
+    /// Top `limit` levels per side, at most [`MAX_DEPTH_LEVELS`].
+    pub fn get_depth(&mut self, limit: usize) -> Depth {
+        let limit = limit.min(MAX_DEPTH_LEVELS);
+        self.ensure_depth_cache(limit);
+
+        let bids = self.depth_cache.bids[..self.depth_cache.bids.len().min(limit)].to_vec();
+        let asks = self.depth_cache.asks[..self.depth_cache.asks.len().min(limit)].to_vec();
 
         Depth {
             bids,
             asks,
-            last_update_id: "0".to_string(),
+            last_update_id: self.depth_update_id.to_string(),
+            checksum: self.checksum(),
+            timestamp: Utc::now().timestamp_millis(),
+        }
+    }
+
+    /// Publishes depth changes and trades a busy reader kept back; see [`BufferWriter`].
+    ///
+    /// [`BufferWriter`]: crate::market_data::BufferWriter
+    pub fn flush_market_data(&mut self) {
+        self.depth_publisher.flush_pending(&self.market_data.depth);
+        self.trade_publisher.flush_pending(&self.market_data.trades);
+    }
+
+    /// Depth update id of the book as it stands.
+    pub fn update_id(&self) -> u64 {
+        self.depth_update_id
+    }
+
+    /// Every live resting order, best levels first, in queue order within a level.
+    pub fn l3_snapshot(&self) -> L3Book {
+        fn levels<'a>(iter: impl Iterator<Item = (&'a u32, &'a PriceLevel)>) -> Vec<L3Level> {
+            iter.map(|(&price, level)| L3Level {
+                price,
+                orders: (0..level.prices.len())
+                    .filter(|&i| !level.tombstone[i])
+                    .map(|i| L3Order {
+                        order_id: level.prices[i],
+                        quantity: level.quantities[i],
+                    })
+                    .collect(),
+            })
+            .collect()
+        }
+
+        L3Book {
+            update_id: self.depth_update_id,
+            timestamp: Utc::now().timestamp_millis(),
+            bids: levels(self.bids.iter().rev()),
+            asks: levels(self.asks.iter()),
         }
     }
 
+    fn checksum(&mut self) -> u32 {
+        self.ensure_depth_cache(CHECKSUM_LEVELS);
+
+        let cache = &self.depth_cache;
+        depth_checksum(
+            &cache.bids[..cache.bids.len().min(CHECKSUM_LEVELS)],
+            &cache.asks[..cache.asks.len().min(CHECKSUM_LEVELS)],
+        )
+    }
+
+    /// Rebuilds the cache if the book changed or it holds fewer than `levels` levels. The
+    /// hot path only ever asks for [`CHECKSUM_LEVELS`].
     #[inline]
-    fn rebuild_depth_cache(&mut self) {
-        self.depth_cache.bid_count = 0;
-        for (&price, level) in self.bids.iter().rev().take(20) {
-            self.depth_cache.bids[self.depth_cache.bid_count] = [price, level.total_qty];
-            self.depth_cache.bid_count += 1;
+    fn ensure_depth_cache(&mut self, levels: usize) {
+        if !self.depth_cache.dirty && self.depth_cache.levels >= levels {
+            return;
+        }
+
+        let cache = &mut self.depth_cache;
+        cache.bids.clear();
+        cache.bids.extend(
+            self.bids
+                .iter()
+                .rev()
+                .take(levels)
+                .map(|(&price, level)| [price, level.total_qty]),
+        );
+        cache.asks.clear();
+        cache.asks.extend(
+            self.asks
+                .iter()
+                .take(levels)
+                .map(|(&price, level)| [price, level.total_qty]),
+        );
+        cache.levels = levels;
+        cache.dirty = false;
+    }
+}
+
+/// Merges `levels` (best first) into buckets of `step` price units, keeping at most `limit`
+/// buckets. Bids round down and asks round up, so a bucket never looks better than the
+/// levels in it. An ask above the highest multiple of `step` that fits in a `u32` goes into
+/// that bucket, and a bucket's quantity saturates at `u32::MAX`.
+pub fn aggregate_levels<'a>(
+    levels: impl IntoIterator<Item = &'a [u32; 2]>,
+    step: u32,
+    side: Side,
+    limit: usize,
+) -> Vec<[u32; 2]> {
+    let mut buckets: Vec<[u32; 2]> = Vec::with_capacity(limit);
+    for &[price, quantity] in levels {
+        let bucket = match side {
+            Side::Buy => price - price % step,
+            Side::Sell => price
+                .div_ceil(step)
+                .checked_mul(step)
+                .unwrap_or(u32::MAX - u32::MAX % step),
+        };
+        if let Some(last) = buckets.last_mut()
+            && last[0] == bucket
+        {
+            last[1] = last[1].saturating_add(quantity);
+        } else if buckets.len() == limit {
+            break;
+        } else {
+            buckets.push([bucket, quantity]);
         }
+    }
+    buckets
+}
+
+/// L3 event for `order` with `order.quantity` as the quantity left on the book.
+fn l3_event(action: L3Action, order: &Order) -> L3Event {
+    L3Event {
+        action,
+        order_id: order.order_id,
+        side: order.side,
+        price: order.price,
+        quantity: order.quantity,
+        fill_quantity: 0,
+    }
+}
+
+/// Update for `order` with `order.quantity` as the remaining quantity and no fill.
+fn order_update(order: &Order, status: OrderStatus, timestamp: i64) -> OrderUpdateMsg {
+    OrderUpdateMsg {
+        order_id: order.order_id,
+        user_id: order.user_id,
+        side: order.side,
+        price: order.price,
+        status,
+        fill_price: 0,
+        fill_quantity: 0,
+        remaining_quantity: order.quantity,
+        reason: String::new(),
+        timestamp,
+    }
+}
 
-        self.depth_cache.ask_count = 0;
-        for (&price, level) in self.asks.iter().take(20) {
-            self.depth_cache.asks[self.depth_cache.ask_count] = [price, level.total_qty];
-            self.depth_cache.ask_count += 1;
+fn fill_status(remaining: u32) -> OrderStatus {
+    if remaining == 0 {
+        OrderStatus::Filled
+    } else {
+        OrderStatus::PartiallyFilled
+    }
+}
+
+/// Drains `prices` into `[price, total_qty]` pairs, one per distinct price.
+fn level_quantities(prices: &mut Vec<u32>, book: &BTreeMap<u32, PriceLevel>) -> Vec<[u32; 2]> {
+    prices.sort_unstable();
+    prices.dedup();
+    let levels = prices
+        .iter()
+        .map(|price| [*price, book.get(price).map_or(0, |level| level.total_qty)])
+        .collect();
+    prices.clear();
+    levels
+}
+
+/// CRC32 (IEEE 802.3, as in zlib) of the top [`CHECKSUM_LEVELS`] levels of each side.
+///
+/// `bids` run from the best (highest) price down, `asks` from the best (lowest) price up.
+/// The checksummed text is every level as `price:quantity` in decimal, joined by `,`, with
+/// the bid list and the ask list joined by `|`; an empty side contributes an empty list.
+/// For bids `[[101, 5], [100, 2]]` and asks `[[102, 3]]` that is `101:5,100:2|102:3`.
+pub fn depth_checksum(bids: &[[u32; 2]], asks: &[[u32; 2]]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    for (side, levels) in [bids, asks].into_iter().enumerate() {
+        if side == 1 {
+            hasher.update(b"|");
         }
+        for (i, [price, quantity]) in levels.iter().take(CHECKSUM_LEVELS).enumerate() {
+            if i > 0 {
+                hasher.update(b",");
+            }
+            hash_decimal(&mut hasher, *price);
+            hasher.update(b":");
+            hash_decimal(&mut hasher, *quantity);
+        }
+    }
+    hasher.finalize()
+}
 
-        self.depth_cache.dirty = false;
+/// Feeds `value` to `hasher` as decimal text without allocating.
+fn hash_decimal(hasher: &mut crc32fast::Hasher, mut value: u32) {
+    let mut digits = [0u8; 10];
+    let mut start = digits.len();
+    loop {
+        start -= 1;
+        digits[start] = b'0' + (value % 10) as u8;
+        value /= 10;
+        if value == 0 {
+            break;
+        }
     }
+    hasher.update(&digits[start..]);
 }
@@ -1,3 +1,5 @@
+use crate::envelope::Payload;
+use crate::inputs::Side;
 use serde::{Deserialize, Serialize};
 use wincode_derive::{SchemaRead, SchemaWrite};
 
@@ -17,4 +19,229 @@ pub struct Depth {
     pub bids: Vec<[u32; 2]>,
     pub asks: Vec<[u32; 2]>,
     pub last_update_id: String,
+    /// [`crate::orderbook::depth_checksum`] of the book at `last_update_id`.
+    pub checksum: u32,
+    /// When the book reached `last_update_id`, in ms since the Unix epoch.
+    pub timestamp: i64,
+}
+
+/// Trade as broadcast to WebSocket clients. `msg_type` is always 1 and predates the
+/// envelope's message type; it stays so version 1 readers keep working. JSON and
+/// MessagePack frames carry a `type` field instead.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, SchemaWrite, SchemaRead)]
+pub struct TradeMsg {
+    #[serde(skip)]
+    pub msg_type: u8,
+    pub price: u32,
+    pub quantity: u32,
+    pub maker_order_id: u32,
+    pub taker_order_id: u32,
+    pub timestamp: i64,
+}
+
+impl Payload for TradeMsg {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, SchemaWrite, SchemaRead)]
+pub enum AckStatus {
+    Accepted,
+    Rejected,
+}
+
+/// Reply to an order request that did not come over HTTP, correlated by the caller's id.
+/// `Accepted` means queued for matching, like a 200 from `POST /order`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, SchemaWrite, SchemaRead)]
+pub struct OrderAck {
+    pub client_order_id: String,
+    pub order_id: u32,
+    pub status: AckStatus,
+    pub reason: String,
+}
+
+impl Payload for OrderAck {}
+
+/// Incremental depth change. Each level carries its new total quantity; 0 means the level
+/// was removed. Update ids are consecutive, so `first_update_id` of an update is always
+/// `final_update_id + 1` of the previous one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, SchemaWrite, SchemaRead)]
+pub struct DepthUpdateMsg {
+    pub first_update_id: u64,
+    pub final_update_id: u64,
+    pub bids: Vec<[u32; 2]>,
+    pub asks: Vec<[u32; 2]>,
+    /// [`crate::orderbook::depth_checksum`] of the book after this update.
+    pub checksum: u32,
+}
+
+/// Best bid and ask with their total quantities; a price of 0 means that side is empty.
+/// `update_id` is the depth update id the top of book was taken at.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, SchemaWrite, SchemaRead)]
+pub struct BboMsg {
+    pub bid_price: u32,
+    pub bid_quantity: u32,
+    pub ask_price: u32,
+    pub ask_quantity: u32,
+    pub update_id: u64,
+}
+
+/// Rolling 24h statistics. `price_change` is `last_price - open_price`; volumes are in base
+/// units and `quote_volume` sums `price * quantity`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, SchemaWrite, SchemaRead)]
+pub struct TickerMsg {
+    pub last_price: u32,
+    pub open_price: u32,
+    pub high_price: u32,
+    pub low_price: u32,
+    pub volume: u64,
+    pub quote_volume: u64,
+    pub price_change: i64,
+    pub trade_count: u64,
+    pub last_trade_time: i64,
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, SchemaWrite, SchemaRead,
+)]
+pub enum CandleInterval {
+    #[serde(rename = "1m")]
+    OneMinute,
+    #[serde(rename = "5m")]
+    FiveMinutes,
+    #[serde(rename = "1h")]
+    OneHour,
+    #[serde(rename = "1d")]
+    OneDay,
+}
+
+impl CandleInterval {
+    pub const ALL: [CandleInterval; 4] = [
+        CandleInterval::OneMinute,
+        CandleInterval::FiveMinutes,
+        CandleInterval::OneHour,
+        CandleInterval::OneDay,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "1m" => Some(CandleInterval::OneMinute),
+            "5m" => Some(CandleInterval::FiveMinutes),
+            "1h" => Some(CandleInterval::OneHour),
+            "1d" => Some(CandleInterval::OneDay),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CandleInterval::OneMinute => "1m",
+            CandleInterval::FiveMinutes => "5m",
+            CandleInterval::OneHour => "1h",
+            CandleInterval::OneDay => "1d",
+        }
+    }
+
+    pub fn millis(self) -> i64 {
+        match self {
+            CandleInterval::OneMinute => 60_000,
+            CandleInterval::FiveMinutes => 5 * 60_000,
+            CandleInterval::OneHour => 60 * 60_000,
+            CandleInterval::OneDay => 24 * 60 * 60_000,
+        }
+    }
+
+    /// Position in [`CandleInterval::ALL`].
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// OHLCV candle covering `[open_time, close_time]` in ms. `closed` is set once a trade of a
+/// later period has arrived; until then the candle is still being updated.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, SchemaWrite, SchemaRead)]
+pub struct CandleMsg {
+    pub interval: CandleInterval,
+    pub open_time: i64,
+    pub close_time: i64,
+    pub open: u32,
+    pub high: u32,
+    pub low: u32,
+    pub close: u32,
+    pub volume: u64,
+    pub quote_volume: u64,
+    pub trade_count: u64,
+    pub closed: bool,
+}
+
+/// A resting order in an L3 snapshot. Owners are not disclosed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, SchemaWrite, SchemaRead)]
+pub struct L3Order {
+    pub order_id: u32,
+    pub quantity: u32,
+}
+
+/// One price level of an L3 snapshot, orders in queue order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, SchemaWrite, SchemaRead)]
+pub struct L3Level {
+    pub price: u32,
+    pub orders: Vec<L3Order>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, SchemaWrite, SchemaRead)]
+#[serde(rename_all = "snake_case")]
+pub enum L3Action {
+    /// The order joined the back of its level.
+    Add,
+    /// The order's quantity was reduced in place, keeping its priority.
+    Modify,
+    /// The order left the book without trading.
+    Delete,
+    /// The order traded `fill_quantity` as maker.
+    Execute,
+}
+
+/// Change to one resting order. `quantity` is what remains on the book afterwards (0 once
+/// the order is gone); `fill_quantity` is only set on `Execute`.
+#[derive(Debug, Clone, Serialize, Deserialize, SchemaWrite, SchemaRead)]
+pub struct L3Event {
+    pub action: L3Action,
+    pub order_id: u32,
+    pub side: Side,
+    pub price: u32,
+    pub quantity: u32,
+    pub fill_quantity: u32,
+}
+
+/// Order-by-order changes of one book event, in the order they happened. `update_id` is the
+/// depth update id of the same event.
+#[derive(Debug, Clone, Serialize, Deserialize, SchemaWrite, SchemaRead)]
+pub struct L3UpdateMsg {
+    pub update_id: u64,
+    pub events: Vec<L3Event>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, SchemaWrite, SchemaRead)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderStatus {
+    Accepted,
+    PartiallyFilled,
+    Filled,
+    Cancelled,
+    Rejected,
+    Amended,
+}
+
+/// Change to one order, sent only on its owner's private stream. `fill_price` and
+/// `fill_quantity` describe the fill that caused a `PartiallyFilled`/`Filled` update and are
+/// 0 otherwise; `reason` is only set on `Rejected`.
+#[derive(Debug, Clone, Serialize, Deserialize, SchemaWrite, SchemaRead)]
+pub struct OrderUpdateMsg {
+    pub order_id: u32,
+    pub user_id: u32,
+    pub side: Side,
+    pub price: u32,
+    pub status: OrderStatus,
+    pub fill_price: u32,
+    pub fill_quantity: u32,
+    pub remaining_quantity: u32,
+    pub reason: String,
+    pub timestamp: i64,
 }
@@ -1,5 +1,7 @@
 use crate::orderbook::Order;
+use crate::outputs::{CandleInterval, CandleMsg};
 use crate::persist::event::PersistEvent;
+use scylla::transport::errors::QueryError;
 use scylla::{Session, SessionBuilder};
 use uuid::Uuid;
 
@@ -56,14 +58,50 @@ impl ScyllaClient {
             .await
             .unwrap();
 
+        // Closed candles, newest first per market and interval
+        session
+            .query(
+                "CREATE TABLE IF NOT EXISTS clob.candles (
+                    market text,
+                    interval text,
+                    open_time bigint,
+                    close_time bigint,
+                    open int,
+                    high int,
+                    low int,
+                    close int,
+                    volume bigint,
+                    quote_volume bigint,
+                    trade_count bigint,
+                    PRIMARY KEY ((market, interval), open_time)
+                ) WITH CLUSTERING ORDER BY (open_time DESC);",
+                &[],
+            )
+            .await
+            .unwrap();
+
+        // Last sequence applied by the Kafka consumer, per stream. Lets redelivered records
+        // (applied, but offset not yet committed before a crash) be skipped on restart.
+        session
+            .query(
+                "CREATE TABLE IF NOT EXISTS clob.applied_seq (
+                    topic text,
+                    partition int,
+                    market text,
+                    epoch bigint,
+                    seq bigint,
+                    PRIMARY KEY ((topic, partition, market))
+                );",
+                &[],
+            )
+            .await
+            .unwrap();
+
         println!("[Scylla] Connected and schema initialized.");
         Self { session }
     }
 
-    pub async fn insert_order(
-        &self,
-        order: Order,
-    ) -> Result<(), scylla::transport::errors::QueryError> {
+    pub async fn insert_order(&self, order: Order, write_ts: i64) -> Result<(), QueryError> {
         let side_str = match order.side {
             crate::inputs::Side::Buy => "buy",
             crate::inputs::Side::Sell => "sell",
@@ -72,37 +110,54 @@ impl ScyllaClient {
         self.session
             .query(
                 "INSERT INTO clob.orders (order_id, user_id, price, quantity, side) \
-                 VALUES (?, ?, ?, ?, ?);",
+                 VALUES (?, ?, ?, ?, ?) USING TIMESTAMP ?;",
                 (
                     order.order_id as i32,
                     order.user_id as i32,
                     order.price as i32,
                     order.quantity as i32,
                     side_str,
+                    write_ts,
                 ),
             )
             .await?;
         Ok(())
     }
 
-    pub async fn delete_order(
+    pub async fn delete_order(&self, order_id: u32, write_ts: i64) -> Result<(), QueryError> {
+        self.session
+            .query(
+                "DELETE FROM clob.orders USING TIMESTAMP ? WHERE order_id = ?;",
+                (write_ts, order_id as i32),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Sets the remaining quantity of an order. Absolute, so applying it twice is harmless.
+    pub async fn set_quantity(
         &self,
         order_id: u32,
-    ) -> Result<(), scylla::transport::errors::QueryError> {
+        quantity: u32,
+        write_ts: i64,
+    ) -> Result<(), QueryError> {
         self.session
             .query(
-                "DELETE FROM clob.orders WHERE order_id = ?;",
-                (order_id as i32,),
+                "UPDATE clob.orders USING TIMESTAMP ? SET quantity = ? WHERE order_id = ?;",
+                (write_ts, quantity as i32, order_id as i32),
             )
             .await?;
         Ok(())
     }
 
-    pub async fn mark_filled(
+    /// Takes `traded` off the stored quantity of an order. Read-modify-write, so only for
+    /// fills recorded before they carried the remaining quantity.
+    pub async fn subtract_quantity(
         &self,
         order_id: u32,
-        traded_qty: u32,
-    ) -> Result<(), scylla::transport::errors::QueryError> {
+        traded: u32,
+        write_ts: i64,
+    ) -> Result<(), QueryError> {
         let result = self
             .session
             .query(
@@ -111,21 +166,19 @@ impl ScyllaClient {
             )
             .await?;
 
-        if let Some(row) = result.rows.and_then(|mut r| r.pop()) {
-            let current_qty: i32 = row.columns[0].as_ref().unwrap().as_int().unwrap();
-            let new_qty = std::cmp::max(0, current_qty - traded_qty as i32);
-
-            self.session
-                .query(
-                    "UPDATE clob.orders SET quantity = ? WHERE order_id = ?;",
-                    (new_qty, order_id as i32),
-                )
+        let current = result
+            .rows
+            .and_then(|mut rows| rows.pop())
+            .and_then(|row| row.columns[0].as_ref()?.as_int());
+        if let Some(current) = current {
+            let remaining = current.saturating_sub(traded as i32).max(0);
+            self.set_quantity(order_id, remaining as u32, write_ts)
                 .await?;
         }
-
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn insert_trade(
         &self,
         trade_id: [u8; 16],
@@ -134,13 +187,14 @@ impl ScyllaClient {
         maker_order_id: u32,
         taker_order_id: u32,
         timestamp: i64,
-    ) -> Result<(), scylla::transport::errors::QueryError> {
+        write_ts: i64,
+    ) -> Result<(), QueryError> {
         let trade_id = Uuid::from_bytes(trade_id);
 
         self.session
             .query(
                 "INSERT INTO clob.trades (trade_id, price, quantity, maker_order_id, taker_order_id, timestamp) \
-                 VALUES (?, ?, ?, ?, ?, ?);",
+                 VALUES (?, ?, ?, ?, ?, ?) USING TIMESTAMP ?;",
                 (
                     trade_id,
                     price as i32,
@@ -148,34 +202,111 @@ impl ScyllaClient {
                     maker_order_id as i32,
                     taker_order_id as i32,
                     timestamp,
+                    write_ts,
                 ),
             )
             .await?;
         Ok(())
     }
 
-    pub async fn handle_event(&self, event: PersistEvent) {
+    pub async fn insert_candle(
+        &self,
+        market: &str,
+        candle: &CandleMsg,
+        write_ts: i64,
+    ) -> Result<(), QueryError> {
+        self.session
+            .query(
+                "INSERT INTO clob.candles (market, interval, open_time, close_time, open, high, \
+                 low, close, volume, quote_volume, trade_count) \
+                 VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) USING TIMESTAMP ?;",
+                (
+                    market,
+                    candle.interval.name(),
+                    candle.open_time,
+                    candle.close_time,
+                    candle.open as i32,
+                    candle.high as i32,
+                    candle.low as i32,
+                    candle.close as i32,
+                    candle.volume as i64,
+                    candle.quote_volume as i64,
+                    candle.trade_count as i64,
+                    write_ts,
+                ),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// The newest `limit` candles of `market` at `interval`, oldest first. A candle whose
+    /// period is not over yet comes back with `closed` false.
+    pub async fn load_candles(
+        &self,
+        market: &str,
+        interval: CandleInterval,
+        limit: usize,
+    ) -> Result<Vec<CandleMsg>, QueryError> {
+        let result = self
+            .session
+            .query(
+                "SELECT open_time, close_time, open, high, low, close, volume, quote_volume, \
+                 trade_count FROM clob.candles WHERE market = ? AND interval = ? LIMIT ?;",
+                (market, interval.name(), limit as i32),
+            )
+            .await?;
+
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut candles: Vec<CandleMsg> = result
+            .rows
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|row| {
+                let bigint = |i: usize| row.columns[i].as_ref()?.as_bigint();
+                let int = |i: usize| row.columns[i].as_ref()?.as_int().map(|v| v as u32);
+                let close_time = bigint(1)?;
+                Some(CandleMsg {
+                    interval,
+                    open_time: bigint(0)?,
+                    close_time,
+                    open: int(2)?,
+                    high: int(3)?,
+                    low: int(4)?,
+                    close: int(5)?,
+                    volume: bigint(6)? as u64,
+                    quote_volume: bigint(7)? as u64,
+                    trade_count: bigint(8)? as u64,
+                    closed: close_time < now,
+                })
+            })
+            .collect();
+        candles.reverse();
+        Ok(candles)
+    }
+
+    /// Applies `event` with the given write timestamp (see [`write_timestamp`]). Applying
+    /// the same event again, in any order relative to the others, leaves the same state.
+    ///
+    /// [`write_timestamp`]: crate::persist::event::write_timestamp
+    pub async fn apply(&self, event: &PersistEvent, write_ts: i64) -> Result<(), QueryError> {
         match event {
-            PersistEvent::NewOrder(order) => {
-                if let Err(e) = self.insert_order(order).await {
-                    eprintln!("[Scylla] Failed to insert order: {:?}", e);
-                }
-            }
-            PersistEvent::OrderDeleted { order_id } => {
-                if let Err(e) = self.delete_order(order_id).await {
-                    eprintln!("[Scylla] Failed to delete order {}: {:?}", order_id, e);
-                }
-            }
+            PersistEvent::NewOrder(order) => self.insert_order(order.clone(), write_ts).await,
+            PersistEvent::OrderDeleted { order_id } => self.delete_order(*order_id, write_ts).await,
             PersistEvent::OrderFilled {
+                order_id,
+                remaining_qty,
+                ..
+            }
+            | PersistEvent::OrderAmended {
+                order_id,
+                remaining_qty,
+            } => self.set_quantity(*order_id, *remaining_qty, write_ts).await,
+            PersistEvent::LegacyOrderFilled {
                 order_id,
                 traded_qty,
             } => {
-                if let Err(e) = self.mark_filled(order_id, traded_qty).await {
-                    eprintln!(
-                        "[Scylla] Failed to mark order {} filled (qty={}): {:?}",
-                        order_id, traded_qty, e
-                    );
-                }
+                self.subtract_quantity(*order_id, *traded_qty, write_ts)
+                    .await
             }
             PersistEvent::TradeExecuted {
                 trade_id,
@@ -185,20 +316,61 @@ impl ScyllaClient {
                 taker_order_id,
                 timestamp,
             } => {
-                if let Err(e) = self
-                    .insert_trade(
-                        trade_id,
-                        price,
-                        quantity,
-                        maker_order_id,
-                        taker_order_id,
-                        timestamp,
-                    )
-                    .await
-                {
-                    eprintln!("[Scylla] Failed to insert trade {:?}: {:?}", trade_id, e);
-                }
+                self.insert_trade(
+                    *trade_id,
+                    *price,
+                    *quantity,
+                    *maker_order_id,
+                    *taker_order_id,
+                    *timestamp,
+                    write_ts,
+                )
+                .await
+            }
+            PersistEvent::CandleClosed { market, candle }
+            | PersistEvent::CandleUpdated { market, candle } => {
+                self.insert_candle(market, candle, write_ts).await
             }
         }
     }
+
+    pub async fn load_applied_seq(
+        &self,
+        topic: &str,
+        partition: i32,
+        market: &str,
+    ) -> Result<Option<(i64, u64)>, QueryError> {
+        let result = self
+            .session
+            .query(
+                "SELECT epoch, seq FROM clob.applied_seq \
+                 WHERE topic = ? AND partition = ? AND market = ?;",
+                (topic, partition, market),
+            )
+            .await?;
+
+        Ok(result.rows.and_then(|mut r| r.pop()).and_then(|row| {
+            let epoch = row.columns[0].as_ref()?.as_bigint()?;
+            let seq = row.columns[1].as_ref()?.as_bigint()?;
+            Some((epoch, seq as u64))
+        }))
+    }
+
+    pub async fn store_applied_seq(
+        &self,
+        topic: &str,
+        partition: i32,
+        market: &str,
+        epoch: i64,
+        seq: u64,
+    ) -> Result<(), QueryError> {
+        self.session
+            .query(
+                "INSERT INTO clob.applied_seq (topic, partition, market, epoch, seq) \
+                 VALUES (?, ?, ?, ?, ?);",
+                (topic, partition, market, epoch, seq as i64),
+            )
+            .await?;
+        Ok(())
+    }
 }
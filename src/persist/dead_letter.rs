@@ -0,0 +1,187 @@
+use crate::persist::event::{PersistEvent, PersistEventV2, write_timestamp};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+pub const DEFAULT_DEAD_LETTER_PATH: &str = "persist_dead_letter.jsonl";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    #[serde(deserialize_with = "deserialize_event")]
+    pub event: PersistEvent,
+    /// Write timestamp the event was first applied with; re-drives reuse it. Missing in
+    /// letters written before write timestamps existed.
+    #[serde(default)]
+    pub write_timestamp: Option<i64>,
+    pub error: String,
+    pub attempts: u32,
+    pub failed_at: i64,
+}
+
+impl DeadLetter {
+    /// The write timestamp to re-apply the event with.
+    pub fn write_timestamp(&self) -> i64 {
+        self.write_timestamp
+            .unwrap_or_else(|| write_timestamp(self.failed_at, 0))
+    }
+}
+
+/// Reads the event in its current layout, or as written before schema version 3.
+fn deserialize_event<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PersistEvent, D::Error> {
+    let value = serde_json::Value::deserialize(deserializer)?;
+    PersistEvent::deserialize(&value).or_else(|e| {
+        PersistEventV2::deserialize(&value)
+            .map(PersistEvent::from)
+            .map_err(|_| serde::de::Error::custom(e))
+    })
+}
+
+/// Append-only JSON-lines file holding persistence events that exhausted their retries.
+#[derive(Debug, Clone)]
+pub struct DeadLetterQueue {
+    path: PathBuf,
+}
+
+impl DeadLetterQueue {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn append(&self, letter: &DeadLetter) -> io::Result<()> {
+        let line = serde_json::to_string(letter).map_err(io::Error::other)?;
+        self.append_line(&line).await
+    }
+
+    pub async fn append_line(&self, line: &str) -> io::Result<()> {
+        // Opened per write so a concurrent `take` (rename) never leaves us writing to the old file.
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(format!("{}\n", line).as_bytes()).await?;
+        file.flush().await
+    }
+
+    /// Atomically moves the current dead-letter file aside and returns its lines.
+    /// New failures keep landing in a fresh file while the taken batch is re-driven;
+    /// the batch file is only removed once the caller is done with it.
+    pub async fn take(&self) -> io::Result<Option<DeadLetterBatch>> {
+        let taken = self
+            .path
+            .with_extension(format!("redrive-{}", chrono::Utc::now().timestamp_millis()));
+
+        match fs::rename(&self.path, &taken).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        let contents = fs::read_to_string(&taken).await?;
+        let lines = contents
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(str::to_owned)
+            .collect();
+
+        Ok(Some(DeadLetterBatch { path: taken, lines }))
+    }
+}
+
+pub struct DeadLetterBatch {
+    path: PathBuf,
+    pub lines: Vec<String>,
+}
+
+impl DeadLetterBatch {
+    pub async fn finish(self) -> io::Result<()> {
+        fs::remove_file(&self.path).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn temp_queue() -> DeadLetterQueue {
+        DeadLetterQueue::new(
+            std::env::temp_dir().join(format!("dead-letter-{}.jsonl", uuid::Uuid::new_v4())),
+        )
+    }
+
+    #[tokio::test]
+    async fn take_returns_appended_letters_and_starts_a_new_file() {
+        let queue = temp_queue();
+        assert!(queue.take().await.unwrap().is_none());
+
+        let letter = DeadLetter {
+            event: PersistEvent::OrderDeleted { order_id: 7 },
+            write_timestamp: Some(42),
+            error: "timeout".to_string(),
+            attempts: 6,
+            failed_at: 1,
+        };
+        queue.append(&letter).await.unwrap();
+        queue.append_line("not json").await.unwrap();
+
+        let batch = queue.take().await.unwrap().unwrap();
+        assert_eq!(batch.lines.len(), 2);
+        let read: DeadLetter = serde_json::from_str(&batch.lines[0]).unwrap();
+        assert!(matches!(
+            read.event,
+            PersistEvent::OrderDeleted { order_id: 7 }
+        ));
+        assert_eq!(read.write_timestamp(), 42);
+        assert_eq!(read.attempts, 6);
+
+        // Failures during the re-drive land in a fresh file.
+        assert!(!queue.path().exists());
+        queue.append_line("later").await.unwrap();
+        batch.finish().await.unwrap();
+        let next = queue.take().await.unwrap().unwrap();
+        assert_eq!(next.lines, vec!["later".to_string()]);
+        next.finish().await.unwrap();
+    }
+
+    #[test]
+    fn letters_without_a_write_timestamp_fall_back_to_their_failure_time() {
+        let letter: DeadLetter = serde_json::from_str(
+            r#"{"event":{"OrderDeleted":{"order_id":1}},"error":"e","attempts":1,"failed_at":5}"#,
+        )
+        .unwrap();
+        assert_eq!(letter.write_timestamp(), write_timestamp(5, 0));
+    }
+
+    #[test]
+    fn letters_written_before_fills_carried_the_remaining_quantity_still_load() {
+        let letter: DeadLetter = serde_json::from_str(
+            r#"{"event":{"OrderFilled":{"order_id":1,"traded_qty":2}},"error":"e","attempts":1,"failed_at":5}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            letter.event,
+            PersistEvent::LegacyOrderFilled {
+                order_id: 1,
+                traded_qty: 2
+            }
+        ));
+
+        let letter: DeadLetter = serde_json::from_str(
+            r#"{"event":{"OrderFilled":{"order_id":1,"traded_qty":2,"remaining_qty":3}},"error":"e","attempts":1,"failed_at":5}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            letter.event,
+            PersistEvent::OrderFilled {
+                remaining_qty: 3,
+                ..
+            }
+        ));
+    }
+}
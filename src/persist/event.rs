@@ -1,12 +1,20 @@
+use crate::envelope::{
+    EnvelopeError, FIRST_FRAMED_VERSION, LEGACY_VERSION, Payload, decode_payload,
+};
 use crate::orderbook::Order;
+use crate::outputs::CandleMsg;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 use wincode_derive::{SchemaRead, SchemaWrite};
 
-#[derive(Debug, Clone, SchemaWrite, SchemaRead)]
+#[derive(Debug, Clone, Serialize, Deserialize, SchemaWrite, SchemaRead)]
 pub enum PersistEvent {
     NewOrder(Order),
+    /// A resting order traded `traded_qty`, leaving `remaining_qty` on the book.
     OrderFilled {
         order_id: u32,
         traded_qty: u32,
+        remaining_qty: u32,
     },
     OrderDeleted {
         order_id: u32,
@@ -19,4 +27,172 @@ pub enum PersistEvent {
         taker_order_id: u32,
         timestamp: i64,
     },
+    CandleClosed {
+        market: String,
+        candle: CandleMsg,
+    },
+    /// A resting order was amended down to `remaining_qty` at its price, keeping its place.
+    OrderAmended {
+        order_id: u32,
+        remaining_qty: u32,
+    },
+    /// Checkpoint of a candle still open, so a restart can carry on with it.
+    CandleUpdated {
+        market: String,
+        candle: CandleMsg,
+    },
+    /// An `OrderFilled` written before schema version 3, without the remaining quantity.
+    /// Only ever decoded from old records. Applied by taking `traded_qty` off the stored
+    /// quantity, so unlike the other events it is not idempotent.
+    LegacyOrderFilled {
+        order_id: u32,
+        traded_qty: u32,
+    },
+}
+
+/// `PersistEvent` as written by schema versions 1 and 2. Variants are only ever appended,
+/// so it ends where those versions did.
+#[derive(Deserialize, SchemaRead)]
+pub(crate) enum PersistEventV2 {
+    NewOrder(Order),
+    OrderFilled {
+        order_id: u32,
+        traded_qty: u32,
+    },
+    OrderDeleted {
+        order_id: u32,
+    },
+    TradeExecuted {
+        trade_id: [u8; 16],
+        price: u32,
+        quantity: u32,
+        maker_order_id: u32,
+        taker_order_id: u32,
+        timestamp: i64,
+    },
+    CandleClosed {
+        market: String,
+        candle: CandleMsg,
+    },
+}
+
+impl From<PersistEventV2> for PersistEvent {
+    fn from(event: PersistEventV2) -> Self {
+        match event {
+            PersistEventV2::NewOrder(order) => PersistEvent::NewOrder(order),
+            PersistEventV2::OrderFilled {
+                order_id,
+                traded_qty,
+            } => PersistEvent::LegacyOrderFilled {
+                order_id,
+                traded_qty,
+            },
+            PersistEventV2::OrderDeleted { order_id } => PersistEvent::OrderDeleted { order_id },
+            PersistEventV2::TradeExecuted {
+                trade_id,
+                price,
+                quantity,
+                maker_order_id,
+                taker_order_id,
+                timestamp,
+            } => PersistEvent::TradeExecuted {
+                trade_id,
+                price,
+                quantity,
+                maker_order_id,
+                taker_order_id,
+                timestamp,
+            },
+            PersistEventV2::CandleClosed { market, candle } => {
+                PersistEvent::CandleClosed { market, candle }
+            }
+        }
+    }
+}
+
+impl Payload for PersistEvent {
+    fn decode_older(version: u16, bytes: &[u8]) -> Result<Self, EnvelopeError> {
+        match version {
+            LEGACY_VERSION | FIRST_FRAMED_VERSION => {
+                decode_payload::<PersistEventV2>(bytes).map(PersistEvent::from)
+            }
+            _ => Err(EnvelopeError::UnsupportedVersion(version)),
+        }
+    }
+}
+
+/// Events are split across two Kafka topics; ordering and sequence numbers are per stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PersistStream {
+    Orders,
+    Trades,
+}
+
+impl PersistEvent {
+    pub fn stream(&self) -> PersistStream {
+        match self {
+            PersistEvent::TradeExecuted { .. }
+            | PersistEvent::CandleClosed { .. }
+            | PersistEvent::CandleUpdated { .. } => PersistStream::Trades,
+            _ => PersistStream::Orders,
+        }
+    }
+
+    pub fn order_id(&self) -> Option<u32> {
+        match self {
+            PersistEvent::NewOrder(order) => Some(order.order_id),
+            PersistEvent::OrderFilled { order_id, .. }
+            | PersistEvent::LegacyOrderFilled { order_id, .. }
+            | PersistEvent::OrderAmended { order_id, .. }
+            | PersistEvent::OrderDeleted { order_id } => Some(*order_id),
+            PersistEvent::TradeExecuted { .. }
+            | PersistEvent::CandleClosed { .. }
+            | PersistEvent::CandleUpdated { .. } => None,
+        }
+    }
+}
+
+/// Scylla write timestamp of the event with this position. Every statement is written
+/// `USING TIMESTAMP` it, so re-applying an event (a retry after a write timeout, a Kafka
+/// redelivery, a dead-letter re-drive) never overwrites what a later event of the same
+/// order wrote.
+///
+/// In microseconds, like the timestamps Scylla and cqlsh assign: the epoch in µs plus the
+/// sequence number. An epoch's timestamps stay below the next epoch's as long as it
+/// averaged fewer than one event per µs of uptime. The same bound keeps them below the
+/// current time, so a write without `USING TIMESTAMP` (e.g. a manual fix from cqlsh)
+/// overrides what the engine wrote before it.
+pub fn write_timestamp(epoch: i64, seq: u64) -> i64 {
+    epoch * 1_000 + seq as i64
+}
+
+/// A `PersistEvent` stamped by the engine that produced it.
+#[derive(Debug, Clone)]
+pub struct PersistRecord {
+    pub market: Arc<str>,
+    /// Engine start time in ms. Sequence numbers restart at 1 with every epoch.
+    pub epoch: i64,
+    /// Per-market, per-stream sequence number without gaps, starting at 1.
+    pub seq: u64,
+    pub event: PersistEvent,
+}
+
+impl PersistRecord {
+    pub fn write_timestamp(&self) -> i64 {
+        write_timestamp(self.epoch, self.seq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_timestamps_are_microseconds_ordered_by_epoch_then_seq() {
+        let epoch = chrono::Utc::now().timestamp_millis() - 1_000;
+        assert_eq!(write_timestamp(epoch, 0), epoch * 1_000);
+        assert!(write_timestamp(epoch, 5) < write_timestamp(epoch, 6));
+        assert!(write_timestamp(epoch, 999_999) < write_timestamp(epoch + 1_000, 0));
+        assert!(write_timestamp(epoch, 999_999) < chrono::Utc::now().timestamp_micros());
+    }
 }
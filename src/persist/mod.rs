@@ -1,6 +1,10 @@
 pub mod client;
+pub mod dead_letter;
 pub mod event;
+pub mod retry;
 pub mod worker;
 
-pub use event::PersistEvent;
+pub use dead_letter::{DEFAULT_DEAD_LETTER_PATH, DeadLetterQueue};
+pub use event::{PersistEvent, PersistRecord, PersistStream};
+pub use retry::RetryPolicy;
 pub use worker::start_persistence_worker;
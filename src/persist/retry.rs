@@ -0,0 +1,149 @@
+use crate::metrics::PERSIST_RETRIES_TOTAL;
+use scylla::transport::errors::{DbError, QueryError};
+use std::future::Future;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 6,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `attempt` (1-based): doubles every attempt, capped at `max_backoff`.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32 << attempt.saturating_sub(1).min(16);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+/// Runs `op`, retrying transient errors with exponential backoff.
+/// On failure returns the last error and the number of attempts made.
+pub async fn retry_transient<F, Fut>(
+    retry: &RetryPolicy,
+    mut op: F,
+) -> Result<(), (QueryError, u32)>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), QueryError>>,
+{
+    let mut attempt = 1;
+    loop {
+        match op().await {
+            Ok(()) => return Ok(()),
+            Err(e) if is_transient(&e) && attempt < retry.max_attempts => {
+                PERSIST_RETRIES_TOTAL.inc();
+                let delay = retry.backoff(attempt);
+                eprintln!(
+                    "[Persist] Transient error (attempt {}/{}), retrying in {:?}: {:?}",
+                    attempt, retry.max_attempts, delay, e
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err((e, attempt)),
+        }
+    }
+}
+
+/// Errors worth retrying: the cluster or connection was temporarily unable to serve the
+/// query. Anything else (bad query, schema mismatch, auth) will fail the same way again.
+///
+/// A timed-out write may still have been applied. Retrying it is safe only because every
+/// statement is idempotent: absolute values written `USING TIMESTAMP` the event's write
+/// timestamp.
+pub fn is_transient(err: &QueryError) -> bool {
+    match err {
+        QueryError::IoError(_)
+        | QueryError::TimeoutError
+        | QueryError::RequestTimeout(_)
+        | QueryError::TooManyOrphanedStreamIds(_)
+        | QueryError::UnableToAllocStreamId => true,
+        QueryError::DbError(db_err, _) => matches!(
+            db_err,
+            DbError::Unavailable { .. }
+                | DbError::Overloaded
+                | DbError::IsBootstrapping
+                | DbError::ReadTimeout { .. }
+                | DbError::WriteTimeout { .. }
+                | DbError::ServerError
+                | DbError::RateLimitReached { .. }
+        ),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let retry = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_millis(300),
+        };
+        assert_eq!(retry.backoff(1), Duration::from_millis(50));
+        assert_eq!(retry.backoff(2), Duration::from_millis(100));
+        assert_eq!(retry.backoff(3), Duration::from_millis(200));
+        assert_eq!(retry.backoff(4), Duration::from_millis(300));
+        assert_eq!(retry.backoff(u32::MAX), Duration::from_millis(300));
+    }
+
+    fn instant_retries(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        }
+    }
+
+    #[tokio::test]
+    async fn transient_errors_are_retried_until_success() {
+        let mut calls = 0;
+        let result = retry_transient(&instant_retries(5), || {
+            calls += 1;
+            let outcome = if calls < 3 {
+                Err(QueryError::TimeoutError)
+            } else {
+                Ok(())
+            };
+            async move { outcome }
+        })
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(calls, 3);
+    }
+
+    #[tokio::test]
+    async fn retries_stop_at_max_attempts_or_a_permanent_error() {
+        let result = retry_transient(&instant_retries(3), || async {
+            Err(QueryError::TimeoutError)
+        })
+        .await;
+        assert!(matches!(result, Err((QueryError::TimeoutError, 3))));
+
+        let mut calls = 0;
+        let result = retry_transient(&instant_retries(3), || {
+            calls += 1;
+            async { Err(QueryError::InvalidMessage("bad".to_string())) }
+        })
+        .await;
+        assert!(matches!(result, Err((_, 1))));
+        assert_eq!(calls, 1);
+    }
+}
@@ -1,66 +1,215 @@
+use crate::metrics::{PERSIST_FAILURES_TOTAL, PERSIST_REDRIVEN_TOTAL};
 use crate::persist::client::ScyllaClient;
-use crate::persist::event::PersistEvent;
+use crate::persist::dead_letter::{DeadLetter, DeadLetterQueue};
+use crate::persist::event::{PersistEvent, PersistRecord};
+use crate::persist::retry::{RetryPolicy, retry_transient};
+use scylla::transport::errors::QueryError;
+use std::future::Future;
 use tokio::sync::mpsc::UnboundedReceiver;
 
 pub async fn start_persistence_worker(
-    mut rx: UnboundedReceiver<PersistEvent>,
+    mut rx: UnboundedReceiver<PersistRecord>,
     scylla: ScyllaClient,
+    retry: RetryPolicy,
+    dead_letters: DeadLetterQueue,
 ) {
     tokio::spawn(async move {
-        while let Some(event) = rx.recv().await {
-            match event {
-                PersistEvent::NewOrder(order) => {
-                    println!("[Persist] New Order: {:?}", order);
-                    if let Err(e) = scylla.insert_order(order).await {
-                        eprintln!("Failed to persist order: {:?}", e);
-                    }
-                }
-                PersistEvent::OrderDeleted { order_id } => {
-                    println!("[Persist] Order Deleted: {}", order_id);
-                    if let Err(e) = scylla.delete_order(order_id).await {
-                        eprintln!("Failed to delete order: {:?}", e);
-                    }
-                }
-                PersistEvent::OrderFilled {
-                    order_id,
-                    traded_qty,
-                } => {
-                    println!(
-                        "[Persist] Order filled: id={}, qty={}",
-                        order_id, traded_qty
-                    );
-                    if let Err(e) = scylla.mark_filled(order_id, traded_qty).await {
-                        eprintln!("Failed to mark order filled: {:?}", e);
-                    }
-                }
-                PersistEvent::TradeExecuted {
-                    trade_id,
-                    price,
-                    quantity,
-                    maker_order_id,
-                    taker_order_id,
-                    timestamp,
-                } => {
-                    println!(
-                        "[Persist] Trade executed: price={}, qty={}, maker={}, taker={}",
-                        price, quantity, maker_order_id, taker_order_id
-                    );
-
-                    if let Err(e) = scylla
-                        .insert_trade(
-                            trade_id,
-                            price,
-                            quantity,
-                            maker_order_id,
-                            taker_order_id,
-                            timestamp,
-                        )
-                        .await
-                    {
-                        eprintln!("Failed to persist trade: {:?}", e);
-                    }
-                }
+        while let Some(record) = rx.recv().await {
+            let write_ts = record.write_timestamp();
+            let event = record.event;
+            if let Err((e, attempts)) = apply_with_retry(&scylla, &event, write_ts, &retry).await {
+                dead_letter(&dead_letters, event, write_ts, &e, attempts).await;
             }
         }
     });
 }
+
+/// Applies `event`, retrying transient errors with exponential backoff.
+/// On failure returns the last error and the number of attempts made.
+pub async fn apply_with_retry(
+    scylla: &ScyllaClient,
+    event: &PersistEvent,
+    write_ts: i64,
+    retry: &RetryPolicy,
+) -> Result<(), (QueryError, u32)> {
+    retry_transient(retry, || scylla.apply(event, write_ts)).await
+}
+
+async fn dead_letter(
+    queue: &DeadLetterQueue,
+    event: PersistEvent,
+    write_ts: i64,
+    err: &QueryError,
+    attempts: u32,
+) {
+    PERSIST_FAILURES_TOTAL.inc();
+    eprintln!(
+        "[Persist] Giving up after {} attempt(s), dead-lettering {:?}: {:?}",
+        attempts, event, err
+    );
+
+    let letter = DeadLetter {
+        event,
+        write_timestamp: Some(write_ts),
+        error: err.to_string(),
+        attempts,
+        failed_at: chrono::Utc::now().timestamp_millis(),
+    };
+
+    if let Err(e) = queue.append(&letter).await {
+        eprintln!(
+            "[Persist] Failed to write dead letter to {}: {:?} (event lost: {:?})",
+            queue.path().display(),
+            e,
+            letter.event
+        );
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RedriveReport {
+    pub redriven: usize,
+    pub failed: usize,
+}
+
+/// Re-applies every event in the dead-letter file. Events that fail again are written
+/// back to the queue, so re-driving is safe to repeat.
+///
+/// Later events of the same orders were applied while these sat in the queue. Each event
+/// is re-applied with its original write timestamp, so Scylla keeps the later state (a
+/// re-driven `NewOrder` does not bring back an order deleted since).
+pub async fn redrive_dead_letters(
+    scylla: &ScyllaClient,
+    queue: &DeadLetterQueue,
+    retry: &RetryPolicy,
+) -> std::io::Result<RedriveReport> {
+    redrive_with(queue, |event, write_ts| async move {
+        apply_with_retry(scylla, &event, write_ts, retry).await
+    })
+    .await
+}
+
+/// [`redrive_dead_letters`] with the apply step supplied by the caller. Letters are
+/// re-applied oldest write first.
+pub async fn redrive_with<F, Fut>(
+    queue: &DeadLetterQueue,
+    mut apply: F,
+) -> std::io::Result<RedriveReport>
+where
+    F: FnMut(PersistEvent, i64) -> Fut,
+    Fut: Future<Output = Result<(), (QueryError, u32)>>,
+{
+    let mut report = RedriveReport::default();
+
+    let Some(batch) = queue.take().await? else {
+        return Ok(report);
+    };
+
+    let mut letters = Vec::with_capacity(batch.lines.len());
+    for line in &batch.lines {
+        match serde_json::from_str::<DeadLetter>(line) {
+            Ok(letter) => letters.push(letter),
+            Err(e) => {
+                eprintln!("[Persist] Unreadable dead letter kept as-is: {}", e);
+                queue.append_line(line).await?;
+                report.failed += 1;
+            }
+        }
+    }
+    letters.sort_by_key(DeadLetter::write_timestamp);
+
+    for letter in letters {
+        let write_ts = letter.write_timestamp();
+        match apply(letter.event.clone(), write_ts).await {
+            Ok(()) => {
+                PERSIST_REDRIVEN_TOTAL.inc();
+                report.redriven += 1;
+            }
+            Err((e, attempts)) => {
+                dead_letter(
+                    queue,
+                    letter.event,
+                    write_ts,
+                    &e,
+                    letter.attempts + attempts,
+                )
+                .await;
+                report.failed += 1;
+            }
+        }
+    }
+
+    batch.finish().await?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persist::dead_letter::tests::temp_queue;
+
+    fn letter(order_id: u32, write_ts: i64) -> DeadLetter {
+        DeadLetter {
+            event: PersistEvent::OrderDeleted { order_id },
+            write_timestamp: Some(write_ts),
+            error: "timeout".to_string(),
+            attempts: 6,
+            failed_at: 0,
+        }
+    }
+
+    fn order_id(event: &PersistEvent) -> u32 {
+        event.order_id().unwrap()
+    }
+
+    #[tokio::test]
+    async fn redrive_applies_letters_in_write_order_with_their_timestamps() {
+        let queue = temp_queue();
+        queue.append(&letter(2, 200)).await.unwrap();
+        queue.append(&letter(1, 100)).await.unwrap();
+
+        let mut applied = Vec::new();
+        let report = redrive_with(&queue, |event, write_ts| {
+            applied.push((order_id(&event), write_ts));
+            async { Ok(()) }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!((report.redriven, report.failed), (2, 0));
+        assert_eq!(applied, vec![(1, 100), (2, 200)]);
+        assert!(queue.take().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn letters_failing_again_are_requeued_and_can_be_redriven_later() {
+        let queue = temp_queue();
+        queue.append(&letter(1, 100)).await.unwrap();
+        queue.append(&letter(2, 200)).await.unwrap();
+        queue.append_line("garbage").await.unwrap();
+
+        let report = redrive_with(&queue, |event, _| {
+            let outcome = match order_id(&event) {
+                1 => Ok(()),
+                _ => Err((QueryError::TimeoutError, 2)),
+            };
+            async move { outcome }
+        })
+        .await
+        .unwrap();
+        assert_eq!((report.redriven, report.failed), (1, 2));
+
+        let batch = queue.take().await.unwrap().unwrap();
+        assert_eq!(batch.lines.len(), 2);
+        assert!(batch.lines.contains(&"garbage".to_string()));
+        let requeued = batch
+            .lines
+            .iter()
+            .find_map(|line| serde_json::from_str::<DeadLetter>(line).ok())
+            .unwrap();
+        assert_eq!(order_id(&requeued.event), 2);
+        assert_eq!(requeued.write_timestamp(), 200);
+        assert_eq!(requeued.attempts, 8);
+        batch.finish().await.unwrap();
+    }
+}
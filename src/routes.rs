@@ -2,23 +2,27 @@ use actix_web::{
     HttpRequest, HttpResponse, Responder, delete, get, post,
     web::{self, Data},
 };
-use parking_lot::RwLock;
+use chrono::Utc;
 use prometheus::{Encoder, TextEncoder};
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use std::sync::atomic::Ordering;
 use std::time::Instant;
 use tokio::sync::mpsc;
+use wincode::SchemaWrite;
 
 use crate::{
     ORDER_ID_COUNTER,
-    events::OrderEvent,
+    events::{OrderEvent, OrderRequest},
     inputs::{CreateOrderInput, DeleteOrder},
+    market_data::SharedMarketData,
     metrics::{HTTP_LATENCY_MS, HTTP_REQUESTS_TOTAL},
     msgpack::MsgPackResponse,
-    outputs::{CreateOrderResponse, DeleteOrderResponse, Depth},
+    orderbook::MAX_DEPTH_LEVELS,
+    outputs::{CandleInterval, CreateOrderResponse, DeleteOrderResponse, L3Level},
 };
 
-type OrderSender = Arc<mpsc::UnboundedSender<OrderEvent>>;
+pub type OrderSender = Arc<mpsc::UnboundedSender<OrderRequest>>;
 
 fn is_msgpack(req: &HttpRequest) -> bool {
     req.headers()
@@ -52,6 +56,39 @@ fn wants_wincode(req: &HttpRequest) -> bool {
         .unwrap_or(false)
 }
 
+/// `value` as MessagePack if the `Accept` header asks for it, else as JSON.
+fn respond<T: Serialize>(req: &HttpRequest, value: &T) -> HttpResponse {
+    if wants_msgpack(req) {
+        match rmp_serde::to_vec_named(value) {
+            Ok(bytes) => {
+                return HttpResponse::Ok()
+                    .content_type("application/msgpack")
+                    .body(bytes);
+            }
+            Err(e) => eprintln!("Failed to serialize response to MessagePack: {}", e),
+        }
+    }
+    HttpResponse::Ok().json(value)
+}
+
+/// Like [`respond`], but also as wincode if the `Accept` header asks for it.
+fn respond_wincode<T: Serialize + SchemaWrite<Src = T>>(
+    req: &HttpRequest,
+    value: &T,
+) -> HttpResponse {
+    if wants_wincode(req) {
+        match wincode::serialize(value) {
+            Ok(bytes) => {
+                return HttpResponse::Ok()
+                    .content_type("application/octet-stream")
+                    .body(bytes);
+            }
+            Err(e) => eprintln!("Failed to serialize response to wincode: {:?}", e),
+        }
+    }
+    respond(req, value)
+}
+
 #[post("/order")]
 pub async fn create_order(
     req: HttpRequest,
@@ -92,7 +129,7 @@ pub async fn create_order(
         side: input.side,
     };
 
-    match sender.send(event) {
+    match sender.send(event.into()) {
         Ok(_) => {
             HTTP_LATENCY_MS.observe(start.elapsed().as_secs_f64() * 1000.0);
             let response = CreateOrderResponse {
@@ -149,7 +186,7 @@ pub async fn delete_order(
     let order_id = input.order_id;
     let event = OrderEvent::DeleteOrder { order_id };
 
-    match sender.send(event) {
+    match sender.send(event.into()) {
         Ok(_) => {
             HTTP_LATENCY_MS.observe(start.elapsed().as_secs_f64() * 1000.0);
             let response = DeleteOrderResponse {
@@ -174,44 +211,235 @@ pub async fn delete_order(
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct DepthQuery {
+    pub limit: Option<usize>,
+    pub step: Option<u32>,
+}
+
+/// Top `limit` levels per side (default 10, at most `MAX_DEPTH_LEVELS`). With `step`, the
+/// levels are first merged into price buckets of that size. `checksum` always covers the
+/// unaggregated book.
 #[get("/depth")]
-pub async fn get_depth(req: HttpRequest, depth: Data<Arc<RwLock<Depth>>>) -> impl Responder {
+pub async fn get_depth(
+    req: HttpRequest,
+    query: web::Query<DepthQuery>,
+    market_data: Data<SharedMarketData>,
+) -> impl Responder {
     let start = Instant::now();
     HTTP_REQUESTS_TOTAL.inc();
 
-    let d = depth.read();
-    let response = Depth {
-        bids: d.bids.clone(),
-        asks: d.asks.clone(),
-        last_update_id: d.last_update_id.clone(),
+    let limit = query.limit.unwrap_or(10);
+    if limit == 0 || limit > MAX_DEPTH_LEVELS {
+        return HttpResponse::BadRequest()
+            .body(format!("limit must be between 1 and {}", MAX_DEPTH_LEVELS));
+    }
+    if query.step == Some(0) {
+        return HttpResponse::BadRequest().body("step must be positive");
+    }
+
+    let response = match query.step {
+        Some(step) if step > 1 => market_data.depth.aggregated(step, limit),
+        _ => market_data.depth.depth(limit),
     };
-    drop(d);
 
     HTTP_LATENCY_MS.observe(start.elapsed().as_secs_f64() * 1000.0);
 
-    if wants_wincode(&req) {
-        match wincode::serialize(&response) {
-            Ok(bytes) => HttpResponse::Ok()
-                .content_type("application/octet-stream")
-                .body(bytes),
-            Err(e) => {
-                eprintln!("Failed to serialize depth to wincode: {:?}", e);
-                HttpResponse::Ok().json(response)
-            }
-        }
-    } else if wants_msgpack(&req) {
-        match rmp_serde::to_vec(&response) {
-            Ok(bytes) => HttpResponse::Ok()
-                .content_type("application/msgpack")
-                .body(bytes),
-            Err(e) => {
-                eprintln!("Failed to serialize depth to MessagePack: {}", e);
-                HttpResponse::Ok().json(response)
-            }
-        }
-    } else {
-        HttpResponse::Ok().json(response)
+    respond_wincode(&req, &response)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RecentTradesQuery {
+    pub market: Option<String>,
+    pub limit: Option<usize>,
+}
+
+/// Newest trades of the market, oldest first: `limit` (default 100) of the last
+/// `RECENT_TRADES_CAPACITY`.
+#[get("/trades/recent")]
+pub async fn get_recent_trades(
+    req: HttpRequest,
+    query: web::Query<RecentTradesQuery>,
+    market_data: Data<SharedMarketData>,
+) -> impl Responder {
+    let start = Instant::now();
+    HTTP_REQUESTS_TOTAL.inc();
+
+    if let Some(market) = &query.market
+        && **market != *market_data.market
+    {
+        return HttpResponse::NotFound().body(format!("unknown market '{}'", market));
+    }
+    let trades = market_data
+        .trades
+        .read()
+        .recent_trades
+        .latest(query.limit.unwrap_or(100));
+
+    HTTP_LATENCY_MS.observe(start.elapsed().as_secs_f64() * 1000.0);
+
+    respond(&req, &trades)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MarketQuery {
+    pub market: Option<String>,
+}
+
+/// Best bid and ask with the `seq` of the `bbo` message that last changed them.
+#[get("/bbo")]
+pub async fn get_bbo(
+    req: HttpRequest,
+    query: web::Query<MarketQuery>,
+    market_data: Data<SharedMarketData>,
+) -> impl Responder {
+    let start = Instant::now();
+    HTTP_REQUESTS_TOTAL.inc();
+
+    if let Some(market) = &query.market
+        && **market != *market_data.market
+    {
+        return HttpResponse::NotFound().body(format!("unknown market '{}'", market));
     }
+    let bbo = market_data.bbo.read().clone();
+
+    HTTP_LATENCY_MS.observe(start.elapsed().as_secs_f64() * 1000.0);
+
+    respond(&req, &bbo)
+}
+
+/// Rolling 24h statistics of the market as of now.
+#[get("/ticker")]
+pub async fn get_ticker(
+    req: HttpRequest,
+    query: web::Query<MarketQuery>,
+    market_data: Data<SharedMarketData>,
+) -> impl Responder {
+    let start = Instant::now();
+    HTTP_REQUESTS_TOTAL.inc();
+
+    if let Some(market) = &query.market
+        && **market != *market_data.market
+    {
+        return HttpResponse::NotFound().body(format!("unknown market '{}'", market));
+    }
+    let ticker = market_data
+        .trades
+        .read()
+        .ticker
+        .stats(Utc::now().timestamp_millis());
+
+    HTTP_LATENCY_MS.observe(start.elapsed().as_secs_f64() * 1000.0);
+
+    respond(&req, &ticker)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct KlinesQuery {
+    pub symbol: String,
+    pub interval: String,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub limit: Option<usize>,
+}
+
+/// Candles of `interval` opened between `from` and `to` (ms), oldest first, including the
+/// one still open. `limit` defaults to 500.
+#[get("/klines")]
+pub async fn get_klines(
+    req: HttpRequest,
+    query: web::Query<KlinesQuery>,
+    market_data: Data<SharedMarketData>,
+) -> impl Responder {
+    let start = Instant::now();
+    HTTP_REQUESTS_TOTAL.inc();
+
+    if *query.symbol != *market_data.market {
+        return HttpResponse::NotFound().body(format!("unknown market '{}'", query.symbol));
+    }
+    let Some(interval) = CandleInterval::from_name(&query.interval) else {
+        return HttpResponse::BadRequest().body(format!(
+            "unknown interval '{}', expected 1m, 5m, 1h or 1d",
+            query.interval
+        ));
+    };
+    let candles = market_data.trades.read().candles.range(
+        interval,
+        query.from,
+        query.to,
+        query.limit.unwrap_or(500),
+    );
+
+    HTTP_LATENCY_MS.observe(start.elapsed().as_secs_f64() * 1000.0);
+
+    respond(&req, &candles)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct L3Query {
+    pub snapshot_id: Option<u64>,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct L3Page<'a> {
+    /// `update_id` of the snapshot; pass it back to fetch the next page of the same book.
+    pub snapshot_id: u64,
+    pub timestamp: i64,
+    pub bids: &'a [L3Level],
+    pub asks: &'a [L3Level],
+    /// `offset` of the next page, if either side has more levels.
+    pub next_offset: Option<usize>,
+}
+
+/// Individual resting orders per level, in queue order. Without `snapshot_id` a new
+/// snapshot is taken; pages are `limit` levels per side (default 100, at most 1000)
+/// starting at `offset`.
+#[get("/l3")]
+pub async fn get_l3(
+    req: HttpRequest,
+    query: web::Query<L3Query>,
+    market_data: Data<SharedMarketData>,
+) -> impl Responder {
+    let start = Instant::now();
+    HTTP_REQUESTS_TOTAL.inc();
+
+    let limit = query.limit.unwrap_or(100);
+    if limit == 0 || limit > 1000 {
+        return HttpResponse::BadRequest().body("limit must be between 1 and 1000");
+    }
+
+    let book = match query.snapshot_id {
+        Some(id) => match market_data.l3.get(id) {
+            Some(book) => book,
+            None => {
+                return HttpResponse::Gone()
+                    .body(format!("snapshot {} has expired, start over", id));
+            }
+        },
+        None => match market_data.l3.take().await {
+            Some(book) => book,
+            None => return HttpResponse::InternalServerError().body("Matching engine unavailable"),
+        },
+    };
+
+    let offset = query.offset.unwrap_or(0);
+    let page = |levels: &[L3Level]| -> std::ops::Range<usize> {
+        offset.min(levels.len())..offset.saturating_add(limit).min(levels.len())
+    };
+    let end = offset.saturating_add(limit);
+    let response = L3Page {
+        snapshot_id: book.update_id,
+        timestamp: book.timestamp,
+        bids: &book.bids[page(&book.bids)],
+        asks: &book.asks[page(&book.asks)],
+        next_offset: (end < book.bids.len().max(book.asks.len())).then_some(end),
+    };
+
+    HTTP_LATENCY_MS.observe(start.elapsed().as_secs_f64() * 1000.0);
+
+    respond(&req, &response)
 }
 
 #[get("/metrics")]
@@ -0,0 +1,689 @@
+use crate::config::WsConfig;
+use crate::metrics::{
+    MD_PUBLISH_DROPPED_TOTAL, WS_DEPTH_CONFLATED_TOTAL, WS_MESSAGES_DROPPED_TOTAL,
+    WS_SLOW_DISCONNECTS_TOTAL,
+};
+use crate::outputs::CandleInterval;
+use crate::worker::feed::{Encoding, FeedMessage, FeedPayload};
+use crate::worker::ws::WsMessage;
+use actix::prelude::*;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::cell::OnceCell;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+/// Messages the matching loop may publish ahead of the fan-out thread before new ones
+/// are dropped.
+const PUBLISH_QUEUE_CAPACITY: usize = 65_536;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    Trades,
+    Depth,
+    Bbo,
+    Ticker,
+    #[serde(rename = "candles_1m")]
+    Candles1m,
+    #[serde(rename = "candles_5m")]
+    Candles5m,
+    #[serde(rename = "candles_1h")]
+    Candles1h,
+    #[serde(rename = "candles_1d")]
+    Candles1d,
+    /// Order-by-order book changes.
+    L3,
+    /// Private: the subscriber's own order updates.
+    Orders,
+}
+
+impl Channel {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "trades" => Some(Channel::Trades),
+            "depth" => Some(Channel::Depth),
+            "bbo" => Some(Channel::Bbo),
+            "ticker" => Some(Channel::Ticker),
+            "candles_1m" => Some(Channel::Candles1m),
+            "candles_5m" => Some(Channel::Candles5m),
+            "candles_1h" => Some(Channel::Candles1h),
+            "candles_1d" => Some(Channel::Candles1d),
+            "l3" => Some(Channel::L3),
+            "orders" => Some(Channel::Orders),
+            _ => None,
+        }
+    }
+
+    pub fn candles(interval: CandleInterval) -> Self {
+        match interval {
+            CandleInterval::OneMinute => Channel::Candles1m,
+            CandleInterval::FiveMinutes => Channel::Candles5m,
+            CandleInterval::OneHour => Channel::Candles1h,
+            CandleInterval::OneDay => Channel::Candles1d,
+        }
+    }
+
+    pub fn is_private(self) -> bool {
+        matches!(self, Channel::Orders)
+    }
+}
+
+/// One channel of one market; private channels are further scoped to a single user.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Topic {
+    pub channel: Channel,
+    pub market: Arc<str>,
+    pub user: Option<u32>,
+}
+
+impl Topic {
+    pub fn new(channel: Channel, market: impl Into<Arc<str>>) -> Self {
+        Self {
+            channel,
+            market: market.into(),
+            user: None,
+        }
+    }
+
+    pub fn private(channel: Channel, market: impl Into<Arc<str>>, user_id: u32) -> Self {
+        Self {
+            channel,
+            market: market.into(),
+            user: Some(user_id),
+        }
+    }
+}
+
+pub type SessionId = u64;
+
+/// Delivery state one session shares with the fan-out thread.
+#[derive(Debug, Default)]
+pub struct Outbound {
+    /// Messages handed to the session but not yet written to its socket.
+    pub pending: AtomicUsize,
+    /// Set by the fan-out when the session lagged for too long; the session closes itself.
+    pub disconnect: AtomicBool,
+}
+
+/// A subscribed session as seen by the fan-out.
+#[derive(Clone)]
+pub struct SessionHandle {
+    pub recipient: Recipient<WsMessage>,
+    pub outbound: Arc<Outbound>,
+    pub encoding: Encoding,
+    /// Live messages up to this `seq` were already sent by replay; set by `subscribe`.
+    pub skip_through: u64,
+}
+
+impl SessionHandle {
+    fn send(&self, frame: WsMessage) {
+        self.outbound.pending.fetch_add(1, Ordering::Relaxed);
+        self.recipient.do_send(frame);
+    }
+}
+
+type Subscribers = HashMap<SessionId, SessionHandle>;
+
+/// Recent messages of a public topic, for subscribers resuming after a given `seq`.
+#[derive(Default)]
+struct History {
+    messages: VecDeque<FeedMessage>,
+    /// `seq` of the newest message evicted so far; resuming from before it leaves a gap.
+    evicted_seq: u64,
+}
+
+/// Subscribers and history share one lock so a resuming subscriber is replayed exactly
+/// the messages it has not been sent live.
+#[derive(Default)]
+struct State {
+    subscriptions: HashMap<Topic, Subscribers>,
+    history: HashMap<Topic, History>,
+}
+
+struct Published {
+    topic: Topic,
+    message: FeedMessage,
+}
+
+/// Market-data fan-out.
+///
+/// `publish` and `publish_private` are the only calls made from the matching loop: they
+/// hand the message to a queue and return. Public messages go through a bounded queue and
+/// are dropped when it is full; private ones go through an unbounded queue, because a user
+/// cannot recover a lost order update from any snapshot. A dedicated thread drains both,
+/// private first, and delivers each message to the topic's subscribers. It is encoded at
+/// most once per [`Encoding`], on first use, and the frame is shared between all
+/// subscribers using that encoding.
+///
+/// A session with more than `max_pending` undelivered messages is lagging: its depth
+/// updates are merged into one pending update per topic, other public messages are dropped,
+/// and private messages are still sent. Once it catches up it is told which messages it
+/// missed ([`WsMessage::Dropped`]), then the merged updates are sent with the next message
+/// for it. A session lagging for longer than `max_lag` is disconnected.
+///
+/// The last `replay_capacity` messages of each public topic are kept so that subscribers
+/// can resume after the last `seq` they saw.
+#[derive(Clone)]
+pub struct Broadcaster {
+    state: Arc<Mutex<State>>,
+    publish_tx: mpsc::Sender<Published>,
+    private_tx: mpsc::UnboundedSender<Published>,
+    next_session_id: Arc<AtomicU64>,
+}
+
+impl Default for Broadcaster {
+    fn default() -> Self {
+        Self::new(&WsConfig::default())
+    }
+}
+
+impl Broadcaster {
+    /// Creates the broadcaster and starts its fan-out thread, which runs until every
+    /// clone of the broadcaster is dropped.
+    pub fn new(config: &WsConfig) -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+        let (publish_tx, publish_rx) = mpsc::channel(PUBLISH_QUEUE_CAPACITY);
+        let (private_tx, private_rx) = mpsc::unbounded_channel();
+
+        let fanout = Fanout {
+            state: state.clone(),
+            replay_capacity: config.replay_capacity,
+            max_pending: config.max_pending,
+            max_lag: config.max_lag,
+            lagging: HashMap::new(),
+        };
+        std::thread::Builder::new()
+            .name("md-fanout".to_string())
+            .spawn(move || fanout.run(publish_rx, private_rx))
+            .expect("failed to spawn market data fan-out thread");
+
+        Self {
+            state,
+            publish_tx,
+            private_tx,
+            next_session_id: Arc::new(AtomicU64::new(1)),
+        }
+    }
+
+    pub fn next_session_id(&self) -> SessionId {
+        self.next_session_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Subscribes a session. With `since`, the held messages with a later `seq` are sent
+    /// first; if some of them were already evicted the session gets a
+    /// [`WsMessage::ResumeGap`] before the replay.
+    pub fn subscribe(
+        &self,
+        topic: Topic,
+        session: SessionId,
+        handle: SessionHandle,
+        since: Option<u64>,
+    ) {
+        self.subscribe_all(std::slice::from_ref(&topic), session, handle, since);
+    }
+
+    /// Subscribes a session to several topics at once. The replay after `since` merges the
+    /// held messages of all of them in `seq` order, so topics sharing a sequence (the
+    /// public channels of a market) replay with increasing ids.
+    ///
+    /// The replay is copied under the lock but encoded and sent outside it, so a resuming
+    /// client does not hold up the fan-out. Messages published meanwhile are sent under
+    /// the lock just before the session is registered, which keeps the replay gapless and
+    /// ahead of live delivery.
+    pub fn subscribe_all(
+        &self,
+        topics: &[Topic],
+        session: SessionId,
+        mut handle: SessionHandle,
+        since: Option<u64>,
+    ) {
+        if let Some(since) = since {
+            let replay = held_after(&self.state.lock(), topics, since, &handle);
+            handle.skip_through = send_replay(&replay, &handle).unwrap_or(since);
+        }
+
+        let mut state = self.state.lock();
+        if since.is_some() {
+            let published = held_after(&state, topics, handle.skip_through, &handle);
+            if let Some(last_seq) = send_replay(&published, &handle) {
+                handle.skip_through = last_seq;
+            }
+        }
+
+        for topic in topics {
+            state
+                .subscriptions
+                .entry(topic.clone())
+                .or_default()
+                .insert(session, handle.clone());
+        }
+    }
+
+    pub fn unsubscribe(&self, topic: &Topic, session: SessionId) {
+        remove_subscriber(&mut self.state.lock().subscriptions, topic, session);
+    }
+
+    /// Drops every subscription of a session; called when it stops.
+    pub fn unsubscribe_all<'a>(
+        &self,
+        topics: impl IntoIterator<Item = &'a Topic>,
+        session: SessionId,
+    ) {
+        let mut state = self.state.lock();
+        for topic in topics {
+            remove_subscriber(&mut state.subscriptions, topic, session);
+        }
+    }
+
+    /// Queues a message for delivery. Never blocks: if the fan-out thread is that far
+    /// behind, the message is dropped and counted.
+    pub fn publish(&self, topic: Topic, message: FeedMessage) {
+        if let Err(TrySendError::Full(_)) = self.publish_tx.try_send(Published { topic, message }) {
+            MD_PUBLISH_DROPPED_TOTAL.inc();
+        }
+    }
+
+    /// Queues a message of a private topic. Never blocks and never drops.
+    pub fn publish_private(&self, topic: Topic, message: FeedMessage) {
+        debug_assert!(topic.user.is_some(), "not a private topic: {:?}", topic);
+        let _ = self.private_tx.send(Published { topic, message });
+    }
+}
+
+/// Copies the held messages of `topics` after `since`, in `seq` order. A topic that has
+/// already evicted some of them is reported to the session with a
+/// [`WsMessage::ResumeGap`].
+fn held_after(
+    state: &State,
+    topics: &[Topic],
+    since: u64,
+    handle: &SessionHandle,
+) -> Vec<FeedMessage> {
+    let mut held = Vec::new();
+    for topic in topics {
+        let Some(history) = state.history.get(topic) else {
+            continue;
+        };
+        if since < history.evicted_seq {
+            let oldest_seq = history.messages.front().map_or(0, |m| m.seq);
+            handle.recipient.do_send(WsMessage::ResumeGap {
+                topic: topic.clone(),
+                oldest_seq,
+            });
+        }
+        held.extend(history.messages.iter().filter(|m| m.seq > since).cloned());
+    }
+    held.sort_by_key(|m| m.seq);
+    held
+}
+
+/// Sends replayed messages to a session and returns the `seq` of the last one.
+fn send_replay(messages: &[FeedMessage], handle: &SessionHandle) -> Option<u64> {
+    for message in messages {
+        if let Some(frame) = message.encode(handle.encoding) {
+            handle.send(frame);
+        }
+    }
+    messages.last().map(|m| m.seq)
+}
+
+fn remove_subscriber(
+    subscriptions: &mut HashMap<Topic, Subscribers>,
+    topic: &Topic,
+    session: SessionId,
+) {
+    if let Some(clients) = subscriptions.get_mut(topic) {
+        clients.remove(&session);
+        if clients.is_empty() {
+            subscriptions.remove(topic);
+        }
+    }
+}
+
+/// A session over its pending limit, the depth updates held back from it and the `seq`
+/// range of the other public messages it was not sent, per topic.
+struct Lag {
+    since: Instant,
+    depth: HashMap<Topic, FeedMessage>,
+    dropped: HashMap<Topic, (u64, u64)>,
+}
+
+/// Folds a later depth update into a held-back one: its levels replace ours, its ids,
+/// checksum and sequence win.
+fn merge_depth(held: &mut FeedMessage, later: &FeedMessage) {
+    if let (FeedPayload::DepthUpdate(held_update), FeedPayload::DepthUpdate(later_update)) =
+        (&mut held.payload, &later.payload)
+    {
+        merge_levels(&mut held_update.bids, &later_update.bids);
+        merge_levels(&mut held_update.asks, &later_update.asks);
+        held_update.final_update_id = later_update.final_update_id;
+        held_update.checksum = later_update.checksum;
+        held.seq = later.seq;
+        held.timestamp = later.timestamp;
+    }
+}
+
+fn merge_levels(levels: &mut Vec<[u32; 2]>, later: &[[u32; 2]]) {
+    for &[price, quantity] in later {
+        match levels.iter_mut().find(|level| level[0] == price) {
+            Some(level) => level[1] = quantity,
+            None => levels.push([price, quantity]),
+        }
+    }
+}
+
+/// One published message and its frames, encoded lazily per [`Encoding`].
+struct Frames<'a> {
+    message: &'a FeedMessage,
+    encoded: [OnceCell<Option<WsMessage>>; Encoding::ALL.len()],
+}
+
+impl<'a> Frames<'a> {
+    fn new(message: &'a FeedMessage) -> Self {
+        Self {
+            message,
+            encoded: Default::default(),
+        }
+    }
+
+    fn get(&self, encoding: Encoding) -> Option<WsMessage> {
+        self.encoded[encoding.index()]
+            .get_or_init(|| self.message.encode(encoding))
+            .clone()
+    }
+}
+
+struct Fanout {
+    state: Arc<Mutex<State>>,
+    replay_capacity: usize,
+    max_pending: usize,
+    max_lag: Duration,
+    lagging: HashMap<SessionId, Lag>,
+}
+
+impl Fanout {
+    /// Delivers every published message to the live subscribers of its topic, pruning
+    /// recipients whose session has stopped without unsubscribing and disconnecting
+    /// sessions that lag for too long. Runs until both queues are closed.
+    fn run(
+        mut self,
+        mut publish_rx: mpsc::Receiver<Published>,
+        mut private_rx: mpsc::UnboundedReceiver<Published>,
+    ) {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .expect("failed to build market data fan-out runtime");
+
+        runtime.block_on(async {
+            loop {
+                let msg = tokio::select! {
+                    biased;
+                    Some(msg) = private_rx.recv() => msg,
+                    Some(msg) = publish_rx.recv() => msg,
+                    else => break,
+                };
+                self.dispatch(msg);
+            }
+        });
+    }
+
+    fn dispatch(&mut self, msg: Published) {
+        let state = self.state.clone();
+        let mut state = state.lock();
+        let mut dead = Vec::new();
+        let mut slow = Vec::new();
+
+        if let Some(clients) = state.subscriptions.get(&msg.topic) {
+            let now = Instant::now();
+            let frames = Frames::new(&msg.message);
+            for (&session, client) in clients {
+                if !client.recipient.connected() {
+                    dead.push(session);
+                } else if !self.deliver(session, client, &msg.topic, &frames, now) {
+                    slow.push((session, client.clone()));
+                }
+            }
+        }
+
+        for session in dead {
+            remove_subscriber(&mut state.subscriptions, &msg.topic, session);
+            self.lagging.remove(&session);
+        }
+
+        for (session, client) in slow {
+            state.subscriptions.retain(|_, clients| {
+                clients.remove(&session);
+                !clients.is_empty()
+            });
+            self.lagging.remove(&session);
+            client.outbound.disconnect.store(true, Ordering::Relaxed);
+            // Wakes the session even if it has nothing else queued.
+            client.recipient.do_send(WsMessage::Disconnect);
+            WS_SLOW_DISCONNECTS_TOTAL.inc();
+        }
+
+        if msg.topic.user.is_none() && self.replay_capacity > 0 {
+            let history = state.history.entry(msg.topic).or_default();
+            if history.messages.len() == self.replay_capacity
+                && let Some(evicted) = history.messages.pop_front()
+            {
+                history.evicted_seq = evicted.seq;
+            }
+            history.messages.push_back(msg.message);
+        }
+    }
+
+    /// Sends, conflates or drops a message for one session. Returns `false` if the session
+    /// has lagged for longer than `max_lag` and must be disconnected.
+    fn deliver(
+        &mut self,
+        session: SessionId,
+        client: &SessionHandle,
+        topic: &Topic,
+        frames: &Frames<'_>,
+        now: Instant,
+    ) -> bool {
+        if frames.message.seq <= client.skip_through {
+            return true;
+        }
+        let is_depth = topic.channel == Channel::Depth;
+
+        if client.outbound.pending.load(Ordering::Relaxed) < self.max_pending {
+            let Some(mut lag) = self.lagging.remove(&session) else {
+                if let Some(frame) = frames.get(client.encoding) {
+                    client.send(frame);
+                }
+                return true;
+            };
+
+            // Caught up: report what was dropped, then send what was held back, with this
+            // message merged in if it continues one of the held-back depth streams.
+            for (topic, (first_seq, last_seq)) in lag.dropped.drain() {
+                client.recipient.do_send(WsMessage::Dropped {
+                    topic,
+                    first_seq,
+                    last_seq,
+                });
+            }
+            let mut current = frames.get(client.encoding);
+            if is_depth && let Some(held) = lag.depth.get_mut(topic) {
+                merge_depth(held, frames.message);
+                current = None;
+            }
+            for (_, held) in lag.depth.drain() {
+                if let Some(frame) = held.encode(client.encoding) {
+                    client.send(frame);
+                }
+            }
+            if let Some(frame) = current {
+                client.send(frame);
+            }
+            return true;
+        }
+
+        let lag = self.lagging.entry(session).or_insert_with(|| Lag {
+            since: now,
+            depth: HashMap::new(),
+            dropped: HashMap::new(),
+        });
+        if now.duration_since(lag.since) > self.max_lag {
+            return false;
+        }
+
+        if topic.user.is_some() {
+            if let Some(frame) = frames.get(client.encoding) {
+                client.send(frame);
+            }
+        } else if is_depth {
+            match lag.depth.get_mut(topic) {
+                Some(held) => merge_depth(held, frames.message),
+                None => {
+                    lag.depth.insert(topic.clone(), frames.message.clone());
+                }
+            }
+            WS_DEPTH_CONFLATED_TOTAL.inc();
+        } else {
+            let seq = frames.message.seq;
+            lag.dropped
+                .entry(topic.clone())
+                .and_modify(|range| range.1 = seq)
+                .or_insert((seq, seq));
+            WS_MESSAGES_DROPPED_TOTAL.inc();
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outputs::{DepthUpdateMsg, TradeMsg};
+
+    /// Stands in for a session: keeps every frame and notice it is sent, and never drains
+    /// `pending`, so the test controls when it lags.
+    struct Collector(Arc<Mutex<Vec<WsMessage>>>);
+
+    impl Actor for Collector {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<WsMessage> for Collector {
+        type Result = ();
+
+        fn handle(&mut self, msg: WsMessage, _ctx: &mut Context<Self>) {
+            self.0.lock().push(msg);
+        }
+    }
+
+    fn depth(seq: u64, bids: &[[u32; 2]], asks: &[[u32; 2]]) -> FeedMessage {
+        FeedMessage {
+            market: "BTC-USDT".into(),
+            seq,
+            timestamp: seq as i64,
+            payload: FeedPayload::DepthUpdate(DepthUpdateMsg {
+                first_update_id: seq,
+                final_update_id: seq,
+                bids: bids.to_vec(),
+                asks: asks.to_vec(),
+                checksum: seq as u32,
+            }),
+        }
+    }
+
+    fn trade(seq: u64) -> FeedMessage {
+        FeedMessage {
+            market: "BTC-USDT".into(),
+            seq,
+            timestamp: seq as i64,
+            payload: FeedPayload::Trade(TradeMsg {
+                msg_type: 2,
+                price: 100,
+                quantity: 1,
+                maker_order_id: 1,
+                taker_order_id: 2,
+                timestamp: seq as i64,
+            }),
+        }
+    }
+
+    fn json(msg: &WsMessage) -> serde_json::Value {
+        match msg {
+            WsMessage::Text(text) => serde_json::from_str(text).unwrap(),
+            _ => panic!("expected a text frame"),
+        }
+    }
+
+    #[test]
+    fn merged_depth_keeps_the_first_id_and_the_latest_levels() {
+        let mut held = depth(3, &[[100, 5], [99, 1]], &[[101, 2]]);
+        merge_depth(&mut held, &depth(4, &[[100, 0], [98, 7]], &[]));
+        merge_depth(&mut held, &depth(6, &[[99, 3]], &[[101, 4]]));
+
+        let FeedPayload::DepthUpdate(update) = &held.payload else {
+            unreachable!()
+        };
+        assert_eq!((update.first_update_id, update.final_update_id), (3, 6));
+        assert_eq!(update.bids, [[100, 0], [99, 3], [98, 7]]);
+        assert_eq!(update.asks, [[101, 4]]);
+        assert_eq!((update.checksum, held.seq, held.timestamp), (6, 6, 6));
+    }
+
+    #[actix_web::test]
+    async fn lagging_session_gets_merged_depth_and_a_dropped_notice() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let handle = SessionHandle {
+            recipient: Collector(received.clone()).start().recipient(),
+            outbound: Arc::new(Outbound::default()),
+            encoding: Encoding::Json,
+            skip_through: 0,
+        };
+        let mut fanout = Fanout {
+            state: Arc::default(),
+            replay_capacity: 0,
+            max_pending: 1,
+            max_lag: Duration::from_secs(60),
+            lagging: HashMap::new(),
+        };
+        let depth_topic = Topic::new(Channel::Depth, "BTC-USDT");
+        let trades_topic = Topic::new(Channel::Trades, "BTC-USDT");
+        let mut deliver = |topic: &Topic, message: FeedMessage| {
+            let frames = Frames::new(&message);
+            assert!(fanout.deliver(1, &handle, topic, &frames, Instant::now()));
+        };
+
+        deliver(&depth_topic, depth(1, &[[100, 1]], &[]));
+        // One message pending: the session now lags.
+        deliver(&depth_topic, depth(2, &[[100, 2]], &[]));
+        deliver(&trades_topic, trade(3));
+        deliver(&depth_topic, depth(4, &[[99, 1]], &[]));
+        deliver(&trades_topic, trade(5));
+
+        handle.outbound.pending.store(0, Ordering::Relaxed);
+        deliver(&trades_topic, trade(6));
+
+        actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+        let received = received.lock();
+        assert_eq!(received.len(), 4);
+        assert_eq!(json(&received[0])["seq"], 1);
+        match &received[1] {
+            WsMessage::Dropped {
+                topic,
+                first_seq,
+                last_seq,
+            } => assert_eq!((topic, *first_seq, *last_seq), (&trades_topic, 3, 5)),
+            _ => panic!("expected a dropped notice"),
+        }
+        let merged = json(&received[2]);
+        assert_eq!(merged["type"], "depth_update");
+        assert_eq!(
+            (&merged["first_update_id"], &merged["final_update_id"]),
+            (&2.into(), &4.into())
+        );
+        assert_eq!(merged["bids"], serde_json::json!([[100, 2], [99, 1]]));
+        assert_eq!(json(&received[3])["seq"], 6);
+    }
+}
@@ -0,0 +1,157 @@
+use crate::envelope::{self, MessageType};
+use crate::outputs::{
+    BboMsg, CandleMsg, DepthUpdateMsg, L3UpdateMsg, OrderUpdateMsg, TickerMsg, TradeMsg,
+};
+use crate::worker::ws::WsMessage;
+use bytes::Bytes;
+use bytestring::ByteString;
+use serde::Serialize;
+use std::sync::Arc;
+
+/// Wire format of the market-data and order-update frames a session receives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    /// Text frames: `{"type": "trade", "market": ..., "seq": ..., ...}`.
+    Json,
+    /// Binary frames carrying the same map as the JSON form.
+    MsgPack,
+    /// Binary frames carrying a versioned wincode envelope (see `crate::envelope`).
+    Wincode,
+    /// Server-Sent Events: the JSON form as `data`, with `seq` as the event id and `type`
+    /// as the event name. Only used by `/stream`.
+    Sse,
+}
+
+impl Encoding {
+    pub const ALL: [Encoding; 4] = [
+        Encoding::Json,
+        Encoding::MsgPack,
+        Encoding::Wincode,
+        Encoding::Sse,
+    ];
+    /// Accepted `Sec-WebSocket-Protocol` values, in the order of [`Encoding::ALL`].
+    pub const SUBPROTOCOLS: [&'static str; 3] = ["clob.json", "clob.msgpack", "clob.wincode"];
+
+    /// Parses a `format` query parameter value of `/ws`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(Encoding::Json),
+            "msgpack" => Some(Encoding::MsgPack),
+            "wincode" => Some(Encoding::Wincode),
+            _ => None,
+        }
+    }
+
+    pub fn from_subprotocol(protocol: &str) -> Option<Self> {
+        Self::SUBPROTOCOLS
+            .iter()
+            .position(|p| *p == protocol)
+            .map(|i| Self::ALL[i])
+    }
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum FeedPayload {
+    Trade(TradeMsg),
+    DepthUpdate(DepthUpdateMsg),
+    OrderUpdate(OrderUpdateMsg),
+    Bbo(BboMsg),
+    Ticker(TickerMsg),
+    Candle(CandleMsg),
+    L3Update(L3UpdateMsg),
+}
+
+/// A message published by the engine, encoded by the fan-out for each format in use.
+#[derive(Debug, Clone)]
+pub struct FeedMessage {
+    pub market: Arc<str>,
+    pub seq: u64,
+    pub timestamp: i64,
+    pub payload: FeedPayload,
+}
+
+#[derive(Serialize)]
+struct Tagged<'a, T> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    market: &'a str,
+    seq: u64,
+    #[serde(flatten)]
+    data: &'a T,
+}
+
+impl FeedMessage {
+    pub fn encode(&self, encoding: Encoding) -> Option<WsMessage> {
+        match &self.payload {
+            FeedPayload::Trade(trade) => {
+                self.encode_as(encoding, "trade", MessageType::Trade, trade)
+            }
+            FeedPayload::DepthUpdate(update) => {
+                self.encode_as(encoding, "depth_update", MessageType::DepthUpdate, update)
+            }
+            FeedPayload::OrderUpdate(update) => {
+                self.encode_as(encoding, "order_update", MessageType::OrderUpdate, update)
+            }
+            FeedPayload::Bbo(bbo) => self.encode_as(encoding, "bbo", MessageType::Bbo, bbo),
+            FeedPayload::Ticker(ticker) => {
+                self.encode_as(encoding, "ticker", MessageType::Ticker, ticker)
+            }
+            FeedPayload::Candle(candle) => {
+                self.encode_as(encoding, "candle", MessageType::Candle, candle)
+            }
+            FeedPayload::L3Update(update) => {
+                self.encode_as(encoding, "l3_update", MessageType::L3Update, update)
+            }
+        }
+    }
+
+    fn encode_as<T>(
+        &self,
+        encoding: Encoding,
+        kind: &'static str,
+        msg_type: MessageType,
+        data: &T,
+    ) -> Option<WsMessage>
+    where
+        T: Serialize + wincode::SchemaWrite<Src = T>,
+    {
+        let tagged = Tagged {
+            kind,
+            market: &self.market,
+            seq: self.seq,
+            data,
+        };
+
+        let encoded = match encoding {
+            Encoding::Json => serde_json::to_string(&tagged)
+                .map(|json| WsMessage::Text(ByteString::from(json)))
+                .map_err(|e| e.to_string()),
+            Encoding::MsgPack => rmp_serde::to_vec_named(&tagged)
+                .map(|bytes| WsMessage::Binary(Bytes::from(bytes)))
+                .map_err(|e| e.to_string()),
+            Encoding::Sse => serde_json::to_string(&tagged)
+                .map(|json| {
+                    let event = format!("id: {}\nevent: {}\ndata: {}\n\n", self.seq, kind, json);
+                    WsMessage::Text(ByteString::from(event))
+                })
+                .map_err(|e| e.to_string()),
+            Encoding::Wincode => {
+                envelope::encode(msg_type, &self.market, self.seq, self.timestamp, data)
+                    .map(|bytes| WsMessage::Binary(Bytes::from(bytes)))
+                    .map_err(|e| e.to_string())
+            }
+        };
+
+        match encoded {
+            Ok(frame) => Some(frame),
+            Err(e) => {
+                eprintln!("[WS] Failed to encode {} as {:?}: {}", kind, encoding, e);
+                None
+            }
+        }
+    }
+}
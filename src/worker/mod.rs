@@ -1,2 +1,14 @@
+pub mod broadcaster;
+pub use broadcaster::*;
+
+pub mod feed;
+pub use feed::*;
+
+pub mod protocol;
+pub use protocol::*;
+
+pub mod sse;
+pub use sse::*;
+
 pub mod ws;
 pub use ws::*;
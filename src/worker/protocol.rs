@@ -0,0 +1,100 @@
+use crate::inputs::Side;
+use crate::outputs::AckStatus;
+use crate::worker::broadcaster::Channel;
+use serde::{Deserialize, Serialize};
+
+/// Control messages a client may send on `/ws`, as JSON text frames or MessagePack binary
+/// frames. Replies use the encoding of the request.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// With `since`, first replays the held messages with a later `seq`.
+    Subscribe {
+        channel: Channel,
+        market: String,
+        #[serde(default)]
+        since: Option<u64>,
+        /// Trades channel only: start with up to this many recent trades.
+        #[serde(default)]
+        recent: Option<usize>,
+    },
+    Unsubscribe {
+        channel: Channel,
+        market: String,
+    },
+    NewOrder {
+        req_id: String,
+        market: String,
+        side: Side,
+        price: u32,
+        quantity: u32,
+    },
+    CancelOrder {
+        req_id: String,
+        market: String,
+        order_id: u32,
+    },
+    AmendOrder {
+        req_id: String,
+        market: String,
+        order_id: u32,
+        price: u32,
+        quantity: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlEncoding {
+    Json,
+    MsgPack,
+}
+
+/// Control replies sent by the server.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage<'a> {
+    Subscribed {
+        channel: Channel,
+        market: &'a str,
+    },
+    Unsubscribed {
+        channel: Channel,
+        market: &'a str,
+    },
+    Error {
+        message: String,
+    },
+    /// A resumed subscription is missing messages: the oldest one still held is
+    /// `oldest_seq`. Resync from a snapshot.
+    ResumeGap {
+        channel: Channel,
+        market: &'a str,
+        oldest_seq: u64,
+    },
+    /// A lagging session was not sent this channel's messages from `first_seq` to
+    /// `last_seq`. Resubscribe with `since` before `first_seq` to replay them.
+    MessagesDropped {
+        channel: Channel,
+        market: &'a str,
+        first_seq: u64,
+        last_seq: u64,
+    },
+    /// Sent once the engine has processed the request: `Accepted` means it was taken, and
+    /// fills and cancels then arrive on the `orders` channel.
+    OrderAck {
+        req_id: &'a str,
+        order_id: u32,
+        status: AckStatus,
+        reason: String,
+    },
+}
+
+impl ServerMessage<'_> {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("server messages always serialize")
+    }
+
+    pub fn to_msgpack(&self) -> Vec<u8> {
+        rmp_serde::to_vec_named(self).expect("server messages always serialize")
+    }
+}
@@ -0,0 +1,196 @@
+use crate::config::WsConfig;
+use crate::market_data::SharedMarketData;
+use crate::metrics::SSE_CONNECTED_CLIENTS;
+use crate::worker::broadcaster::{Broadcaster, Channel, Outbound, SessionHandle, SessionId, Topic};
+use crate::worker::feed::Encoding;
+use crate::worker::protocol::ServerMessage;
+use crate::worker::ws::WsMessage;
+use actix::prelude::*;
+use actix_web::{HttpRequest, HttpResponse, web};
+use bytes::Bytes;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use tokio::sync::mpsc;
+
+const DEFAULT_CHANNELS: [Channel; 3] = [Channel::Trades, Channel::Depth, Channel::Ticker];
+
+struct Chunk {
+    data: Bytes,
+    /// Published messages count towards [`Outbound::pending`]; keepalives do not.
+    counted: bool,
+}
+
+/// One `/stream` client. Subscribes like a WebSocket session and relays its frames, already
+/// encoded as SSE events, into the response body.
+pub struct SseSession {
+    id: SessionId,
+    broadcaster: Broadcaster,
+    topics: Vec<Topic>,
+    last_event_id: Option<u64>,
+    outbound: Arc<Outbound>,
+    body: mpsc::UnboundedSender<Chunk>,
+    config: WsConfig,
+}
+
+impl SseSession {
+    /// Writes to the response body; a closed body means the client went away.
+    fn push(&self, data: Bytes, counted: bool, ctx: &mut Context<Self>) {
+        if self.body.send(Chunk { data, counted }).is_err() {
+            ctx.stop();
+        }
+    }
+}
+
+impl Actor for SseSession {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        SSE_CONNECTED_CLIENTS.inc();
+
+        let handle = SessionHandle {
+            recipient: ctx.address().recipient(),
+            outbound: self.outbound.clone(),
+            encoding: Encoding::Sse,
+            skip_through: 0,
+        };
+        self.broadcaster
+            .subscribe_all(&self.topics, self.id, handle, self.last_event_id);
+
+        // Comment lines keep proxies from timing the stream out and reveal dead clients.
+        ctx.run_interval(self.config.heartbeat_interval, |act, ctx| {
+            act.push(Bytes::from_static(b": keepalive\n\n"), false, ctx);
+        });
+        if let Some(max_age) = self.config.max_connection_age {
+            ctx.run_later(max_age, |_, ctx| ctx.stop());
+        }
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        self.broadcaster.unsubscribe_all(&self.topics, self.id);
+        SSE_CONNECTED_CLIENTS.dec();
+    }
+}
+
+impl Handler<WsMessage> for SseSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: WsMessage, ctx: &mut Context<Self>) {
+        if self.outbound.disconnect.load(Ordering::Relaxed) {
+            ctx.stop();
+            return;
+        }
+
+        match msg {
+            WsMessage::Text(event) => self.push(event.into_bytes(), true, ctx),
+            WsMessage::Binary(data) => self.push(data, true, ctx),
+            WsMessage::Disconnect => ctx.stop(),
+            WsMessage::ResumeGap { topic, oldest_seq } => {
+                let gap = ServerMessage::ResumeGap {
+                    channel: topic.channel,
+                    market: &topic.market,
+                    oldest_seq,
+                };
+                let event = format!("event: resume_gap\ndata: {}\n\n", gap.to_json());
+                self.push(Bytes::from(event), false, ctx);
+            }
+            WsMessage::Dropped {
+                topic,
+                first_seq,
+                last_seq,
+            } => {
+                let dropped = ServerMessage::MessagesDropped {
+                    channel: topic.channel,
+                    market: &topic.market,
+                    first_seq,
+                    last_seq,
+                };
+                let event = format!("event: messages_dropped\ndata: {}\n\n", dropped.to_json());
+                self.push(Bytes::from(event), false, ctx);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StreamQuery {
+    pub market: String,
+    /// Comma-separated public channels; defaults to `trades,depth,ticker`.
+    pub channels: Option<String>,
+    /// Used when the `Last-Event-ID` header is absent, e.g. on the first connection.
+    pub last_event_id: Option<u64>,
+}
+
+/// `GET /stream?market=...`: public market data as Server-Sent Events. Event ids are the
+/// market's `seq`, shared by its public channels, so a reconnecting client resumes all of
+/// them from its `Last-Event-ID`.
+pub async fn sse_index(
+    req: HttpRequest,
+    query: web::Query<StreamQuery>,
+    broadcaster: web::Data<Broadcaster>,
+    market_data: web::Data<SharedMarketData>,
+    config: web::Data<WsConfig>,
+) -> HttpResponse {
+    if query.market != *market_data.market {
+        return HttpResponse::BadRequest().body(format!("unknown market '{}'", query.market));
+    }
+
+    let channels = match &query.channels {
+        None => DEFAULT_CHANNELS.to_vec(),
+        Some(names) => {
+            let mut channels = Vec::new();
+            for name in names.split(',').map(str::trim) {
+                match Channel::from_name(name) {
+                    Some(channel) if !channel.is_private() => channels.push(channel),
+                    _ => {
+                        return HttpResponse::BadRequest()
+                            .body(format!("unknown public channel '{}'", name));
+                    }
+                }
+            }
+            channels
+        }
+    };
+
+    let last_event_id = req
+        .headers()
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok())
+        .or(query.last_event_id);
+
+    let outbound = Arc::new(Outbound::default());
+    let (body, chunks) = mpsc::unbounded_channel::<Chunk>();
+
+    SseSession {
+        id: broadcaster.next_session_id(),
+        broadcaster: broadcaster.get_ref().clone(),
+        topics: channels
+            .into_iter()
+            .map(|channel| Topic::new(channel, query.market.as_str()))
+            .collect(),
+        last_event_id,
+        outbound: outbound.clone(),
+        body,
+        config: config.get_ref().clone(),
+    }
+    .start();
+
+    // A chunk only stops counting as pending once the server actually writes it out.
+    let stream = futures::stream::unfold(chunks, move |mut chunks| {
+        let outbound = outbound.clone();
+        async move {
+            let chunk = chunks.recv().await?;
+            if chunk.counted {
+                outbound.pending.fetch_sub(1, Ordering::Relaxed);
+            }
+            Some((Ok::<_, actix_web::Error>(chunk.data), chunks))
+        }
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(stream)
+}
@@ -1,50 +1,404 @@
+use crate::ORDER_ID_COUNTER;
+use crate::auth::{ApiKeys, Auth};
+use crate::config::WsConfig;
+use crate::events::{OrderEvent, OrderRequest, Verdict};
+use crate::market_data::SharedMarketData;
+use crate::metrics::WS_CONNECTED_CLIENTS;
+use crate::outputs::AckStatus;
+use crate::routes::OrderSender;
+use crate::worker::broadcaster::{Broadcaster, Channel, Outbound, SessionHandle, SessionId, Topic};
+use crate::worker::feed::{Encoding, FeedMessage, FeedPayload};
+use crate::worker::protocol::{ClientMessage, ControlEncoding, ServerMessage};
 use actix::prelude::*;
 use actix_web_actors::ws;
-use std::sync::{Arc, Mutex};
+use bytes::Bytes;
+use bytestring::ByteString;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use tokio::sync::oneshot;
 
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub enum WsMessage {
+    Text(ByteString),
+    Binary(Bytes),
+    /// The session fell too far behind; see [`Outbound::disconnect`].
+    Disconnect,
+    /// Sent ahead of a replay that could not start at the requested `seq`.
+    ResumeGap {
+        topic: Topic,
+        oldest_seq: u64,
+    },
+    /// Messages of `topic` from `first_seq` to `last_seq` were not sent while the session
+    /// lagged.
+    Dropped {
+        topic: Topic,
+        first_seq: u64,
+        last_seq: u64,
+    },
+}
+
+/// Where WebSocket sessions send orders: the same queue as `POST /order`.
 #[derive(Clone)]
-pub struct Broadcaster {
-    clients: Arc<Mutex<Vec<Recipient<WsMessage>>>>,
+pub struct OrderEntry {
+    pub sender: OrderSender,
+    pub market: String,
+}
+
+pub struct WsSession {
+    pub id: SessionId,
+    pub broadcaster: Broadcaster,
+    pub subscriptions: HashSet<Topic>,
+    /// Set when the connection presented a valid API key; required for private channels
+    /// and order entry.
+    pub user_id: Option<u32>,
+    pub order_entry: OrderEntry,
+    pub market_data: SharedMarketData,
+    pub outbound: Arc<Outbound>,
+    /// Format of published frames, chosen at connect time.
+    pub encoding: Encoding,
+    pub config: WsConfig,
+    /// Last time anything arrived from the client.
+    pub last_seen: Instant,
 }
 
-impl Broadcaster {
-    pub fn new() -> Self {
+impl WsSession {
+    pub fn new(
+        broadcaster: Broadcaster,
+        user_id: Option<u32>,
+        order_entry: OrderEntry,
+        market_data: SharedMarketData,
+        encoding: Encoding,
+        config: WsConfig,
+    ) -> Self {
         Self {
-            clients: Arc::new(Mutex::new(Vec::new())),
+            id: broadcaster.next_session_id(),
+            broadcaster,
+            subscriptions: HashSet::new(),
+            user_id,
+            order_entry,
+            market_data,
+            outbound: Arc::new(Outbound::default()),
+            encoding,
+            config,
+            last_seen: Instant::now(),
+        }
+    }
+
+    fn close(ctx: &mut ws::WebsocketContext<Self>, code: ws::CloseCode, description: &str) {
+        ctx.close(Some(ws::CloseReason {
+            code,
+            description: Some(description.to_string()),
+        }));
+        ctx.stop();
+    }
+
+    /// Pings the client every `heartbeat_interval` and closes the session once nothing has
+    /// arrived from it for `client_timeout`.
+    fn start_heartbeat(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        ctx.run_interval(self.config.heartbeat_interval, |act, ctx| {
+            if act.last_seen.elapsed() > act.config.client_timeout {
+                Self::close(ctx, ws::CloseCode::Away, "heartbeat timeout");
+                return;
+            }
+            ctx.ping(b"");
+        });
+    }
+
+    /// Private channels resolve to the session's own user; there is no way to name another.
+    fn topic(&self, channel: Channel, market: &str) -> Result<Topic, String> {
+        if market != &*self.market_data.market {
+            return Err(format!("unknown market '{}'", market));
+        }
+        if !channel.is_private() {
+            return Ok(Topic::new(channel, market));
+        }
+        match self.user_id {
+            Some(user_id) => Ok(Topic::private(channel, market, user_id)),
+            None => Err("private channels require an API key".to_string()),
         }
     }
-    pub fn broadcast(&self, msg: &str) {
-        let clients = self.clients.lock().unwrap();
-        for client in clients.iter() {
-            let _ = client.do_send(WsMessage::Text(msg.to_owned()));
+
+    /// The topic to subscribe to. `since` resumes against the market's public `seq`, which
+    /// private updates do not carry, so it is refused on private channels.
+    fn subscription(
+        &self,
+        channel: Channel,
+        market: &str,
+        since: Option<u64>,
+    ) -> Result<Topic, String> {
+        if channel.is_private() && since.is_some() {
+            return Err("since is not supported on private channels".to_string());
+        }
+        self.topic(channel, market)
+    }
+
+    /// Encoding of server-initiated control messages: MessagePack for MessagePack sessions,
+    /// JSON otherwise.
+    fn control_encoding(&self) -> ControlEncoding {
+        match self.encoding {
+            Encoding::MsgPack => ControlEncoding::MsgPack,
+            _ => ControlEncoding::Json,
         }
     }
 
-    pub fn broadcast_bytes(&self, data: &[u8]) {
-        let clients = self.clients.lock().unwrap();
-        for client in clients.iter() {
-            let _ = client.do_send(WsMessage::Binary(data.to_vec()));
+    fn reply(
+        ctx: &mut ws::WebsocketContext<Self>,
+        encoding: ControlEncoding,
+        msg: &ServerMessage<'_>,
+    ) {
+        match encoding {
+            ControlEncoding::Json => ctx.text(msg.to_json()),
+            ControlEncoding::MsgPack => ctx.binary(msg.to_msgpack()),
+        }
+    }
+
+    /// Writes up to `limit` recent trades of the topic's market straight to the client and
+    /// returns the `seq` of the last one, from which the live subscription resumes.
+    fn send_recent_trades(
+        &self,
+        topic: &Topic,
+        limit: usize,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) -> Option<u64> {
+        let trades = self.market_data.trades.read().recent_trades.latest(limit);
+
+        let mut last_seq = None;
+        for recent in trades {
+            last_seq = Some(recent.seq);
+            let message = FeedMessage {
+                market: topic.market.clone(),
+                seq: recent.seq,
+                timestamp: recent.trade.timestamp,
+                payload: FeedPayload::Trade(recent.trade),
+            };
+            match message.encode(self.encoding) {
+                Some(WsMessage::Text(text)) => ctx.text(text),
+                Some(WsMessage::Binary(bytes)) => ctx.binary(bytes),
+                _ => {}
+            }
+        }
+        last_seq
+    }
+
+    fn handle_client_message(
+        &mut self,
+        msg: ClientMessage,
+        encoding: ControlEncoding,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        match msg {
+            ClientMessage::Subscribe {
+                channel,
+                market,
+                since,
+                recent,
+            } => {
+                let topic = match self.subscription(channel, &market, since) {
+                    Ok(topic) => topic,
+                    Err(message) => {
+                        Self::reply(ctx, encoding, &ServerMessage::Error { message });
+                        return;
+                    }
+                };
+                let reply = ServerMessage::Subscribed {
+                    channel,
+                    market: &market,
+                };
+                Self::reply(ctx, encoding, &reply);
+                if self.subscriptions.insert(topic.clone()) {
+                    let since = match (since, recent) {
+                        (None, Some(limit)) if channel == Channel::Trades => {
+                            self.send_recent_trades(&topic, limit, ctx)
+                        }
+                        _ => since,
+                    };
+                    let handle = SessionHandle {
+                        recipient: ctx.address().recipient(),
+                        outbound: self.outbound.clone(),
+                        encoding: self.encoding,
+                        skip_through: 0,
+                    };
+                    self.broadcaster.subscribe(topic, self.id, handle, since);
+                }
+            }
+            ClientMessage::Unsubscribe { channel, market } => {
+                if let Ok(topic) = self.topic(channel, &market)
+                    && self.subscriptions.remove(&topic)
+                {
+                    self.broadcaster.unsubscribe(&topic, self.id);
+                }
+                let reply = ServerMessage::Unsubscribed {
+                    channel,
+                    market: &market,
+                };
+                Self::reply(ctx, encoding, &reply);
+            }
+            order => {
+                if let Some(ack) = self.order_entry.handle(self.user_id, &order) {
+                    ctx.spawn(ack.settle().into_actor(self).map(move |ack, _, ctx| {
+                        Self::reply(ctx, encoding, &ack.message());
+                    }));
+                }
+            }
         }
     }
 }
 
-#[derive(Message)]
-#[rtype(result = "()")]
-pub enum WsMessage {
-    Text(String),
-    Binary(Vec<u8>),
+/// The ack of an order-entry message, which for a queued event waits on the engine.
+pub struct PendingAck {
+    req_id: String,
+    order_id: u32,
+    status: AckStatus,
+    reason: String,
+    verdict: Option<oneshot::Receiver<Verdict>>,
 }
 
-pub struct WsSession {
-    pub broadcaster: Broadcaster,
+impl PendingAck {
+    /// Waits for the engine's verdict, if the event was queued at all.
+    pub async fn settle(mut self) -> Self {
+        if let Some(verdict) = self.verdict.take() {
+            match verdict.await {
+                Ok(None) => self.status = AckStatus::Accepted,
+                Ok(Some(reason)) => self.reason = reason.to_string(),
+                Err(_) => self.reason = "order processing unavailable".to_string(),
+            }
+        }
+        self
+    }
+
+    pub fn message(&self) -> ServerMessage<'_> {
+        ServerMessage::OrderAck {
+            req_id: &self.req_id,
+            order_id: self.order_id,
+            status: self.status,
+            reason: self.reason.clone(),
+        }
+    }
+}
+
+impl OrderEntry {
+    /// Queues an order-entry message from a session authenticated as `user_id` and returns
+    /// its ack; `None` for messages that are not order entry.
+    pub fn handle(&self, user_id: Option<u32>, msg: &ClientMessage) -> Option<PendingAck> {
+        let ack = match msg {
+            ClientMessage::NewOrder {
+                req_id,
+                market,
+                side,
+                price,
+                quantity,
+            } => self.submit(user_id, req_id, market, |user_id| {
+                if *price == 0 || *quantity == 0 {
+                    return Err("price and quantity must be positive");
+                }
+                let order_id = ORDER_ID_COUNTER.fetch_add(1, Ordering::SeqCst);
+                let event = OrderEvent::NewOrder {
+                    order_id,
+                    user_id,
+                    price: *price,
+                    quantity: *quantity,
+                    side: *side,
+                };
+                Ok((event, order_id))
+            }),
+            ClientMessage::CancelOrder {
+                req_id,
+                market,
+                order_id,
+            } => self.submit(user_id, req_id, market, |user_id| {
+                let event = OrderEvent::CancelOrder {
+                    order_id: *order_id,
+                    user_id,
+                };
+                Ok((event, *order_id))
+            }),
+            ClientMessage::AmendOrder {
+                req_id,
+                market,
+                order_id,
+                price,
+                quantity,
+            } => self.submit(user_id, req_id, market, |user_id| {
+                if *price == 0 || *quantity == 0 {
+                    return Err("price and quantity must be positive");
+                }
+                let event = OrderEvent::AmendOrder {
+                    order_id: *order_id,
+                    user_id,
+                    price: *price,
+                    quantity: *quantity,
+                };
+                Ok((event, *order_id))
+            }),
+            ClientMessage::Subscribe { .. } | ClientMessage::Unsubscribe { .. } => return None,
+        };
+        Some(ack)
+    }
+
+    /// Queues the event built by `request` for `user_id`; the ack carries the engine's
+    /// verdict, so a cancel or amend of someone else's or an unknown order is rejected.
+    fn submit(
+        &self,
+        user_id: Option<u32>,
+        req_id: &str,
+        market: &str,
+        request: impl FnOnce(u32) -> Result<(OrderEvent, u32), &'static str>,
+    ) -> PendingAck {
+        let reject = |reason: &str| PendingAck {
+            req_id: req_id.to_string(),
+            order_id: 0,
+            status: AckStatus::Rejected,
+            reason: reason.to_string(),
+            verdict: None,
+        };
+
+        let Some(user_id) = user_id else {
+            return reject("order entry requires an API key");
+        };
+        if market != self.market {
+            return reject(&format!("unknown market '{}'", market));
+        }
+
+        let (event, order_id) = match request(user_id) {
+            Ok(queued) => queued,
+            Err(reason) => return reject(reason),
+        };
+        let (request, verdict) = OrderRequest::with_reply(event);
+        if self.sender.send(request).is_err() {
+            return reject("order processing unavailable");
+        }
+
+        PendingAck {
+            req_id: req_id.to_string(),
+            order_id,
+            status: AckStatus::Rejected,
+            reason: String::new(),
+            verdict: Some(verdict),
+        }
+    }
 }
 
 impl Actor for WsSession {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        let addr = ctx.address().recipient();
-        self.broadcaster.clients.lock().unwrap().push(addr);
+        WS_CONNECTED_CLIENTS.inc();
+        self.start_heartbeat(ctx);
+        if let Some(max_age) = self.config.max_connection_age {
+            ctx.run_later(max_age, |_, ctx| {
+                Self::close(ctx, ws::CloseCode::Restart, "max connection age reached");
+            });
+        }
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        self.broadcaster
+            .unsubscribe_all(&self.subscriptions, self.id);
+        self.subscriptions.clear();
+        WS_CONNECTED_CLIENTS.dec();
     }
 }
 
@@ -54,10 +408,50 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsSession {
         msg: Result<ws::Message, ws::ProtocolError>,
         ctx: &mut ws::WebsocketContext<Self>,
     ) {
-        if let Ok(ws::Message::Text(text)) = msg {
-            if text == "ping" {
-                ctx.text("pong");
+        let Ok(msg) = msg else {
+            ctx.stop();
+            return;
+        };
+        self.last_seen = Instant::now();
+
+        match msg {
+            ws::Message::Ping(payload) => ctx.pong(&payload),
+            ws::Message::Pong(_) => {}
+            ws::Message::Close(reason) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            ws::Message::Text(text) if text == "ping" => ctx.text("pong"),
+            ws::Message::Text(text) => match serde_json::from_str::<ClientMessage>(&text) {
+                Ok(msg) => self.handle_client_message(msg, ControlEncoding::Json, ctx),
+                Err(e) => {
+                    let message = format!("invalid message: {}", e);
+                    Self::reply(
+                        ctx,
+                        ControlEncoding::Json,
+                        &ServerMessage::Error { message },
+                    );
+                }
+            },
+            ws::Message::Binary(data) => match rmp_serde::from_slice::<ClientMessage>(&data) {
+                Ok(msg) => self.handle_client_message(msg, ControlEncoding::MsgPack, ctx),
+                Err(e) => {
+                    let message = format!("invalid message: {}", e);
+                    Self::reply(
+                        ctx,
+                        ControlEncoding::MsgPack,
+                        &ServerMessage::Error { message },
+                    );
+                }
+            },
+            ws::Message::Continuation(_) => {
+                Self::close(
+                    ctx,
+                    ws::CloseCode::Unsupported,
+                    "fragmented messages are not supported",
+                );
             }
+            ws::Message::Nop => {}
         }
     }
 }
@@ -66,20 +460,170 @@ impl Handler<WsMessage> for WsSession {
     type Result = ();
 
     fn handle(&mut self, msg: WsMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        if let WsMessage::Text(_) | WsMessage::Binary(_) = msg {
+            self.outbound.pending.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        // Checked on every message so the backlog is discarded rather than written out.
+        if self.outbound.disconnect.load(Ordering::Relaxed) {
+            Self::close(ctx, ws::CloseCode::Policy, "slow consumer");
+            return;
+        }
+
         match msg {
             WsMessage::Text(text) => ctx.text(text),
             WsMessage::Binary(data) => ctx.binary(data),
+            WsMessage::Disconnect => {}
+            WsMessage::ResumeGap { topic, oldest_seq } => {
+                let gap = ServerMessage::ResumeGap {
+                    channel: topic.channel,
+                    market: &topic.market,
+                    oldest_seq,
+                };
+                Self::reply(ctx, self.control_encoding(), &gap);
+            }
+            WsMessage::Dropped {
+                topic,
+                first_seq,
+                last_seq,
+            } => {
+                let dropped = ServerMessage::MessagesDropped {
+                    channel: topic.channel,
+                    market: &topic.market,
+                    first_seq,
+                    last_seq,
+                };
+                Self::reply(ctx, self.control_encoding(), &dropped);
+            }
         }
     }
 }
 
+/// Picks the frame encoding from `?format=json|msgpack|wincode`, else from the first
+/// supported `Sec-WebSocket-Protocol` the client offered, else JSON. Binary formats are
+/// opt-in so that a plain WebSocket client can read the feed.
+fn negotiate_encoding(req: &actix_web::HttpRequest) -> Result<Encoding, String> {
+    let query = actix_web::web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .map_err(|e| e.to_string())?;
+    if let Some(format) = query.get("format") {
+        return Encoding::from_name(format).ok_or_else(|| format!("unknown format '{}'", format));
+    }
+
+    let offered = req
+        .headers()
+        .get("sec-websocket-protocol")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    Ok(offered
+        .split(',')
+        .find_map(|p| Encoding::from_subprotocol(p.trim()))
+        .unwrap_or(Encoding::Json))
+}
+
 pub async fn ws_index(
     req: actix_web::HttpRequest,
     stream: actix_web::web::Payload,
     broadcaster: actix_web::web::Data<Broadcaster>,
+    api_keys: actix_web::web::Data<ApiKeys>,
+    order_entry: actix_web::web::Data<OrderEntry>,
+    market_data: actix_web::web::Data<SharedMarketData>,
+    config: actix_web::web::Data<WsConfig>,
 ) -> actix_web::Result<actix_web::HttpResponse> {
-    let session = WsSession {
-        broadcaster: broadcaster.get_ref().clone(),
+    let user_id = match api_keys.authenticate(&req) {
+        Auth::Anonymous => None,
+        Auth::User(user_id) => Some(user_id),
+        Auth::Invalid => {
+            return Ok(actix_web::HttpResponse::Unauthorized().body("Invalid API key"));
+        }
+    };
+
+    let encoding = match negotiate_encoding(&req) {
+        Ok(encoding) => encoding,
+        Err(e) => return Ok(actix_web::HttpResponse::BadRequest().body(e)),
     };
-    ws::start(session, &req, stream)
+
+    let session = WsSession::new(
+        broadcaster.get_ref().clone(),
+        user_id,
+        order_entry.get_ref().clone(),
+        market_data.get_ref().clone(),
+        encoding,
+        config.get_ref().clone(),
+    );
+    ws::WsResponseBuilder::new(session, &req, stream)
+        .protocols(&Encoding::SUBPROTOCOLS)
+        .start()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use std::time::Duration;
+
+    #[test]
+    fn encoding_defaults_to_json_and_binary_formats_are_opt_in() {
+        let plain = TestRequest::with_uri("/ws").to_http_request();
+        assert_eq!(negotiate_encoding(&plain), Ok(Encoding::Json));
+
+        let offered = TestRequest::with_uri("/ws")
+            .insert_header(("Sec-WebSocket-Protocol", "chat, clob.wincode"))
+            .to_http_request();
+        assert_eq!(negotiate_encoding(&offered), Ok(Encoding::Wincode));
+
+        let both = TestRequest::with_uri("/ws?format=msgpack")
+            .insert_header(("Sec-WebSocket-Protocol", "clob.wincode"))
+            .to_http_request();
+        assert_eq!(negotiate_encoding(&both), Ok(Encoding::MsgPack));
+
+        let unknown = TestRequest::with_uri("/ws?format=xml").to_http_request();
+        assert!(negotiate_encoding(&unknown).is_err());
+    }
+
+    #[test]
+    fn subscriptions_are_limited_to_the_served_market_and_own_user() {
+        let (market_data, _l3_requests) =
+            crate::market_data::MarketData::shared("BTC-USDT", Duration::from_millis(50));
+        let (sender, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let order_entry = OrderEntry {
+            sender: Arc::new(sender),
+            market: "BTC-USDT".to_string(),
+        };
+        let session = |user_id| {
+            WsSession::new(
+                Broadcaster::default(),
+                user_id,
+                order_entry.clone(),
+                market_data.clone(),
+                Encoding::Json,
+                WsConfig::default(),
+            )
+        };
+
+        let anonymous = session(None);
+        assert_eq!(
+            anonymous.topic(Channel::Trades, "BTC-USDT"),
+            Ok(Topic::new(Channel::Trades, "BTC-USDT"))
+        );
+        assert_eq!(
+            anonymous.topic(Channel::Trades, "ETH-USDT"),
+            Err("unknown market 'ETH-USDT'".to_string())
+        );
+        assert!(anonymous.topic(Channel::Orders, "BTC-USDT").is_err());
+
+        assert_eq!(
+            session(Some(7)).topic(Channel::Orders, "BTC-USDT"),
+            Ok(Topic::private(Channel::Orders, "BTC-USDT", 7))
+        );
+
+        assert!(
+            anonymous
+                .subscription(Channel::Trades, "BTC-USDT", Some(5))
+                .is_ok()
+        );
+        assert_eq!(
+            session(Some(7)).subscription(Channel::Orders, "BTC-USDT", Some(5)),
+            Err("since is not supported on private channels".to_string())
+        );
+    }
 }
@@ -0,0 +1,37 @@
+//! Setup shared by the integration tests.
+
+use orderbooks::inputs::Side;
+use orderbooks::market_data::{MarketData, SharedMarketData};
+use orderbooks::orderbook::{Order, OrderBook};
+use orderbooks::persist::PersistRecord;
+use orderbooks::worker::Broadcaster;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+/// An order book with its market data and the receiving end of its persistence channel.
+pub fn book() -> (
+    OrderBook,
+    SharedMarketData,
+    UnboundedReceiver<PersistRecord>,
+) {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let (market_data, _l3_requests) = MarketData::shared("BTC-USDT", Duration::from_millis(50));
+    let book = OrderBook::new(
+        market_data.market.clone(),
+        tx,
+        Arc::new(Broadcaster::default()),
+        market_data.clone(),
+    );
+    (book, market_data, rx)
+}
+
+pub fn order(order_id: u32, price: u32, quantity: u32, side: Side) -> Order {
+    Order {
+        order_id,
+        user_id: 1,
+        price,
+        quantity,
+        side,
+    }
+}
@@ -0,0 +1,139 @@
+use orderbooks::config::{Command, Config, PersistBackend};
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[test]
+fn file_overrides_only_the_keys_it_sets() {
+    let config = Config::from_toml(
+        r#"
+        market = "ETH-USDT"
+
+        [server]
+        workers = 4
+
+        [persistence]
+        backend = "kafka"
+        retry_max_attempts = 3
+
+        [engine]
+        depth_max_staleness_ms = 20
+
+        [ws]
+        max_connection_age_secs = 0
+
+        [api_keys]
+        "secret-key" = 7
+        "#,
+    )
+    .unwrap();
+    config.validate().unwrap();
+
+    assert_eq!(config.server.workers, 4);
+    assert_eq!(config.server.bind_address, "127.0.0.1:8080");
+    assert_eq!(config.persistence.backend, PersistBackend::Kafka);
+    assert_eq!(config.persistence.scylla_uri, "127.0.0.1:9042");
+    let retry = config.persistence.retry_policy();
+    assert_eq!(retry.max_attempts, 3);
+    assert_eq!(retry.initial_backoff, Duration::from_millis(50));
+    assert_eq!(
+        config.persistence.dead_letter_path,
+        "persist_dead_letter.jsonl"
+    );
+    assert_eq!(config.market, "ETH-USDT");
+    assert_eq!(config.engine.ring_capacity, 65536);
+    assert_eq!(config.engine.depth_max_staleness, Duration::from_millis(20));
+    assert_eq!(config.ws.max_connection_age, None);
+    assert_eq!(config.api_keys.len(), 1);
+
+    let printed = config.to_toml();
+    assert!(!printed.contains("secret-key"));
+    assert!(printed.contains("ETH-USDT"));
+    // The redacted keys are the only part that does not load back.
+    assert!(Config::from_toml(&printed).is_err());
+    let without_keys: String = printed
+        .lines()
+        .filter(|line| !line.starts_with("api_keys"))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    assert_eq!(Config::from_toml(&without_keys).unwrap().market, "ETH-USDT");
+}
+
+#[test]
+fn invalid_settings_are_rejected() {
+    assert!(Config::from_toml("[server]\nport = 8080").is_err());
+
+    assert!(Config::from_toml("markets = [\"A\", \"B\"]").is_err());
+    let no_market = Config::from_toml("market = \"\"").unwrap();
+    assert!(no_market.validate().is_err());
+
+    let no_spins = Config::from_toml("[engine]\nidle_spins = 0").unwrap();
+    assert!(no_spins.validate().is_err());
+
+    let no_attempts = Config::from_toml("[persistence]\nretry_max_attempts = 0").unwrap();
+    assert!(no_attempts.validate().is_err());
+    let backoff = Config::from_toml(
+        "[persistence]\nretry_initial_backoff_ms = 100\nretry_max_backoff_ms = 10",
+    );
+    assert!(backoff.unwrap().validate().is_err());
+
+    let timeout = Config::from_toml("[ws]\nheartbeat_ms = 5000\nclient_timeout_ms = 5000");
+    assert!(timeout.unwrap().validate().is_err());
+
+    Config::default().validate().unwrap();
+}
+
+fn overrides(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+    let vars: HashMap<String, String> = vars
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    move |key| vars.get(key).cloned()
+}
+
+#[test]
+fn environment_selects_the_kafka_backend_over_the_file() {
+    let mut config = Config::from_toml("[persistence]\nbackend = \"direct\"").unwrap();
+    config
+        .apply_overrides(&overrides(&[
+            ("CLOB_PERSIST_BACKEND", "kafka"),
+            ("CLOB_KAFKA_RUN_CONSUMER", "false"),
+            ("CLOB_KAFKA_BROKERS", "kafka-1:9092,kafka-2:9092"),
+            ("CLOB_DEAD_LETTER_PATH", "/var/lib/clob/dlq.jsonl"),
+            ("CLOB_PERSIST_RETRY_MAX_BACKOFF_MS", "2000"),
+        ]))
+        .unwrap();
+    assert_eq!(
+        config.persistence.dead_letter_path,
+        "/var/lib/clob/dlq.jsonl"
+    );
+    assert_eq!(
+        config.persistence.retry_policy().max_backoff,
+        Duration::from_secs(2)
+    );
+    assert_eq!(config.persistence.backend, PersistBackend::Kafka);
+    assert!(!config.kafka.run_consumer);
+    assert_eq!(config.kafka.brokers, "kafka-1:9092,kafka-2:9092");
+
+    let mut unset = Config::default();
+    unset.apply_overrides(&overrides(&[])).unwrap();
+    assert_eq!(unset.persistence.backend, PersistBackend::Direct);
+
+    let invalid = Config::default().apply_overrides(&overrides(&[("CLOB_PERSIST_BACKEND", "s3")]));
+    assert!(invalid.is_err());
+    let invalid = Config::default().apply_overrides(&overrides(&[("CLOB_WORKERS", "many")]));
+    assert!(invalid.is_err());
+}
+
+#[test]
+fn first_argument_selects_the_command() {
+    assert_eq!(Command::parse(None), Ok(Command::Serve));
+    assert_eq!(
+        Command::parse(Some("persist-consumer")),
+        Ok(Command::PersistConsumer)
+    );
+    assert_eq!(
+        Command::parse(Some("redrive-dlq")),
+        Ok(Command::RedriveDeadLetters)
+    );
+    assert!(Command::parse(Some("serve-everything")).is_err());
+}
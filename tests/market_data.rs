@@ -0,0 +1,370 @@
+mod common;
+
+use common::{book, order};
+use orderbooks::inputs::Side;
+use orderbooks::market_data::{Candles, TICKER_WINDOW_MS, Ticker};
+use orderbooks::orderbook::{OPEN_CANDLE_CHECKPOINT_MS, aggregate_levels};
+use orderbooks::outputs::{CandleInterval, TradeMsg};
+use orderbooks::persist::PersistEvent;
+
+fn trade(price: u32, quantity: u32, timestamp: i64) -> TradeMsg {
+    TradeMsg {
+        msg_type: 2,
+        price,
+        quantity,
+        maker_order_id: 1,
+        taker_order_id: 2,
+        timestamp,
+    }
+}
+
+#[test]
+fn ticker_rolls_trades_out_of_the_window() {
+    let mut ticker = Ticker::default();
+    ticker.record(&trade(100, 2, 0));
+    ticker.record(&trade(120, 1, 60_000));
+    ticker.record(&trade(90, 3, 120_000));
+
+    let stats = ticker.stats(120_000);
+    assert_eq!(
+        (stats.open_price, stats.high_price, stats.low_price),
+        (100, 120, 90)
+    );
+    assert_eq!(
+        (stats.volume, stats.quote_volume, stats.trade_count),
+        (6, 590, 3)
+    );
+    assert_eq!(stats.price_change, -10);
+
+    // The first bucket has left the window; nothing new traded.
+    let stats = ticker.stats(TICKER_WINDOW_MS + 60_000);
+    assert_eq!(
+        (stats.open_price, stats.high_price, stats.low_price),
+        (120, 120, 90)
+    );
+    assert_eq!((stats.volume, stats.trade_count), (4, 2));
+
+    // A trade past the window evicts everything before it, including the high.
+    ticker.record(&trade(95, 1, TICKER_WINDOW_MS + 120_000));
+    let stats = ticker.stats(TICKER_WINDOW_MS + 120_000);
+    assert_eq!(
+        (stats.open_price, stats.high_price, stats.low_price),
+        (90, 95, 90)
+    );
+    assert_eq!(
+        (stats.volume, stats.trade_count, stats.price_change),
+        (4, 2, 5)
+    );
+
+    let stats = ticker.stats(3 * TICKER_WINDOW_MS);
+    assert_eq!((stats.trade_count, stats.price_change), (0, 0));
+    assert_eq!(stats.last_price, 95);
+}
+
+#[test]
+fn candles_close_on_the_first_trade_of_a_later_period() {
+    let mut candles = Candles::default();
+    let mut closed = Vec::new();
+    candles.record(&trade(100, 2, 1_000), &mut closed);
+    candles.record(&trade(105, 1, 30_000), &mut closed);
+    candles.record(&trade(98, 4, 59_999), &mut closed);
+    assert!(closed.is_empty());
+
+    candles.record(&trade(101, 1, 60_000), &mut closed);
+    assert_eq!(closed.len(), 1);
+    let minute = &closed[0];
+    assert_eq!(minute.interval, CandleInterval::OneMinute);
+    assert_eq!((minute.open_time, minute.close_time), (0, 59_999));
+    assert_eq!(
+        (minute.open, minute.high, minute.low, minute.close),
+        (100, 105, 98, 98)
+    );
+    assert_eq!(
+        (minute.volume, minute.quote_volume, minute.trade_count),
+        (7, 697, 3)
+    );
+    assert!(minute.closed);
+
+    let five = candles.current(CandleInterval::FiveMinutes).unwrap();
+    assert_eq!((five.open, five.close, five.trade_count), (100, 101, 4));
+    assert!(!five.closed);
+
+    let range = candles.range(CandleInterval::OneMinute, None, None, 10);
+    assert_eq!(
+        range.iter().map(|c| c.open_time).collect::<Vec<_>>(),
+        [0, 60_000]
+    );
+    let range = candles.range(CandleInterval::OneMinute, Some(1), None, 10);
+    assert_eq!(range.len(), 1);
+    assert_eq!(range[0].open_time, 60_000);
+}
+
+#[test]
+fn candles_close_at_the_end_of_their_period_without_a_trade() {
+    let mut candles = Candles::default();
+    let mut closed = Vec::new();
+    candles.record(&trade(100, 2, 1_000), &mut closed);
+
+    candles.close_until(59_999, &mut closed);
+    assert!(closed.is_empty());
+    candles.close_until(60_000, &mut closed);
+    assert_eq!(closed.len(), 1);
+    assert_eq!(closed[0].interval, CandleInterval::OneMinute);
+    assert!(closed[0].closed);
+    assert!(candles.current(CandleInterval::OneMinute).is_none());
+    assert!(candles.current(CandleInterval::FiveMinutes).is_some());
+
+    // The next trade opens a new candle rather than reopening the closed one.
+    candles.record(&trade(101, 1, 61_000), &mut closed);
+    assert_eq!(closed.len(), 1);
+    let range = candles.range(CandleInterval::OneMinute, None, None, 10);
+    assert_eq!(
+        range
+            .iter()
+            .map(|c| (c.open_time, c.closed))
+            .collect::<Vec<_>>(),
+        [(0, true), (60_000, false)]
+    );
+}
+
+#[test]
+fn restored_open_candle_carries_on() {
+    let mut loaded = Candles::default();
+    let mut closed = Vec::new();
+    loaded.record(&trade(100, 2, 1_000), &mut closed);
+    loaded.record(&trade(105, 1, 61_000), &mut closed);
+    let persisted = loaded.range(CandleInterval::OneMinute, None, None, 10);
+
+    let mut candles = Candles::default();
+    candles.restore(CandleInterval::OneMinute, persisted);
+    candles.record(&trade(103, 3, 62_000), &mut closed);
+
+    let minute = candles.current(CandleInterval::OneMinute).unwrap();
+    assert_eq!(minute.open_time, 60_000);
+    assert_eq!(
+        (minute.open, minute.high, minute.close, minute.volume),
+        (105, 105, 103, 4)
+    );
+    assert_eq!(
+        candles
+            .range(CandleInterval::OneMinute, None, None, 10)
+            .len(),
+        2
+    );
+}
+
+#[test]
+fn depth_aggregates_into_conservative_price_buckets() {
+    let bids = [[105, 1], [101, 2], [100, 3], [99, 4], [90, 5]];
+    let asks = [[106, 1], [110, 2], [111, 3], [125, 4]];
+
+    assert_eq!(
+        aggregate_levels(&bids, 5, Side::Buy, 10),
+        [[105, 1], [100, 5], [95, 4], [90, 5]]
+    );
+    assert_eq!(
+        aggregate_levels(&asks, 5, Side::Sell, 10),
+        [[110, 3], [115, 3], [125, 4]]
+    );
+    assert_eq!(
+        aggregate_levels(&bids, 5, Side::Buy, 2),
+        [[105, 1], [100, 5]]
+    );
+
+    let top = u32::MAX - u32::MAX % 1000;
+    assert_eq!(
+        aggregate_levels(
+            &[[u32::MAX - 1, u32::MAX], [u32::MAX, 1]],
+            1000,
+            Side::Sell,
+            10
+        ),
+        [[top, u32::MAX]]
+    );
+}
+
+#[test]
+fn l3_snapshot_lists_live_orders_in_queue_order() {
+    let (mut book, _market_data, _rx) = book();
+    book.match_limit_order(order(1, 100, 5, Side::Buy));
+    book.match_limit_order(order(2, 100, 3, Side::Buy));
+    book.match_limit_order(order(3, 100, 4, Side::Buy));
+    book.match_limit_order(order(4, 99, 1, Side::Buy));
+    book.match_limit_order(order(5, 101, 2, Side::Sell));
+    book.delete_order(2);
+    book.match_limit_order(order(6, 100, 6, Side::Sell));
+
+    let snapshot = book.l3_snapshot();
+    assert_eq!(snapshot.update_id, 7);
+    let levels: Vec<_> = snapshot
+        .bids
+        .iter()
+        .map(|level| {
+            let orders: Vec<_> = level
+                .orders
+                .iter()
+                .map(|o| (o.order_id, o.quantity))
+                .collect();
+            (level.price, orders)
+        })
+        .collect();
+    assert_eq!(levels, [(100, vec![(3, 3)]), (99, vec![(4, 1)])]);
+    assert_eq!(snapshot.asks.len(), 1);
+    assert_eq!(snapshot.asks[0].orders[0].order_id, 5);
+}
+
+#[test]
+fn depth_snapshot_follows_every_book_event() {
+    let (mut book, market_data, _rx) = book();
+
+    book.match_limit_order(order(1, 100, 5, Side::Buy));
+    book.match_limit_order(order(2, 98, 2, Side::Buy));
+    book.match_limit_order(order(3, 103, 4, Side::Sell));
+    book.match_limit_order(order(4, 101, 1, Side::Sell));
+    book.match_limit_order(order(5, 100, 3, Side::Sell));
+    book.delete_order(4);
+
+    let published = market_data.depth.depth(10);
+    let expected = book.get_depth(10);
+    assert_eq!(published.last_update_id, "6");
+    assert_eq!(published.bids, [[100, 2], [98, 2]]);
+    assert_eq!(published.asks, [[103, 4]]);
+    assert_eq!(published.bids, expected.bids);
+    assert_eq!(published.asks, expected.asks);
+    assert_eq!(published.checksum, expected.checksum);
+}
+
+#[test]
+fn trade_views_follow_every_trade() {
+    let (mut book, market_data, _rx) = book();
+
+    book.match_limit_order(order(1, 100, 5, Side::Sell));
+    book.match_limit_order(order(2, 101, 5, Side::Sell));
+    book.match_limit_order(order(3, 101, 7, Side::Buy));
+
+    let view = market_data.trades.read();
+    let trades = view.recent_trades.latest(10);
+    assert_eq!(
+        trades
+            .iter()
+            .map(|t| (t.trade.price, t.trade.quantity))
+            .collect::<Vec<_>>(),
+        [(100, 5), (101, 2)]
+    );
+    assert!(trades[0].seq < trades[1].seq);
+
+    let stats = view.ticker.stats(trades[1].trade.timestamp);
+    assert_eq!(
+        (stats.last_price, stats.volume, stats.trade_count),
+        (101, 7, 2)
+    );
+    let minute = view.candles.current(CandleInterval::OneMinute).unwrap();
+    assert_eq!((minute.open, minute.close, minute.volume), (100, 101, 7));
+}
+
+#[test]
+fn a_taker_sweeping_many_makers_publishes_every_trade() {
+    let (mut book, market_data, mut rx) = book();
+    for order_id in 1..=100 {
+        book.match_limit_order(order(order_id, 100, 1, Side::Sell));
+    }
+    book.match_limit_order(order(101, 100, 100, Side::Buy));
+
+    let persisted = std::iter::from_fn(|| rx.try_recv().ok())
+        .filter(|record| matches!(record.event, PersistEvent::TradeExecuted { .. }))
+        .count();
+    assert_eq!(persisted, 100);
+    let view = market_data.trades.read();
+    let trades = view.recent_trades.latest(1000);
+    assert_eq!(trades.len(), 100);
+    assert_eq!(view.ticker.stats(trades[99].trade.timestamp).volume, 100);
+}
+
+#[test]
+fn open_candles_are_checkpointed_and_closed_on_time() {
+    let (mut book, market_data, mut rx) = book();
+    let mut candle_events = || {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .filter_map(|record| match record.event {
+                PersistEvent::CandleUpdated { candle, .. } => Some((false, candle)),
+                PersistEvent::CandleClosed { candle, .. } => Some((true, candle)),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    book.match_limit_order(order(1, 100, 5, Side::Sell));
+    book.match_limit_order(order(2, 100, 5, Side::Buy));
+    let traded_at = market_data.trades.read().recent_trades.latest(1)[0]
+        .trade
+        .timestamp;
+
+    book.close_due_candles(traded_at);
+    let events = candle_events();
+    assert_eq!(events.len(), CandleInterval::ALL.len());
+    assert!(events.iter().all(|(closed, c)| !closed && !c.closed));
+    // Nothing changed since.
+    book.close_due_candles(traded_at + OPEN_CANDLE_CHECKPOINT_MS);
+    assert!(candle_events().is_empty());
+
+    // A day later every candle has closed, though nothing traded.
+    book.close_due_candles(traded_at + 86_400_000);
+    let events = candle_events();
+    assert_eq!(events.len(), CandleInterval::ALL.len());
+    assert!(events.iter().all(|(closed, c)| *closed && c.closed));
+
+    let view = market_data.trades.read();
+    for interval in CandleInterval::ALL {
+        assert!(view.candles.current(interval).is_none());
+        let range = view.candles.range(interval, None, None, 10);
+        assert!(range.len() == 1 && range[0].closed);
+    }
+}
+
+#[test]
+fn bbo_is_published_only_when_the_top_of_book_changes() {
+    let (mut book, market_data, _rx) = book();
+    let bbo = || {
+        let snapshot = market_data.bbo.read();
+        let bbo = &snapshot.bbo;
+        (
+            snapshot.seq,
+            bbo.update_id,
+            [
+                bbo.bid_price,
+                bbo.bid_quantity,
+                bbo.ask_price,
+                bbo.ask_quantity,
+            ],
+        )
+    };
+
+    book.match_limit_order(order(1, 100, 5, Side::Buy));
+    let (seq, update_id, top) = bbo();
+    assert_eq!(update_id, book.l3_snapshot().update_id);
+    assert_eq!(top, [100, 5, 0, 0]);
+
+    // Below the best bid: the book changed, its top did not.
+    book.match_limit_order(order(2, 99, 5, Side::Buy));
+    book.cancel_order(2, 1);
+    assert_eq!(book.l3_snapshot().update_id, update_id + 2);
+    assert_eq!(bbo(), (seq, update_id, top));
+
+    // More quantity at the best price.
+    book.match_limit_order(order(3, 100, 2, Side::Buy));
+    let (next_seq, update_id, top) = bbo();
+    assert!(next_seq > seq);
+    assert_eq!(update_id, book.l3_snapshot().update_id);
+    assert_eq!(top, [100, 7, 0, 0]);
+
+    book.match_limit_order(order(4, 105, 1, Side::Sell));
+    let (_, update_id, top) = bbo();
+    assert_eq!(update_id, book.l3_snapshot().update_id);
+    assert_eq!(top, [100, 7, 105, 1]);
+
+    // A trade at the best bid takes quantity off it.
+    book.match_limit_order(order(5, 100, 3, Side::Sell));
+    let (_, update_id, top) = bbo();
+    assert_eq!(update_id, book.l3_snapshot().update_id);
+    assert_eq!(top, [100, 4, 105, 1]);
+}
@@ -0,0 +1,286 @@
+mod common;
+
+use common::{book, order};
+use orderbooks::events::{OrderEvent, OrderRequest, Verdict};
+use orderbooks::inputs::Side;
+use orderbooks::orderbook::{Order, OrderBook};
+use orderbooks::persist::{PersistEvent, PersistRecord};
+use orderbooks::worker::{ClientMessage, OrderEntry, PendingAck};
+use std::sync::Arc;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+fn order_entry() -> (OrderEntry, UnboundedReceiver<OrderRequest>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let entry = OrderEntry {
+        sender: Arc::new(tx),
+        market: "BTC-USDT".to_string(),
+    };
+    (entry, rx)
+}
+
+fn request(json: &str) -> ClientMessage {
+    serde_json::from_str(json).unwrap()
+}
+
+/// Answers every queued request with `verdict`, as the matching loop would, and returns
+/// their events.
+fn engine(rx: &mut UnboundedReceiver<OrderRequest>, verdict: Verdict) -> Vec<OrderEvent> {
+    std::iter::from_fn(|| rx.try_recv().ok())
+        .map(|request| {
+            if let Some(reply) = request.reply {
+                reply.send(verdict).unwrap();
+            }
+            request.event
+        })
+        .collect()
+}
+
+async fn ack(pending: Option<PendingAck>) -> serde_json::Value {
+    let ack = pending.expect("order entry is acked").settle().await;
+    serde_json::from_str(&ack.message().to_json()).unwrap()
+}
+
+fn persisted(rx: &mut UnboundedReceiver<PersistRecord>) -> Vec<PersistEvent> {
+    std::iter::from_fn(|| rx.try_recv().ok())
+        .map(|record| record.event)
+        .collect()
+}
+
+/// `(order_id, quantity)` of each bid at `price`, front of the queue first.
+fn bid_queue(book: &OrderBook, price: u32) -> Vec<(u32, u32)> {
+    book.l3_snapshot()
+        .bids
+        .iter()
+        .find(|level| level.price == price)
+        .map(|level| {
+            level
+                .orders
+                .iter()
+                .map(|o| (o.order_id, o.quantity))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[tokio::test]
+async fn new_order_is_queued_for_the_session_user_and_acked() {
+    let (entry, mut rx) = order_entry();
+    let msg = request(
+        r#"{"op":"new_order","req_id":"r1","market":"BTC-USDT","side":"Buy","price":100,"quantity":5}"#,
+    );
+
+    let pending = entry.handle(Some(7), &msg);
+    let events = engine(&mut rx, None);
+    let ack = ack(pending).await;
+    assert_eq!(ack["type"], "order_ack");
+    assert_eq!(ack["req_id"], "r1");
+    assert_eq!(ack["status"], "Accepted");
+
+    match events[..] {
+        [
+            OrderEvent::NewOrder {
+                order_id,
+                user_id,
+                price,
+                quantity,
+                ..
+            },
+        ] => {
+            assert_eq!(order_id as u64, ack["order_id"].as_u64().unwrap());
+            assert_eq!((user_id, price, quantity), (7, 100, 5));
+        }
+        ref other => panic!("unexpected events {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn cancel_and_amend_carry_the_session_user() {
+    let (entry, mut rx) = order_entry();
+    let cancel = request(r#"{"op":"cancel_order","req_id":"c","market":"BTC-USDT","order_id":3}"#);
+    let amend = request(
+        r#"{"op":"amend_order","req_id":"a","market":"BTC-USDT","order_id":4,"price":99,"quantity":2}"#,
+    );
+
+    let cancelled = entry.handle(Some(7), &cancel);
+    let amended = entry.handle(Some(7), &amend);
+    let events = engine(&mut rx, None);
+    assert_eq!(ack(cancelled).await["order_id"], 3);
+    assert_eq!(ack(amended).await["order_id"], 4);
+    assert!(matches!(
+        events[..],
+        [
+            OrderEvent::CancelOrder {
+                order_id: 3,
+                user_id: 7
+            },
+            OrderEvent::AmendOrder {
+                order_id: 4,
+                user_id: 7,
+                price: 99,
+                quantity: 2
+            }
+        ]
+    ));
+}
+
+#[tokio::test]
+async fn requests_refused_by_the_engine_are_acked_rejected() {
+    let (entry, mut rx) = order_entry();
+    let cancel = request(r#"{"op":"cancel_order","req_id":"c","market":"BTC-USDT","order_id":3}"#);
+
+    let pending = entry.handle(Some(7), &cancel);
+    engine(&mut rx, Some("order belongs to another user"));
+    let refused = ack(pending).await;
+    assert_eq!(refused["status"], "Rejected");
+    assert_eq!(refused["order_id"], 3);
+    assert_eq!(refused["reason"], "order belongs to another user");
+
+    let pending = entry.handle(Some(7), &cancel);
+    drop(rx);
+    assert_eq!(ack(pending).await["reason"], "order processing unavailable");
+}
+
+#[tokio::test]
+async fn invalid_order_entry_is_rejected_without_reaching_the_engine() {
+    let (entry, mut rx) = order_entry();
+    let new_order = |market: &str, price: u32| {
+        request(&format!(
+            r#"{{"op":"new_order","req_id":"r","market":"{}","side":"Sell","price":{},"quantity":1}}"#,
+            market, price
+        ))
+    };
+    let amend = request(
+        r#"{"op":"amend_order","req_id":"a","market":"BTC-USDT","order_id":4,"price":99,"quantity":0}"#,
+    );
+
+    async fn reason(pending: Option<PendingAck>) -> String {
+        let ack = ack(pending).await;
+        assert_eq!(ack["status"], "Rejected");
+        assert_eq!(ack["order_id"], 0);
+        ack["reason"].as_str().unwrap().to_string()
+    }
+    assert_eq!(
+        reason(entry.handle(None, &new_order("BTC-USDT", 100))).await,
+        "order entry requires an API key"
+    );
+    assert_eq!(
+        reason(entry.handle(Some(7), &new_order("ETH-USDT", 100))).await,
+        "unknown market 'ETH-USDT'"
+    );
+    assert_eq!(
+        reason(entry.handle(Some(7), &new_order("BTC-USDT", 0))).await,
+        "price and quantity must be positive"
+    );
+    assert_eq!(
+        reason(entry.handle(Some(7), &amend)).await,
+        "price and quantity must be positive"
+    );
+    assert!(rx.try_recv().is_err());
+
+    let subscribe = request(r#"{"op":"subscribe","channel":"trades","market":"BTC-USDT"}"#);
+    assert!(entry.handle(Some(7), &subscribe).is_none());
+}
+
+#[tokio::test]
+async fn binary_order_entry_is_acked_in_msgpack() {
+    #[derive(serde::Serialize)]
+    struct CancelOrder<'a> {
+        op: &'a str,
+        req_id: &'a str,
+        market: &'a str,
+        order_id: u32,
+    }
+    let frame = rmp_serde::to_vec_named(&CancelOrder {
+        op: "cancel_order",
+        req_id: "bin",
+        market: "BTC-USDT",
+        order_id: 9,
+    })
+    .unwrap();
+    let msg: ClientMessage = rmp_serde::from_slice(&frame).unwrap();
+
+    let (entry, mut rx) = order_entry();
+    let pending = entry.handle(Some(7), &msg).unwrap();
+    engine(&mut rx, None);
+    let reply = pending.settle().await.message().to_msgpack();
+    let ack: serde_json::Value = rmp_serde::from_slice(&reply).unwrap();
+    assert_eq!(
+        (ack["req_id"].as_str(), ack["order_id"].as_u64()),
+        (Some("bin"), Some(9))
+    );
+}
+
+#[test]
+fn amending_down_at_the_same_price_keeps_time_priority() {
+    let (mut book, _market_data, mut rx) = book();
+    book.match_limit_order(order(1, 100, 5, Side::Buy));
+    book.match_limit_order(order(2, 100, 5, Side::Buy));
+    persisted(&mut rx);
+
+    book.amend_order(1, 1, 100, 3);
+    assert_eq!(bid_queue(&book, 100), [(1, 3), (2, 5)]);
+    assert!(matches!(
+        persisted(&mut rx)[..],
+        [PersistEvent::OrderAmended {
+            order_id: 1,
+            remaining_qty: 3
+        }]
+    ));
+
+    // The amended order is still first in line.
+    book.match_limit_order(order(3, 100, 4, Side::Sell));
+    assert_eq!(bid_queue(&book, 100), [(2, 4)]);
+}
+
+#[test]
+fn amending_up_or_repricing_moves_to_the_back_of_the_queue() {
+    let (mut book, _market_data, mut rx) = book();
+    book.match_limit_order(order(1, 100, 5, Side::Buy));
+    book.match_limit_order(order(2, 100, 5, Side::Buy));
+    book.match_limit_order(order(3, 101, 5, Side::Buy));
+    persisted(&mut rx);
+
+    book.amend_order(1, 1, 100, 6);
+    assert_eq!(bid_queue(&book, 100), [(2, 5), (1, 6)]);
+    let events = persisted(&mut rx);
+    assert!(matches!(
+        events[..],
+        [
+            PersistEvent::OrderDeleted { order_id: 1 },
+            PersistEvent::NewOrder(Order {
+                order_id: 1,
+                quantity: 6,
+                ..
+            })
+        ]
+    ));
+
+    book.amend_order(3, 1, 100, 5);
+    assert_eq!(bid_queue(&book, 100), [(2, 5), (1, 6), (3, 5)]);
+    assert!(bid_queue(&book, 101).is_empty());
+}
+
+#[test]
+fn cancel_is_persisted_and_a_no_op_amend_changes_nothing() {
+    let (mut book, _market_data, mut rx) = book();
+    book.match_limit_order(order(1, 100, 5, Side::Buy));
+    book.match_limit_order(order(2, 101, 5, Side::Buy));
+    persisted(&mut rx);
+    let update_id = book.l3_snapshot().update_id;
+
+    book.amend_order(1, 1, 100, 5);
+    assert_eq!(book.l3_snapshot().update_id, update_id);
+    assert!(persisted(&mut rx).is_empty());
+
+    assert_eq!(
+        book.cancel_order(2, 2),
+        Some("order belongs to another user")
+    );
+    assert_eq!(book.cancel_order(9, 1), Some("unknown order"));
+    assert_eq!(book.cancel_order(2, 1), None);
+    assert_eq!(book.l3_snapshot().update_id, update_id + 1);
+    assert!(matches!(
+        persisted(&mut rx)[..],
+        [PersistEvent::OrderDeleted { order_id: 2 }]
+    ));
+}
@@ -0,0 +1,166 @@
+use actix::prelude::*;
+use actix_web::test::TestRequest;
+use orderbooks::auth::{ApiKeys, Auth};
+use orderbooks::inputs::Side;
+use orderbooks::market_data::MarketData;
+use orderbooks::orderbook::{Order, OrderBook};
+use orderbooks::outputs::{OrderStatus, OrderUpdateMsg};
+use orderbooks::worker::{
+    Broadcaster, Channel, Encoding, Outbound, SessionHandle, Topic, WsMessage,
+};
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// Stands in for a `WsSession`: records the `(seq, update)` of every JSON frame it is sent.
+struct Collector {
+    outbound: Arc<Outbound>,
+    received: Arc<Mutex<Vec<(u64, OrderUpdateMsg)>>>,
+}
+
+impl Actor for Collector {
+    type Context = Context<Self>;
+}
+
+impl Handler<WsMessage> for Collector {
+    type Result = ();
+
+    fn handle(&mut self, msg: WsMessage, _ctx: &mut Context<Self>) {
+        if let WsMessage::Text(text) = msg {
+            self.outbound.pending.fetch_sub(1, Ordering::Relaxed);
+            let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+            let seq = value["seq"].as_u64().unwrap();
+            let update = serde_json::from_value(value).unwrap();
+            self.received.lock().push((seq, update));
+        }
+    }
+}
+
+fn subscribe_orders(
+    broadcaster: &Broadcaster,
+    user_id: u32,
+) -> Arc<Mutex<Vec<(u64, OrderUpdateMsg)>>> {
+    let outbound = Arc::new(Outbound::default());
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let collector = Collector {
+        outbound: outbound.clone(),
+        received: received.clone(),
+    }
+    .start();
+    let handle = SessionHandle {
+        recipient: collector.recipient(),
+        outbound,
+        encoding: Encoding::Json,
+        skip_through: 0,
+    };
+    broadcaster.subscribe(
+        Topic::private(Channel::Orders, "BTC-USDT", user_id),
+        broadcaster.next_session_id(),
+        handle,
+        None,
+    );
+    received
+}
+
+async fn wait_for(received: &Mutex<Vec<(u64, OrderUpdateMsg)>>, count: usize) {
+    for _ in 0..200 {
+        if received.lock().len() >= count {
+            return;
+        }
+        actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("expected {} updates, got {}", count, received.lock().len());
+}
+
+#[actix_web::test]
+async fn private_updates_reach_only_their_owner_in_sequence() {
+    let broadcaster = Arc::new(Broadcaster::default());
+    let alice = subscribe_orders(&broadcaster, 1);
+    let bob = subscribe_orders(&broadcaster, 2);
+
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let (market_data, _l3_requests) = MarketData::shared("BTC-USDT", Duration::from_millis(50));
+    let mut book = OrderBook::new(market_data.market.clone(), tx, broadcaster, market_data);
+    let order = |order_id, user_id, price, quantity, side| Order {
+        order_id,
+        user_id,
+        price,
+        quantity,
+        side,
+    };
+
+    book.match_limit_order(order(1, 1, 100, 5, Side::Buy));
+    book.match_limit_order(order(2, 2, 100, 3, Side::Sell));
+    // Bob cannot cancel Alice's order; the refusal goes to Bob alone.
+    book.cancel_order(1, 2);
+    book.cancel_order(1, 1);
+
+    wait_for(&alice, 3).await;
+    wait_for(&bob, 3).await;
+    // Anything misrouted would have arrived by now.
+    actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+
+    let alice = alice.lock();
+    let statuses: Vec<_> = alice.iter().map(|(_, u)| u.status).collect();
+    assert_eq!(
+        statuses,
+        [
+            OrderStatus::Accepted,
+            OrderStatus::PartiallyFilled,
+            OrderStatus::Cancelled
+        ]
+    );
+    assert_eq!(alice[1].1.remaining_quantity, 2);
+
+    let bob = bob.lock();
+    let statuses: Vec<_> = bob.iter().map(|(_, u)| u.status).collect();
+    assert_eq!(
+        statuses,
+        [
+            OrderStatus::Accepted,
+            OrderStatus::Filled,
+            OrderStatus::Rejected
+        ]
+    );
+
+    for (received, user_id) in [(&*alice, 1), (&*bob, 2)] {
+        assert!(received.iter().all(|(_, u)| u.user_id == user_id));
+        let seqs: Vec<_> = received.iter().map(|(seq, _)| *seq).collect();
+        assert_eq!(seqs, [1, 2, 3], "user {}'s seq must have no gaps", user_id);
+    }
+}
+
+#[test]
+fn api_keys_resolve_to_their_user() {
+    let keys = ApiKeys::parse("alpha:1, beta:2,").unwrap();
+    assert_eq!(keys.len(), 2);
+
+    let bearer = TestRequest::default()
+        .insert_header(("Authorization", "Bearer alpha"))
+        .to_http_request();
+    assert_eq!(keys.authenticate(&bearer), Auth::User(1));
+
+    let header = TestRequest::default()
+        .insert_header(("X-API-Key", "beta"))
+        .to_http_request();
+    assert_eq!(keys.authenticate(&header), Auth::User(2));
+
+    let query = TestRequest::with_uri("/ws?api_key=beta").to_http_request();
+    assert_eq!(keys.authenticate(&query), Auth::User(2));
+
+    let unknown = TestRequest::default()
+        .insert_header(("X-API-Key", "gamma"))
+        .to_http_request();
+    assert_eq!(keys.authenticate(&unknown), Auth::Invalid);
+
+    let anonymous = TestRequest::default().to_http_request();
+    assert_eq!(keys.authenticate(&anonymous), Auth::Anonymous);
+}
+
+#[test]
+fn malformed_api_key_entries_are_refused() {
+    assert!(ApiKeys::parse("alpha").is_err());
+    assert!(ApiKeys::parse("alpha:bob").is_err());
+    assert!(ApiKeys::parse("").unwrap().is_empty());
+}
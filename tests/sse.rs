@@ -0,0 +1,177 @@
+use actix_web::body::MessageBody;
+use actix_web::test::{self, TestRequest};
+use actix_web::web::{self, Data};
+use actix_web::{App, http::StatusCode};
+use orderbooks::config::WsConfig;
+use orderbooks::market_data::MarketData;
+use orderbooks::outputs::{BboMsg, TradeMsg};
+use orderbooks::worker::{Broadcaster, Channel, FeedMessage, FeedPayload, Topic, sse_index};
+use std::time::Duration;
+
+fn trade(seq: u64) -> FeedMessage {
+    FeedMessage {
+        market: "BTC-USDT".into(),
+        seq,
+        timestamp: seq as i64,
+        payload: FeedPayload::Trade(TradeMsg {
+            msg_type: 2,
+            price: 100,
+            quantity: 1,
+            maker_order_id: 1,
+            taker_order_id: 2,
+            timestamp: seq as i64,
+        }),
+    }
+}
+
+fn bbo(seq: u64) -> FeedMessage {
+    FeedMessage {
+        market: "BTC-USDT".into(),
+        seq,
+        timestamp: seq as i64,
+        payload: FeedPayload::Bbo(BboMsg {
+            bid_price: 99,
+            bid_quantity: 1,
+            ask_price: 101,
+            ask_quantity: 1,
+            update_id: seq,
+        }),
+    }
+}
+
+/// A broadcaster holding `trades` at seqs 1, 3, 5 and `bbo` at 2, 4, 6 for replay.
+async fn broadcaster(replay_capacity: usize) -> Broadcaster {
+    let broadcaster = Broadcaster::new(&WsConfig {
+        replay_capacity,
+        ..WsConfig::default()
+    });
+    for seq in 1..=6 {
+        if seq % 2 == 1 {
+            broadcaster.publish(Topic::new(Channel::Trades, "BTC-USDT"), trade(seq));
+        } else {
+            broadcaster.publish(Topic::new(Channel::Bbo, "BTC-USDT"), bbo(seq));
+        }
+    }
+    // Let the fan-out thread file them into the history.
+    actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+    broadcaster
+}
+
+/// Opens `/stream` and returns the `(event, id)` of its first `count` events.
+async fn stream(
+    broadcaster: Broadcaster,
+    req: TestRequest,
+    count: usize,
+) -> Vec<(String, Option<u64>)> {
+    let (market_data, _l3_requests) = MarketData::shared("BTC-USDT", Duration::from_millis(50));
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(broadcaster))
+            .app_data(Data::new(market_data))
+            .app_data(Data::new(WsConfig::default()))
+            .route("/stream", web::get().to(sse_index)),
+    )
+    .await;
+    let resp = test::call_service(&app, req.to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let mut body = std::pin::pin!(resp.into_body());
+    let mut events = Vec::new();
+    while events.len() < count {
+        let chunk = actix_web::rt::time::timeout(
+            Duration::from_secs(2),
+            std::future::poll_fn(|cx| body.as_mut().poll_next(cx)),
+        )
+        .await
+        .expect("stream stalled")
+        .expect("stream ended")
+        .unwrap();
+        let text = std::str::from_utf8(&chunk).unwrap();
+        let field = |name: &str| {
+            text.lines()
+                .find_map(|line| line.strip_prefix(name))
+                .map(str::to_owned)
+        };
+        events.push((
+            field("event: ").unwrap(),
+            field("id: ").map(|id| id.parse().unwrap()),
+        ));
+    }
+    events
+}
+
+#[actix_web::test]
+async fn last_event_id_header_resumes_all_channels_in_seq_order() {
+    let events = stream(
+        broadcaster(10).await,
+        TestRequest::get()
+            .uri("/stream?market=BTC-USDT&channels=trades,bbo&last_event_id=1")
+            .insert_header(("Last-Event-ID", "2")),
+        4,
+    )
+    .await;
+
+    // The header wins over the query parameter, and the two channels interleave by seq.
+    assert_eq!(
+        events,
+        [
+            ("trade".to_string(), Some(3)),
+            ("bbo".to_string(), Some(4)),
+            ("trade".to_string(), Some(5)),
+            ("bbo".to_string(), Some(6)),
+        ]
+    );
+}
+
+#[actix_web::test]
+async fn last_event_id_query_resumes_the_first_connection() {
+    let events = stream(
+        broadcaster(10).await,
+        TestRequest::get().uri("/stream?market=BTC-USDT&channels=trades&last_event_id=3"),
+        1,
+    )
+    .await;
+    assert_eq!(events, [("trade".to_string(), Some(5))]);
+}
+
+#[actix_web::test]
+async fn resuming_past_the_held_messages_reports_a_gap() {
+    // Only the last two messages of each channel are held.
+    let events = stream(
+        broadcaster(2).await,
+        TestRequest::get()
+            .uri("/stream?market=BTC-USDT&channels=trades")
+            .insert_header(("Last-Event-ID", "0")),
+        3,
+    )
+    .await;
+    assert_eq!(
+        events,
+        [
+            ("resume_gap".to_string(), None),
+            ("trade".to_string(), Some(3)),
+            ("trade".to_string(), Some(5)),
+        ]
+    );
+}
+
+#[actix_web::test]
+async fn unknown_market_or_channel_is_refused() {
+    let (market_data, _l3_requests) = MarketData::shared("BTC-USDT", Duration::from_millis(50));
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(Broadcaster::default()))
+            .app_data(Data::new(market_data))
+            .app_data(Data::new(WsConfig::default()))
+            .route("/stream", web::get().to(sse_index)),
+    )
+    .await;
+
+    for uri in [
+        "/stream?market=ETH-USDT",
+        "/stream?market=BTC-USDT&channels=trades,orders",
+    ] {
+        let resp = test::call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", uri);
+    }
+}
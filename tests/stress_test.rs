@@ -1,4 +1,5 @@
 use reqwest::Client;
+#[allow(clippy::single_component_path_imports)]
 use rmp_serde;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
@@ -0,0 +1,442 @@
+use orderbooks::envelope::{self, EnvelopeError, LEGACY_VERSION, MessageType, SCHEMA_VERSION};
+use orderbooks::events::OrderEvent;
+use orderbooks::inputs::Side;
+use orderbooks::orderbook::{CHECKSUM_LEVELS, Order, depth_checksum};
+use orderbooks::outputs::{AckStatus, CandleInterval, CandleMsg, OrderAck, TradeMsg};
+use orderbooks::persist::PersistEvent;
+use orderbooks::worker::{Encoding, FeedMessage, FeedPayload, WsMessage};
+
+const TIMESTAMP: i64 = 1730836400000;
+
+// Golden bytes. If one of these changes, every consumer still running the old code breaks:
+// bump `SCHEMA_VERSION` and keep decoding the previous layout instead of editing them.
+// Older versions stay here as what has to keep decoding.
+const TRADE_V1: &str = "01650000000500000001000000020000008087e3fd92010000";
+const TRADE_V2: &str = "c10b0200020800000000000000\
+                        4254432d55534454\
+                        0700000000000000\
+                        8087e3fd92010000\
+                        1900000000000000\
+                        01650000000500000001000000020000008087e3fd92010000";
+
+const TRADE_V3: &str = "c10b0300020800000000000000\
+                        4254432d55534454\
+                        0700000000000000\
+                        8087e3fd92010000\
+                        1900000000000000\
+                        01650000000500000001000000020000008087e3fd92010000";
+
+const PERSIST_V1: &str = "000000002a00000007000000640000000300000001000000";
+const PERSIST_V2: &str = "c10b0200010800000000000000\
+                          4254432d55534454\
+                          0100000000000000\
+                          8087e3fd92010000\
+                          1800000000000000\
+                          000000002a00000007000000640000000300000001000000";
+
+const PERSIST_V3: &str = "c10b0300010800000000000000\
+                          4254432d55534454\
+                          0100000000000000\
+                          8087e3fd92010000\
+                          1800000000000000\
+                          000000002a00000007000000640000000300000001000000";
+
+// `OrderFilled` before (versions 1 and 2) and after it carried `remaining_qty`.
+const FILLED_V1: &str = "010000002a00000002000000";
+const FILLED_V2: &str = "c10b0200010800000000000000\
+                         4254432d55534454\
+                         0200000000000000\
+                         8087e3fd92010000\
+                         0c00000000000000\
+                         010000002a00000002000000";
+const FILLED_V3: &str = "c10b0300010800000000000000\
+                         4254432d55534454\
+                         0200000000000000\
+                         8087e3fd92010000\
+                         1000000000000000\
+                         010000002a0000000200000001000000";
+
+const ORDER_V1: &str = "0100000009000000";
+const ORDER_V2: &str = "c10b0200030800000000000000\
+                        4554482d55534454\
+                        0300000000000000\
+                        8087e3fd92010000\
+                        0800000000000000\
+                        0100000009000000";
+
+const ORDER_V3: &str = "c10b0300030800000000000000\
+                        4554482d55534454\
+                        0300000000000000\
+                        8087e3fd92010000\
+                        0800000000000000\
+                        0100000009000000";
+
+fn hex(s: &str) -> Vec<u8> {
+    let s: String = s.split_whitespace().collect();
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+fn trade() -> TradeMsg {
+    TradeMsg {
+        msg_type: 1,
+        price: 101,
+        quantity: 5,
+        maker_order_id: 1,
+        taker_order_id: 2,
+        timestamp: TIMESTAMP,
+    }
+}
+
+fn new_order() -> PersistEvent {
+    PersistEvent::NewOrder(Order {
+        order_id: 42,
+        user_id: 7,
+        price: 100,
+        quantity: 3,
+        side: Side::Sell,
+    })
+}
+
+#[test]
+fn trade_envelope_matches_golden_bytes() {
+    let encoded = envelope::encode(MessageType::Trade, "BTC-USDT", 7, TIMESTAMP, &trade()).unwrap();
+    assert_eq!(encoded, hex(TRADE_V3));
+    assert_eq!(wincode::serialize(&trade()).unwrap(), hex(TRADE_V1));
+
+    let (header, decoded) = envelope::decode::<TradeMsg>(&encoded, MessageType::Trade).unwrap();
+    assert_eq!(decoded, trade());
+    assert_eq!(header.version, SCHEMA_VERSION);
+    assert_eq!(header.market, "BTC-USDT");
+    assert_eq!(header.seq, 7);
+    assert_eq!(header.timestamp, TIMESTAMP);
+}
+
+#[test]
+fn persist_event_envelope_matches_golden_bytes() {
+    let encoded = envelope::encode(
+        MessageType::PersistEvent,
+        "BTC-USDT",
+        1,
+        TIMESTAMP,
+        &new_order(),
+    )
+    .unwrap();
+    assert_eq!(encoded, hex(PERSIST_V3));
+    assert_eq!(wincode::serialize(&new_order()).unwrap(), hex(PERSIST_V1));
+
+    let (_, decoded) =
+        envelope::decode::<PersistEvent>(&encoded, MessageType::PersistEvent).unwrap();
+    assert!(matches!(
+        decoded,
+        PersistEvent::NewOrder(Order {
+            order_id: 42,
+            user_id: 7,
+            price: 100,
+            quantity: 3,
+            side: Side::Sell,
+        })
+    ));
+}
+
+#[test]
+fn order_event_envelope_matches_golden_bytes() {
+    let event = OrderEvent::DeleteOrder { order_id: 9 };
+    let encoded =
+        envelope::encode(MessageType::OrderEvent, "ETH-USDT", 3, TIMESTAMP, &event).unwrap();
+    assert_eq!(encoded, hex(ORDER_V3));
+    assert_eq!(wincode::serialize(&event).unwrap(), hex(ORDER_V1));
+}
+
+#[test]
+fn order_filled_decodes_in_both_layouts() {
+    let filled = PersistEvent::OrderFilled {
+        order_id: 42,
+        traded_qty: 2,
+        remaining_qty: 1,
+    };
+    let encoded =
+        envelope::encode(MessageType::PersistEvent, "BTC-USDT", 2, TIMESTAMP, &filled).unwrap();
+    assert_eq!(encoded, hex(FILLED_V3));
+    let (_, decoded) =
+        envelope::decode::<PersistEvent>(&encoded, MessageType::PersistEvent).unwrap();
+    assert!(matches!(
+        decoded,
+        PersistEvent::OrderFilled {
+            order_id: 42,
+            traded_qty: 2,
+            remaining_qty: 1
+        }
+    ));
+
+    for (old, version) in [(FILLED_V2, 2), (FILLED_V1, LEGACY_VERSION)] {
+        let (header, decoded) =
+            envelope::decode::<PersistEvent>(&hex(old), MessageType::PersistEvent).unwrap();
+        assert_eq!(header.version, version);
+        assert!(matches!(
+            decoded,
+            PersistEvent::LegacyOrderFilled {
+                order_id: 42,
+                traded_qty: 2
+            }
+        ));
+    }
+}
+
+#[test]
+fn decodes_the_previous_envelope_version() {
+    let (header, decoded) =
+        envelope::decode::<TradeMsg>(&hex(TRADE_V2), MessageType::Trade).unwrap();
+    assert_eq!(header.version, 2);
+    assert_eq!((header.market.as_str(), header.seq), ("BTC-USDT", 7));
+    assert_eq!(decoded, trade());
+
+    let (_, decoded) =
+        envelope::decode::<PersistEvent>(&hex(PERSIST_V2), MessageType::PersistEvent).unwrap();
+    assert!(matches!(
+        decoded,
+        PersistEvent::NewOrder(Order { order_id: 42, .. })
+    ));
+
+    let (_, decoded) =
+        envelope::decode::<OrderEvent>(&hex(ORDER_V2), MessageType::OrderEvent).unwrap();
+    assert!(matches!(decoded, OrderEvent::DeleteOrder { order_id: 9 }));
+}
+
+/// Payload bytes of every message read from Kafka, one entry per variant.
+#[test]
+fn every_kafka_payload_matches_golden_bytes() {
+    let candle = CandleMsg {
+        interval: CandleInterval::FiveMinutes,
+        open_time: 300_000,
+        close_time: 599_999,
+        open: 100,
+        high: 105,
+        low: 98,
+        close: 101,
+        volume: 7,
+        quote_volume: 700,
+        trade_count: 3,
+        closed: true,
+    };
+    let persist_events = [
+        (
+            new_order(),
+            "000000002a00000007000000640000000300000001000000",
+        ),
+        (
+            PersistEvent::OrderFilled {
+                order_id: 42,
+                traded_qty: 2,
+                remaining_qty: 1,
+            },
+            "010000002a0000000200000001000000",
+        ),
+        (
+            PersistEvent::OrderDeleted { order_id: 42 },
+            "020000002a000000",
+        ),
+        (
+            PersistEvent::TradeExecuted {
+                trade_id: [0xab; 16],
+                price: 101,
+                quantity: 5,
+                maker_order_id: 1,
+                taker_order_id: 2,
+                timestamp: TIMESTAMP,
+            },
+            "03000000abababababababababababababababab\
+             650000000500000001000000020000008087e3fd92010000",
+        ),
+        (
+            PersistEvent::CandleClosed {
+                market: "BTC-USDT".to_string(),
+                candle: candle.clone(),
+            },
+            "0400000008000000000000004254432d5553445401000000\
+             e093040000000000bf27090000000000640000006900000062000000650000000700000000000000\
+             bc02000000000000030000000000000001",
+        ),
+        (
+            PersistEvent::OrderAmended {
+                order_id: 42,
+                remaining_qty: 1,
+            },
+            "050000002a00000001000000",
+        ),
+        (
+            PersistEvent::CandleUpdated {
+                market: "BTC-USDT".to_string(),
+                candle: CandleMsg {
+                    closed: false,
+                    ..candle
+                },
+            },
+            "0600000008000000000000004254432d5553445401000000\
+             e093040000000000bf27090000000000640000006900000062000000650000000700000000000000\
+             bc02000000000000030000000000000000",
+        ),
+        (
+            PersistEvent::LegacyOrderFilled {
+                order_id: 42,
+                traded_qty: 2,
+            },
+            "070000002a00000002000000",
+        ),
+    ];
+    for (event, golden) in persist_events {
+        assert_eq!(
+            wincode::serialize(&event).unwrap(),
+            hex(golden),
+            "{:?}",
+            event
+        );
+        let encoded =
+            envelope::encode(MessageType::PersistEvent, "BTC-USDT", 1, TIMESTAMP, &event).unwrap();
+        let (_, decoded) =
+            envelope::decode::<PersistEvent>(&encoded, MessageType::PersistEvent).unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", event));
+    }
+
+    let order_events = [
+        (
+            OrderEvent::NewOrder {
+                order_id: 1,
+                user_id: 7,
+                price: 100,
+                quantity: 3,
+                side: Side::Buy,
+            },
+            "000000000100000007000000640000000300000000000000",
+        ),
+        (OrderEvent::DeleteOrder { order_id: 9 }, ORDER_V1),
+        (
+            OrderEvent::CancelOrder {
+                order_id: 9,
+                user_id: 7,
+            },
+            "020000000900000007000000",
+        ),
+        (
+            OrderEvent::AmendOrder {
+                order_id: 9,
+                user_id: 7,
+                price: 99,
+                quantity: 2,
+            },
+            "0300000009000000070000006300000002000000",
+        ),
+    ];
+    for (event, golden) in order_events {
+        assert_eq!(
+            wincode::serialize(&event).unwrap(),
+            hex(golden),
+            "{:?}",
+            event
+        );
+        let encoded =
+            envelope::encode(MessageType::OrderEvent, "BTC-USDT", 1, TIMESTAMP, &event).unwrap();
+        let (_, decoded) =
+            envelope::decode::<OrderEvent>(&encoded, MessageType::OrderEvent).unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", event));
+    }
+
+    let ack = OrderAck {
+        client_order_id: "c1".to_string(),
+        order_id: 42,
+        status: AckStatus::Rejected,
+        reason: "no".to_string(),
+    };
+    assert_eq!(
+        wincode::serialize(&ack).unwrap(),
+        hex("020000000000000063312a0000000100000002000000000000006e6f")
+    );
+    let encoded = envelope::encode(MessageType::OrderAck, "BTC-USDT", 0, TIMESTAMP, &ack).unwrap();
+    let (_, decoded) = envelope::decode::<OrderAck>(&encoded, MessageType::OrderAck).unwrap();
+    assert_eq!(decoded, ack);
+}
+
+#[test]
+fn decodes_legacy_unframed_payloads() {
+    let (header, decoded) =
+        envelope::decode::<TradeMsg>(&hex(TRADE_V1), MessageType::Trade).unwrap();
+    assert_eq!(header.version, LEGACY_VERSION);
+    assert_eq!(decoded, trade());
+
+    let (header, decoded) =
+        envelope::decode::<OrderEvent>(&hex(ORDER_V1), MessageType::OrderEvent).unwrap();
+    assert_eq!(header.version, LEGACY_VERSION);
+    assert!(matches!(decoded, OrderEvent::DeleteOrder { order_id: 9 }));
+
+    let (header, _) =
+        envelope::decode::<PersistEvent>(&hex(PERSIST_V1), MessageType::PersistEvent).unwrap();
+    assert_eq!(header.version, LEGACY_VERSION);
+}
+
+#[test]
+fn rejects_newer_versions_and_wrong_types() {
+    let mut future = hex(TRADE_V3);
+    future[2] = SCHEMA_VERSION as u8 + 1;
+    assert_eq!(
+        envelope::decode::<TradeMsg>(&future, MessageType::Trade).unwrap_err(),
+        EnvelopeError::UnsupportedVersion(SCHEMA_VERSION + 1)
+    );
+
+    assert_eq!(
+        envelope::decode::<OrderEvent>(&hex(TRADE_V3), MessageType::OrderEvent).unwrap_err(),
+        EnvelopeError::UnexpectedType {
+            expected: MessageType::OrderEvent,
+            got: MessageType::Trade,
+        }
+    );
+}
+
+#[test]
+fn depth_checksum_matches_documented_algorithm() {
+    // crc32("101:5,100:2|102:3") and crc32("|"), as computed by zlib.
+    assert_eq!(
+        depth_checksum(&[[101, 5], [100, 2]], &[[102, 3]]),
+        2763587469
+    );
+    assert_eq!(depth_checksum(&[], &[]), 2343686810);
+    // crc32("0:4294967295|10:0"): zero and the widest values are written in full.
+    assert_eq!(depth_checksum(&[[0, u32::MAX]], &[[10, 0]]), 4018576079);
+
+    let bids: Vec<[u32; 2]> = (0..15).map(|i| [100 - i, 1]).collect();
+    assert_eq!(
+        depth_checksum(&bids, &[]),
+        depth_checksum(&bids[..CHECKSUM_LEVELS], &[])
+    );
+}
+
+#[test]
+fn feed_frames_per_encoding() {
+    let message = FeedMessage {
+        market: "BTC-USDT".into(),
+        seq: 7,
+        timestamp: TIMESTAMP,
+        payload: FeedPayload::Trade(trade()),
+    };
+
+    let Some(WsMessage::Text(json)) = message.encode(Encoding::Json) else {
+        panic!("json frames are text");
+    };
+    assert_eq!(
+        &*json,
+        r#"{"type":"trade","market":"BTC-USDT","seq":7,"price":101,"quantity":5,"maker_order_id":1,"taker_order_id":2,"timestamp":1730836400000}"#
+    );
+
+    let Some(WsMessage::Binary(wincode)) = message.encode(Encoding::Wincode) else {
+        panic!("wincode frames are binary");
+    };
+    assert_eq!(wincode.to_vec(), hex(TRADE_V3));
+
+    let Some(WsMessage::Binary(msgpack)) = message.encode(Encoding::MsgPack) else {
+        panic!("msgpack frames are binary");
+    };
+    let decoded: serde_json::Value = rmp_serde::from_slice(&msgpack).unwrap();
+    let expected: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(decoded, expected);
+}